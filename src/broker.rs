@@ -0,0 +1,366 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write, BufReader, BufWriter};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use mqtt3::{self, MqttRead, MqttWrite};
+
+use config::{self, EmbeddedBrokerConfig};
+use router::{self, Router};
+use supervisor;
+
+/// Longest a client may take to accept a packet before it is dropped, in
+/// seconds. Deliveries to all clients wait for the slowest one.
+const WRITE_TIMEOUT: u64 = 5;
+
+/// A topic filter a client subscribed to.
+struct Subscription {
+    client: u64,
+    filter: String,
+    qos: mqtt3::QoS,
+}
+
+struct Client {
+    client_id: String,
+    writer: BufWriter<TcpStream>,
+    next_pid: u16,
+}
+
+impl Client {
+    fn send(&mut self, packet: &mqtt3::Packet) -> Result<(), mqtt3::Error> {
+        let result = self.writer.write_packet(packet)
+            .and_then(|_| self.writer.flush().map_err(mqtt3::Error::from));
+        if result.is_err() {
+            // The client's thread notices and cleans up.
+            let _ = self.writer.get_ref().shutdown(Shutdown::Both);
+        }
+        result
+    }
+
+    fn next_pid(&mut self) -> mqtt3::PacketIdentifier {
+        self.next_pid = self.next_pid.wrapping_add(1).max(1);
+        mqtt3::PacketIdentifier(self.next_pid)
+    }
+}
+
+struct State {
+    /// Connected clients, by a key unique for the broker's lifetime.
+    clients: HashMap<u64, Client>,
+    next_client: u64,
+    subscriptions: Router<Subscription>,
+    retained: BTreeMap<String, mqtt3::Publish>,
+    /// Passwords by username. Without any, clients connect anonymously.
+    users: HashMap<String, String>,
+}
+
+impl State {
+    fn authenticate(&self, connect: &mqtt3::Connect) -> mqtt3::ConnectReturnCode {
+        if connect.protocol != mqtt3::Protocol::MQTT(4) {
+            return mqtt3::ConnectReturnCode::RefusedProtocolVersion;
+        }
+        if connect.client_id.is_empty() && !connect.clean_session {
+            return mqtt3::ConnectReturnCode::RefusedIdentifierRejected;
+        }
+        if self.users.is_empty() {
+            return mqtt3::ConnectReturnCode::Accepted;
+        }
+        match (&connect.username, &connect.password) {
+            (Some(username), Some(password))
+                if self.users.get(username) == Some(password) => mqtt3::ConnectReturnCode::Accepted,
+            _ => mqtt3::ConnectReturnCode::BadUsernamePassword,
+        }
+    }
+
+    /// Registers an accepted client, taking over from an earlier connection
+    /// with the same client id.
+    fn add_client(&mut self, client_id: &str, stream: TcpStream) -> u64 {
+        // Clients leaving their id to the broker are all different clients.
+        let client_id = match client_id {
+            "" => format!("auto-{}", config::random_hex()),
+            client_id => client_id.to_string(),
+        };
+        let previous: Vec<u64> = self.clients.iter()
+            .filter(|&(_, client)| client.client_id == client_id)
+            .map(|(key, _)| *key)
+            .collect();
+        for key in previous {
+            if let Some(client) = self.clients.remove(&key) {
+                let _ = client.writer.get_ref().shutdown(Shutdown::Both);
+            }
+            self.subscriptions.retain(|s| s.client != key);
+        }
+        self.next_client += 1;
+        self.clients.insert(self.next_client, Client {
+            client_id,
+            writer: BufWriter::new(stream),
+            next_pid: 0,
+        });
+        self.next_client
+    }
+
+    fn remove_client(&mut self, key: u64) {
+        self.clients.remove(&key);
+        self.subscriptions.retain(|s| s.client != key);
+    }
+
+    fn send(&mut self, key: u64, packet: &mqtt3::Packet) -> Result<(), mqtt3::Error> {
+        match self.clients.get_mut(&key) {
+            Some(client) => client.send(packet),
+            None => Ok(()),
+        }
+    }
+
+    /// Keeps or clears a retained message and delivers the publish to its
+    /// subscribers.
+    fn publish(&mut self, publish: mqtt3::Publish) {
+        if publish.retain {
+            if publish.payload.is_empty() {
+                self.retained.remove(&publish.topic_name);
+            } else {
+                self.retained.insert(publish.topic_name.clone(), publish.clone());
+            }
+        }
+
+        // A client subscribed with overlapping filters gets one copy at the
+        // highest of their QoS.
+        let mut receivers: BTreeMap<u64, mqtt3::QoS> = BTreeMap::new();
+        for subscription in self.subscriptions.matches(&publish.topic_name) {
+            let qos = receivers.entry(subscription.client).or_insert(mqtt3::QoS::AtMostOnce);
+            if subscription.qos.to_u8() > qos.to_u8() {
+                *qos = subscription.qos;
+            }
+        }
+        for (key, qos) in receivers {
+            if let Some(client) = self.clients.get_mut(&key) {
+                let _ = deliver(client, &publish, qos, false);
+            }
+        }
+    }
+
+    fn subscribe(&mut self, key: u64, subscribe: &mqtt3::Subscribe) -> Result<(), mqtt3::Error> {
+        let mut return_codes = Vec::new();
+        let mut granted = Vec::new();
+        for topic in &subscribe.topics {
+            if !router::valid_filter(&topic.topic_path) {
+                return_codes.push(mqtt3::SubscribeReturnCodes::Failure);
+                continue;
+            }
+            let qos = lowest(topic.qos, mqtt3::QoS::AtLeastOnce);
+            self.subscriptions.retain(|s| s.client != key || s.filter != topic.topic_path);
+            self.subscriptions.insert(&topic.topic_path, Subscription {
+                client: key,
+                filter: topic.topic_path.clone(),
+                qos,
+            }).map_err(mqtt3::Error::from)?;
+            return_codes.push(mqtt3::SubscribeReturnCodes::Success(qos));
+            granted.push((topic.topic_path.clone(), qos));
+        }
+        self.send(key, &mqtt3::Packet::Suback(Box::new(mqtt3::Suback {
+            pid: subscribe.pid,
+            return_codes,
+        })))?;
+
+        let client = match self.clients.get_mut(&key) {
+            Some(client) => client,
+            None => return Ok(()),
+        };
+        for (filter, qos) in granted {
+            for (topic, publish) in &self.retained {
+                if router::matches(&filter, topic) {
+                    deliver(client, publish, qos, true)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn unsubscribe(&mut self, key: u64, unsubscribe: &mqtt3::Unsubscribe) -> Result<(), mqtt3::Error> {
+        for filter in &unsubscribe.topics {
+            self.subscriptions.retain(|s| s.client != key || &s.filter != filter);
+        }
+        self.send(key, &mqtt3::Packet::Unsuback(unsubscribe.pid))
+    }
+}
+
+/// A small MQTT 3.1.1 broker running inside the adapter, for installs
+/// without one of their own.
+///
+/// It handles QoS 0 and 1, treats QoS 2 publishes as QoS 1 deliveries,
+/// keeps retained messages in memory and publishes wills of clients that
+/// go away without disconnecting. Sessions are always clean: nothing is
+/// kept for a client once it disconnects.
+pub struct Broker {
+    state: Arc<Mutex<State>>,
+    address: SocketAddr,
+}
+
+impl Broker {
+    /// Starts listening and serving clients in the background. Without
+    /// `users` it only listens on loopback addresses, as anyone able to
+    /// connect could control every device.
+    pub fn start(config: &EmbeddedBrokerConfig) -> Result<Broker, io::Error> {
+        let listener = TcpListener::bind((&config.bind[..], config.port))?;
+        let address = listener.local_addr()?;
+        if config.users.is_empty() && !address.ip().is_loopback() {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                      format!("embedded broker on {} needs users", address)));
+        }
+        let state = Arc::new(Mutex::new(State {
+            clients: HashMap::new(),
+            next_client: 0,
+            subscriptions: Router::new(),
+            retained: BTreeMap::new(),
+            users: config.users.clone(),
+        }));
+        let accepting = state.clone();
+        supervisor::spawn("embedded broker", move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        warn!("accept failed: {}", e);
+                        continue;
+                    }
+                };
+                let state = accepting.clone();
+                thread::spawn(move || {
+                    if let Err(e) = serve(&state, stream) {
+                        info!("client failed: {:?}", e);
+                    }
+                });
+            }
+            Ok(())
+        });
+        info!("listening on {}", address);
+        Ok(Broker {
+            state,
+            address,
+        })
+    }
+
+    /// The address clients connect to.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Accepts `username` with `password`. A broker started without users
+    /// stops letting clients in anonymously.
+    pub fn add_user(&self, username: &str, password: &str) {
+        self.state.lock().unwrap().users.insert(username.to_string(), password.to_string());
+    }
+}
+
+/// Talks to one client until it disconnects.
+fn serve(state: &Arc<Mutex<State>>, stream: TcpStream) -> Result<(), mqtt3::Error> {
+    stream.set_write_timeout(Some(Duration::from_secs(WRITE_TIMEOUT)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let connect = match reader.read_packet()? {
+        mqtt3::Packet::Connect(connect) => connect,
+        _ => return Err(mqtt3::Error::IncorrectPacketFormat),
+    };
+
+    let code = state.lock().unwrap().authenticate(&connect);
+    if code != mqtt3::ConnectReturnCode::Accepted {
+        let mut writer = BufWriter::new(stream);
+        writer.write_packet(&mqtt3::Packet::Connack(mqtt3::Connack {
+            session_present: false,
+            code,
+        }))?;
+        writer.flush()?;
+        return Ok(());
+    }
+    if connect.keep_alive > 0 {
+        // Clients get half their keep alive again before they are given up.
+        let keep_alive = u64::from(connect.keep_alive);
+        stream.set_read_timeout(Some(Duration::from_secs(keep_alive + keep_alive / 2)))?;
+    }
+
+    let key = {
+        let mut state = state.lock().unwrap();
+        let key = state.add_client(&connect.client_id, stream.try_clone()?);
+        state.send(key, &mqtt3::Packet::Connack(mqtt3::Connack {
+            session_present: false,
+            code,
+        }))?;
+        key
+    };
+
+    let result = session(state, key, &mut reader);
+    let mut state = state.lock().unwrap();
+    state.remove_client(key);
+    if result.is_err() {
+        if let Some(ref will) = connect.last_will {
+            state.publish(mqtt3::Publish {
+                dup: false,
+                qos: lowest(will.qos, mqtt3::QoS::AtLeastOnce),
+                retain: will.retain,
+                topic_name: will.topic.clone(),
+                pid: None,
+                payload: Arc::new(will.message.clone().into_bytes()),
+            });
+        }
+    }
+    let _ = stream.shutdown(Shutdown::Both);
+    result
+}
+
+/// Handles a connected client's packets. Returns `Ok` once the client
+/// disconnects cleanly.
+fn session(state: &Arc<Mutex<State>>, key: u64,
+           reader: &mut BufReader<TcpStream>) -> Result<(), mqtt3::Error> {
+    loop {
+        let packet = match reader.read_packet() {
+            Ok(packet) => packet,
+            // mqtt3 cannot decode DISCONNECT, the only packet a client sends
+            // that is empty besides PINGREQ.
+            Err(mqtt3::Error::PayloadRequired) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let mut state = state.lock().unwrap();
+        match packet {
+            mqtt3::Packet::Publish(publish) => {
+                if publish.topic_name.contains(['+', '#']) {
+                    return Err(mqtt3::Error::IncorrectPacketFormat);
+                }
+                match (publish.qos, publish.pid) {
+                    (mqtt3::QoS::AtLeastOnce, Some(pid)) => state.send(key, &mqtt3::Packet::Puback(pid))?,
+                    (mqtt3::QoS::ExactlyOnce, Some(pid)) => state.send(key, &mqtt3::Packet::Pubrec(pid))?,
+                    _ => {},
+                }
+                state.publish(*publish);
+            },
+            mqtt3::Packet::Pubrel(pid) => state.send(key, &mqtt3::Packet::Pubcomp(pid))?,
+            mqtt3::Packet::Subscribe(subscribe) => state.subscribe(key, &subscribe)?,
+            mqtt3::Packet::Unsubscribe(unsubscribe) => state.unsubscribe(key, &unsubscribe)?,
+            mqtt3::Packet::Pingreq => state.send(key, &mqtt3::Packet::Pingresp)?,
+            // Deliveries are not resent, so their acknowledgements are not
+            // needed.
+            mqtt3::Packet::Puback(_) => {},
+            _ => return Err(mqtt3::Error::UnsupportedPacketType),
+        }
+    }
+}
+
+/// Sends a publish to a subscriber at `qos` or below.
+fn deliver(client: &mut Client, publish: &mqtt3::Publish, qos: mqtt3::QoS,
+           retain: bool) -> Result<(), mqtt3::Error> {
+    let qos = lowest(publish.qos, qos);
+    let pid = match qos {
+        mqtt3::QoS::AtMostOnce => None,
+        _ => Some(client.next_pid()),
+    };
+    client.send(&mqtt3::Packet::Publish(Box::new(mqtt3::Publish {
+        dup: false,
+        qos,
+        retain,
+        topic_name: publish.topic_name.clone(),
+        pid,
+        payload: publish.payload.clone(),
+    })))
+}
+
+fn lowest(a: mqtt3::QoS, b: mqtt3::QoS) -> mqtt3::QoS {
+    if a.to_u8() <= b.to_u8() { a } else { b }
+}
@@ -0,0 +1,172 @@
+use serde_json::Value;
+
+use gateway::PropertyDescription;
+
+/// Device presets built from the Web Thing capability schemas at
+/// `gateway::SCHEMA_CONTEXT`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Capability {
+    OnOffSwitch,
+    SmartPlug,
+    Light,
+    DimmableLight,
+    ColorLight,
+    BinarySensor,
+    TemperatureSensor,
+    MultiLevelSensor,
+    Thermostat,
+}
+
+impl Capability {
+    pub fn from_name(name: &str) -> Option<Capability> {
+        match name {
+            "OnOffSwitch" => Some(Capability::OnOffSwitch),
+            "SmartPlug" => Some(Capability::SmartPlug),
+            "Light" => Some(Capability::Light),
+            "DimmableLight" => Some(Capability::DimmableLight),
+            "ColorLight" | "ColorControl" => Some(Capability::ColorLight),
+            "BinarySensor" => Some(Capability::BinarySensor),
+            "TemperatureSensor" => Some(Capability::TemperatureSensor),
+            "MultiLevelSensor" => Some(Capability::MultiLevelSensor),
+            "Thermostat" => Some(Capability::Thermostat),
+            _ => None,
+        }
+    }
+
+    /// The `@type` array announced for devices built from this preset.
+    pub fn types(&self) -> Vec<String> {
+        let types: &[&str] = match *self {
+            Capability::OnOffSwitch => &["OnOffSwitch"],
+            Capability::SmartPlug => &["OnOffSwitch", "SmartPlug", "EnergyMonitor"],
+            Capability::Light | Capability::DimmableLight => &["OnOffSwitch", "Light"],
+            Capability::ColorLight => &["OnOffSwitch", "Light", "ColorControl"],
+            Capability::BinarySensor => &["BinarySensor"],
+            Capability::TemperatureSensor => &["TemperatureSensor"],
+            Capability::MultiLevelSensor => &["MultiLevelSensor"],
+            Capability::Thermostat => &["Thermostat", "TemperatureSensor"],
+        };
+        types.iter().map(|t| t.to_string()).collect()
+    }
+
+    /// The matching legacy `type` for gateways without capability support.
+    pub fn legacy_type(&self) -> &'static str {
+        match *self {
+            Capability::OnOffSwitch => "onOffSwitch",
+            Capability::SmartPlug => "smartPlug",
+            Capability::Light => "onOffLight",
+            Capability::DimmableLight => "dimmableLight",
+            Capability::ColorLight => "onOffColorLight",
+            Capability::BinarySensor => "binarySensor",
+            Capability::TemperatureSensor | Capability::MultiLevelSensor => "multiLevelSensor",
+            Capability::Thermostat => "thing",
+        }
+    }
+
+    pub fn properties(&self) -> Vec<PropertyDescription> {
+        match *self {
+            Capability::OnOffSwitch | Capability::Light => vec![on_off()],
+            Capability::SmartPlug => vec![on_off(), PropertyDescription {
+                at_type: Some("InstantaneousPowerProperty".to_string()),
+                title: Some("Power".to_string()),
+                unit: Some("watt".to_string()),
+                read_only: Some(true),
+                ..PropertyDescription::new("power", "number", Value::from(0.0))
+            }],
+            Capability::DimmableLight => vec![on_off(), brightness()],
+            Capability::ColorLight => vec![on_off(), brightness(), PropertyDescription {
+                at_type: Some("ColorProperty".to_string()),
+                title: Some("Color".to_string()),
+                ..PropertyDescription::new("color", "string", Value::String("#ffffff".to_string()))
+            }],
+            Capability::BinarySensor => vec![PropertyDescription {
+                at_type: Some("BooleanProperty".to_string()),
+                title: Some("State".to_string()),
+                read_only: Some(true),
+                ..PropertyDescription::new("on", "boolean", Value::Bool(false))
+            }],
+            Capability::TemperatureSensor => vec![temperature()],
+            Capability::MultiLevelSensor => vec![PropertyDescription {
+                at_type: Some("LevelProperty".to_string()),
+                title: Some("Level".to_string()),
+                minimum: Some(0.0),
+                maximum: Some(100.0),
+                read_only: Some(true),
+                ..PropertyDescription::new("level", "number", Value::from(0.0))
+            }],
+            Capability::Thermostat => vec![temperature(), PropertyDescription {
+                at_type: Some("TargetTemperatureProperty".to_string()),
+                title: Some("Target Temperature".to_string()),
+                unit: Some("degree celsius".to_string()),
+                minimum: Some(5.0),
+                maximum: Some(35.0),
+                multiple_of: Some(0.5),
+                ..PropertyDescription::new("targetTemperature", "number", Value::from(20.0))
+            }, PropertyDescription {
+                at_type: Some("ThermostatModeProperty".to_string()),
+                title: Some("Mode".to_string()),
+                enumeration: Some(enum_values(&["off", "heat", "cool", "auto"])),
+                ..PropertyDescription::new("mode", "string", Value::String("off".to_string()))
+            }, PropertyDescription {
+                at_type: Some("HeatingCoolingProperty".to_string()),
+                title: Some("Heating/Cooling".to_string()),
+                enumeration: Some(enum_values(&["off", "heating", "cooling"])),
+                read_only: Some(true),
+                ..PropertyDescription::new("heatingCooling", "string", Value::String("off".to_string()))
+            }],
+        }
+    }
+}
+
+fn on_off() -> PropertyDescription {
+    PropertyDescription {
+        at_type: Some("OnOffProperty".to_string()),
+        title: Some("On/Off".to_string()),
+        ..PropertyDescription::new("on", "boolean", Value::Bool(false))
+    }
+}
+
+fn brightness() -> PropertyDescription {
+    PropertyDescription {
+        at_type: Some("BrightnessProperty".to_string()),
+        title: Some("Brightness".to_string()),
+        unit: Some("percent".to_string()),
+        minimum: Some(0.0),
+        maximum: Some(100.0),
+        ..PropertyDescription::new("level", "integer", Value::from(100))
+    }
+}
+
+fn temperature() -> PropertyDescription {
+    PropertyDescription {
+        at_type: Some("TemperatureProperty".to_string()),
+        title: Some("Temperature".to_string()),
+        unit: Some("degree celsius".to_string()),
+        read_only: Some(true),
+        ..PropertyDescription::new("temperature", "number", Value::from(0.0))
+    }
+}
+
+fn enum_values(values: &[&str]) -> Vec<Value> {
+    values.iter().map(|v| Value::String(v.to_string())).collect()
+}
+
+/// Picks the closest legacy `type` for an arbitrary set of capability
+/// `@type`s.
+pub fn legacy_type_for(types: &[String]) -> &'static str {
+    let has = |t: &str| types.iter().any(|x| x == t);
+    if has("Light") && has("ColorControl") {
+        "onOffColorLight"
+    } else if has("Light") {
+        "onOffLight"
+    } else if has("SmartPlug") {
+        "smartPlug"
+    } else if has("OnOffSwitch") {
+        "onOffSwitch"
+    } else if has("BinarySensor") || has("MotionSensor") {
+        "binarySensor"
+    } else if has("MultiLevelSensor") || has("TemperatureSensor") {
+        "multiLevelSensor"
+    } else {
+        "thing"
+    }
+}
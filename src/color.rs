@@ -0,0 +1,140 @@
+//! Conversions between `#rrggbb` colors, the way Web Things describe them,
+//! and the color spaces lights report in. Light levels are left out: lights
+//! report brightness separately, so colors are taken at full brightness.
+
+/// Red, green and blue from `#rrggbb`.
+pub fn parse_hex(color: &str) -> Option<[u8; 3]> {
+    let hex = color.trim_start_matches('#');
+    if hex.len() != 6 || !hex.is_ascii() {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
+
+pub fn to_hex(rgb: [u8; 3]) -> String {
+    format!("#{:02x}{:02x}{:02x}", rgb[0], rgb[1], rgb[2])
+}
+
+/// CIE 1931 `x` and `y` chromaticity of an sRGB color. Black has no
+/// chromaticity and is given the D65 white point.
+pub fn rgb_to_xy(rgb: [u8; 3]) -> (f64, f64) {
+    let [r, g, b] = linear(rgb);
+    let x = r * 0.4124 + g * 0.3576 + b * 0.1805;
+    let y = r * 0.2126 + g * 0.7152 + b * 0.0722;
+    let z = r * 0.0193 + g * 0.1192 + b * 0.9505;
+    let sum = x + y + z;
+    if sum == 0.0 {
+        return (0.3127, 0.3290);
+    }
+    (x / sum, y / sum)
+}
+
+/// The brightest sRGB color with chromaticity `x`, `y`.
+pub fn xy_to_rgb(x: f64, y: f64) -> [u8; 3] {
+    if y <= 0.0 {
+        return [255, 255, 255];
+    }
+    let (big_x, big_z) = (x / y, (1.0 - x - y) / y);
+    let r = 3.2406 * big_x - 1.5372 - 0.4986 * big_z;
+    let g = -0.9689 * big_x + 1.8758 + 0.0415 * big_z;
+    let b = 0.0557 * big_x - 0.2040 + 1.0570 * big_z;
+    // Colors outside the sRGB gamut are clipped to it.
+    from_linear([r.max(0.0), g.max(0.0), b.max(0.0)])
+}
+
+/// Hue in degrees and saturation in percent.
+pub fn rgb_to_hs(rgb: [u8; 3]) -> (f64, f64) {
+    let [r, g, b] = [rgb[0] as f64 / 255.0, rgb[1] as f64 / 255.0, rgb[2] as f64 / 255.0];
+    let max = r.max(g).max(b);
+    let delta = max - r.min(g).min(b);
+    if delta == 0.0 {
+        return (0.0, 0.0);
+    }
+    let hue = if max == r {
+        ((g - b) / delta).rem_euclid(6.0)
+    } else if max == g {
+        (b - r) / delta + 2.0
+    } else {
+        (r - g) / delta + 4.0
+    };
+    (hue * 60.0, delta / max * 100.0)
+}
+
+/// The brightest color with `hue` in degrees and `saturation` in percent.
+pub fn hs_to_rgb(hue: f64, saturation: f64) -> [u8; 3] {
+    let s = (saturation / 100.0).clamp(0.0, 1.0);
+    let h = hue.rem_euclid(360.0) / 60.0;
+    let x = 1.0 - (h % 2.0 - 1.0).abs();
+    let (r, g, b) = match h as u8 {
+        0 => (1.0, x, 0.0),
+        1 => (x, 1.0, 0.0),
+        2 => (0.0, 1.0, x),
+        3 => (0.0, x, 1.0),
+        4 => (x, 0.0, 1.0),
+        _ => (1.0, 0.0, x),
+    };
+    let channel = |c: f64| ((1.0 - s + s * c) * 255.0).round() as u8;
+    [channel(r), channel(g), channel(b)]
+}
+
+fn linear(rgb: [u8; 3]) -> [f64; 3] {
+    let expand = |c: u8| {
+        let c = c as f64 / 255.0;
+        if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+    };
+    [expand(rgb[0]), expand(rgb[1]), expand(rgb[2])]
+}
+
+/// Scales linear channels so that the brightest is at full level and
+/// applies the sRGB transfer function.
+fn from_linear(rgb: [f64; 3]) -> [u8; 3] {
+    let max = rgb[0].max(rgb[1]).max(rgb[2]);
+    if max <= 0.0 {
+        return [0, 0, 0];
+    }
+    let compress = |c: f64| {
+        let c = c / max;
+        let c = if c <= 0.0031308 { c * 12.92 } else { 1.055 * c.powf(1.0 / 2.4) - 0.055 };
+        (c * 255.0).round() as u8
+    };
+    [compress(rgb[0]), compress(rgb[1]), compress(rgb[2])]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_colors() {
+        assert_eq!(parse_hex("#ff8000"), Some([255, 128, 0]));
+        assert_eq!(parse_hex("FF8000"), Some([255, 128, 0]));
+        assert_eq!(parse_hex("#ff80"), None);
+        assert_eq!(parse_hex("#gg0000"), None);
+        assert_eq!(to_hex([255, 128, 0]), "#ff8000");
+    }
+
+    #[test]
+    fn xy_round_trips() {
+        for &rgb in &[[255, 0, 0], [0, 255, 0], [0, 0, 255], [255, 255, 255], [255, 128, 0]] {
+            let (x, y) = rgb_to_xy(rgb);
+            let back = xy_to_rgb(x, y);
+            for i in 0..3 {
+                assert!((back[i] as i32 - rgb[i] as i32).abs() <= 2, "{:?} became {:?}", rgb, back);
+            }
+        }
+        let (x, y) = rgb_to_xy([255, 255, 255]);
+        assert!((x - 0.3127).abs() < 0.001 && (y - 0.3290).abs() < 0.001);
+    }
+
+    #[test]
+    fn hs_round_trips() {
+        assert_eq!(rgb_to_hs([255, 0, 0]), (0.0, 100.0));
+        assert_eq!(rgb_to_hs([0, 0, 255]), (240.0, 100.0));
+        assert_eq!(rgb_to_hs([255, 255, 255]), (0.0, 0.0));
+        for &rgb in &[[255, 0, 0], [0, 255, 128], [255, 128, 255], [255, 255, 255]] {
+            let (hue, saturation) = rgb_to_hs(rgb);
+            assert_eq!(hs_to_rgb(hue, saturation), rgb);
+        }
+    }
+}
@@ -1,4 +1,569 @@
-pub const MQTT_SERVER: &'static str = "io.adafruit.com:1883";
-pub const MQTT_USERNAME: &'static str = "username";
-pub const MQTT_PASSWORD: &'static str = "ada-io-key";
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::env;
+use std::fs::{self, File};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
 
+use log::LevelFilter;
+use serde_json::{self, Value};
+
+use gateway::{ActionDescription, PropertyDescription};
+use payload::PayloadFormat;
+use topics::TopicScheme;
+
+pub const MQTT_SERVER: &str = "io.adafruit.com:1883";
+pub const MQTT_USERNAME: &str = "username";
+pub const MQTT_PASSWORD: &str = "ada-io-key";
+pub const CONFIG_PATH: &str = "mqtt-adapter.json";
+pub const PACKAGE_NAME: &str = "mqtt-adapter";
+pub const REGISTRY_FILE: &str = "registry.json";
+pub const QUEUE_FILE: &str = "queue.json";
+pub const CLIENT_ID_FILE: &str = "client-id";
+pub const DEFAULT_DEVICE_NAME: &str = "MQTT Device";
+/// Name of the broker connected to when `Config::brokers` is empty.
+pub const DEFAULT_BROKER: &str = "0";
+
+/// Optional settings read from `CONFIG_PATH`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Config {
+    /// Brokers to connect to, each served by its own adapter. The first one
+    /// also hosts the discovery profiles, the mirror and devices that do not
+    /// name a broker. Without any, the adapter connects to the embedded
+    /// broker if there is one, or else to `MQTT_SERVER`.
+    #[serde(default)]
+    pub brokers: Vec<BrokerConfig>,
+    /// Runs a broker inside the adapter.
+    #[serde(default)]
+    pub embedded_broker: Option<EmbeddedBrokerConfig>,
+    #[serde(default)]
+    pub devices: Vec<DeviceConfig>,
+    /// Enables Zigbee2MQTT bridge discovery.
+    #[serde(default)]
+    pub zigbee2mqtt: Option<Zigbee2MqttConfig>,
+    /// Enables Tasmota native discovery.
+    #[serde(default)]
+    pub tasmota: Option<TasmotaConfig>,
+    /// Enables Shelly Gen1 and Gen2 discovery.
+    #[serde(default)]
+    pub shelly: Option<ShellyConfig>,
+    /// Enables discovery of ESPHome nodes.
+    #[serde(default)]
+    pub esphome: Option<ESPHomeConfig>,
+    /// Enables Sparkplug B edge node support.
+    #[serde(default)]
+    pub sparkplug: Option<SparkplugConfig>,
+    /// Holding of publishes while the broker is unreachable.
+    #[serde(default)]
+    pub offline_queue: QueueConfig,
+    /// The MQTT session kept with the broker.
+    #[serde(default)]
+    pub session: SessionConfig,
+    /// Mirrors the plugin's own Things onto the broker.
+    #[serde(default)]
+    pub mirror: Option<MirrorConfig>,
+    /// Name of the device created when `devices` is empty, defaulting to
+    /// `DEFAULT_DEVICE_NAME`.
+    #[serde(default)]
+    pub default_device_name: Option<String>,
+    /// Which messages are logged.
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    /// Serves the adapter's metrics over HTTP for Prometheus to scrape.
+    #[serde(default)]
+    pub metrics_exporter: Option<MetricsExporterConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueueConfig {
+    /// Seconds a publish is held before it is dropped.
+    #[serde(default = "default_queue_expiry")]
+    pub expiry: u64,
+    #[serde(default = "default_queue_capacity")]
+    pub capacity: usize,
+    /// Keeps held publishes in the data directory across restarts.
+    #[serde(default)]
+    pub persist: bool,
+}
+
+impl Default for QueueConfig {
+    fn default() -> QueueConfig {
+        QueueConfig {
+            expiry: default_queue_expiry(),
+            capacity: default_queue_capacity(),
+            persist: false,
+        }
+    }
+}
+
+fn default_queue_expiry() -> u64 {
+    300
+}
+
+fn default_queue_capacity() -> usize {
+    1000
+}
+
+impl Config {
+    /// The brokers to connect to, never empty.
+    pub fn brokers(&self) -> Vec<BrokerConfig> {
+        if !self.brokers.is_empty() {
+            return self.brokers.clone();
+        }
+        let embedded = self.embedded_broker.is_some();
+        vec![BrokerConfig {
+            name: DEFAULT_BROKER.to_string(),
+            embedded,
+            server: match embedded {
+                true => String::new(),
+                false => MQTT_SERVER.to_string(),
+            },
+            username: Some(MQTT_USERNAME.to_string()),
+            password: Some(MQTT_PASSWORD.to_string()),
+            tls: None,
+            client_id: None,
+        }]
+    }
+
+    /// Whether `broker` hosts the discovery profiles and the mirror.
+    pub fn is_primary(&self, broker: &str) -> bool {
+        self.brokers.first().map_or(DEFAULT_BROKER, |b| b.name.as_str()) == broker
+    }
+
+    /// Configured devices served through `broker`.
+    pub fn devices_on(&self, broker: &str) -> Vec<&DeviceConfig> {
+        let primary = self.is_primary(broker);
+        self.devices.iter()
+            .filter(|device| device.broker.as_ref().map_or(primary, |b| b == broker))
+            .collect()
+    }
+
+    /// Passwords in the configuration, including the default broker's, to
+    /// be kept out of the log.
+    pub fn secrets(&self) -> Vec<String> {
+        let mut secrets = Vec::new();
+        for broker in self.brokers() {
+            secrets.extend(broker.password);
+            secrets.extend(broker.tls.map(|tls| tls.identity_password));
+        }
+        if let Some(ref embedded) = self.embedded_broker {
+            secrets.extend(embedded.users.values().cloned());
+        }
+        secrets
+    }
+
+    fn validate(&self) -> Result<(), io::Error> {
+        for (i, broker) in self.brokers.iter().enumerate() {
+            if self.brokers[..i].iter().any(|b| b.name == broker.name) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("broker {} defined twice", broker.name)));
+            }
+            if broker.embedded && self.embedded_broker.is_none() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("broker {} needs embeddedBroker", broker.name)));
+            }
+        }
+        let brokers = self.brokers();
+        for device in &self.devices {
+            if let Some(ref name) = device.broker {
+                if !brokers.iter().any(|b| &b.name == name) {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              format!("device {} uses unknown broker {}", device.id, name)));
+                }
+            }
+        }
+        self.logging.level()?;
+        self.logging.module_levels()?;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BrokerConfig {
+    /// Referred to by `DeviceConfig::broker`. The broker's adapter is
+    /// `mqtt-<name>`.
+    pub name: String,
+    /// Connects to the embedded broker, ignoring `server`, the credentials
+    /// and `tls`.
+    #[serde(default)]
+    pub embedded: bool,
+    /// `host:port` of the broker.
+    #[serde(default)]
+    pub server: String,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    /// Connects over TLS when set.
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    /// Replaces `SessionConfig::client_id` for this broker.
+    #[serde(default)]
+    pub client_id: Option<String>,
+}
+
+impl BrokerConfig {
+    /// Path of one of the broker's files in the data directory, e.g.
+    /// `registry-<name>.json` for `REGISTRY_FILE`. The default broker uses
+    /// the file names as they are.
+    pub fn data_file(&self, file: &str) -> PathBuf {
+        if self.name == DEFAULT_BROKER {
+            return data_dir().join(file);
+        }
+        let file = Path::new(file);
+        let stem = file.file_stem().map_or(String::new(), |s| s.to_string_lossy().into_owned());
+        let name = match file.extension() {
+            Some(extension) => format!("{}-{}.{}", stem, self.name, extension.to_string_lossy()),
+            None => format!("{}-{}", stem, self.name),
+        };
+        data_dir().join(name)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EmbeddedBrokerConfig {
+    /// Address to listen on, only this host by default. Set `0.0.0.0` for
+    /// devices on the network to connect.
+    #[serde(default = "default_embedded_bind")]
+    pub bind: String,
+    #[serde(default = "default_embedded_port")]
+    pub port: u16,
+    /// Passwords by username. Anyone on this host may connect when there are
+    /// none; other addresses than loopback ones require users.
+    #[serde(default)]
+    pub users: HashMap<String, String>,
+}
+
+fn default_embedded_bind() -> String {
+    "127.0.0.1".to_string()
+}
+
+fn default_embedded_port() -> u16 {
+    1883
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MetricsExporterConfig {
+    /// Address to listen on, only this host by default.
+    #[serde(default = "default_exporter_bind")]
+    pub bind: String,
+    #[serde(default = "default_exporter_port")]
+    pub port: u16,
+}
+
+fn default_exporter_bind() -> String {
+    "127.0.0.1".to_string()
+}
+
+fn default_exporter_port() -> u16 {
+    9464
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TlsConfig {
+    /// PEM file with a certificate authority to trust besides the system's.
+    #[serde(default)]
+    pub ca_file: Option<String>,
+    /// PKCS #12 archive with a client certificate and its key.
+    #[serde(default)]
+    pub identity_file: Option<String>,
+    #[serde(default)]
+    pub identity_password: String,
+    /// Skips certificate verification, e.g. for self-signed certificates.
+    #[serde(default)]
+    pub accept_invalid_certs: bool,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionConfig {
+    /// Client id presented to the broker. Without one an id is generated on
+    /// first start and kept in the data directory.
+    #[serde(default)]
+    pub client_id: Option<String>,
+    /// Asks the broker to keep subscriptions and undelivered messages while
+    /// the adapter is disconnected, instead of starting a clean session.
+    #[serde(default)]
+    pub persistent: bool,
+    /// Inbound publishes held for handling before the adapter stops reading
+    /// from the broker.
+    #[serde(default = "default_receive_maximum")]
+    pub receive_maximum: usize,
+}
+
+impl Default for SessionConfig {
+    fn default() -> SessionConfig {
+        SessionConfig {
+            client_id: None,
+            persistent: false,
+            receive_maximum: default_receive_maximum(),
+        }
+    }
+}
+
+fn default_receive_maximum() -> usize {
+    100
+}
+
+impl SessionConfig {
+    /// The configured client id, or the one generated for this install.
+    pub fn client_id(&self) -> Result<String, io::Error> {
+        if let Some(ref id) = self.client_id {
+            return Ok(id.clone());
+        }
+        let path = data_dir().join(CLIENT_ID_FILE);
+        let mut id = String::new();
+        match File::open(&path) {
+            Ok(mut file) => {
+                file.read_to_string(&mut id)?;
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+            Err(e) => return Err(e),
+        }
+        let id = id.trim();
+        if !id.is_empty() {
+            return Ok(id.to_string());
+        }
+
+        let id = format!("{}-{}", PACKAGE_NAME, random_hex());
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&path, &id)?;
+        Ok(id)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LoggingConfig {
+    /// Least severe level logged: `error`, `warn`, `info`, `debug`, `trace`
+    /// or `off`.
+    #[serde(default = "default_log_level")]
+    pub level: String,
+    /// Levels overriding `level` for modules and their submodules, keyed by
+    /// module path such as `mqtt` or `discovery`.
+    #[serde(default)]
+    pub modules: HashMap<String, String>,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> LoggingConfig {
+        LoggingConfig {
+            level: default_log_level(),
+            modules: HashMap::new(),
+        }
+    }
+}
+
+fn default_log_level() -> String {
+    "info".to_string()
+}
+
+impl LoggingConfig {
+    pub fn level(&self) -> Result<LevelFilter, io::Error> {
+        parse_level(&self.level)
+    }
+
+    pub fn module_levels(&self) -> Result<Vec<(String, LevelFilter)>, io::Error> {
+        self.modules.iter().map(|(module, level)| Ok((module.clone(), parse_level(level)?))).collect()
+    }
+}
+
+fn parse_level(level: &str) -> Result<LevelFilter, io::Error> {
+    level.parse().map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("unknown log level {}", level))
+    })
+}
+
+/// Publishes the Things of this plugin onto the broker.
+///
+/// Only the Things of this plugin are mirrored, not those of other adapters
+/// in the gateway: the mirror follows the messages the plugin sends, and
+/// only once they were written to the gateway socket. While the gateway is
+/// unreachable the mirror does not change either.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MirrorConfig {
+    /// Topic prefix the Things are published under.
+    #[serde(default = "default_mirror_prefix")]
+    pub prefix: String,
+}
+
+fn default_mirror_prefix() -> String {
+    "things".to_string()
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SparkplugConfig {
+    /// Sparkplug group to follow, `+` for all groups.
+    #[serde(default = "default_sparkplug_group")]
+    pub group_id: String,
+}
+
+fn default_sparkplug_group() -> String {
+    "+".to_string()
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ESPHomeConfig {
+    /// Prefix ESPHome publishes its discovery messages under.
+    #[serde(default = "default_esphome_prefix")]
+    pub discovery_prefix: String,
+}
+
+fn default_esphome_prefix() -> String {
+    "homeassistant".to_string()
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ShellyConfig {
+    /// Topic prefix of Gen1 devices.
+    #[serde(default = "default_shelly_prefix")]
+    pub prefix: String,
+}
+
+fn default_shelly_prefix() -> String {
+    "shellies".to_string()
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TasmotaConfig {
+    #[serde(default = "default_tasmota_prefix")]
+    pub discovery_prefix: String,
+}
+
+fn default_tasmota_prefix() -> String {
+    "tasmota/discovery".to_string()
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Zigbee2MqttConfig {
+    #[serde(default = "default_zigbee2mqtt_base")]
+    pub base_topic: String,
+}
+
+fn default_zigbee2mqtt_base() -> String {
+    "zigbee2mqtt".to_string()
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceConfig {
+    pub id: String,
+    pub name: String,
+    /// Name of a `capabilities::Capability` preset to start from.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub capability: Option<String>,
+    /// Capability `@type`s, replacing those of the preset when not empty.
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub types: Vec<String>,
+    /// Property descriptions added to, or replacing those of, the preset.
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub properties: Vec<PropertyDescription>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub actions: Vec<ActionDescription>,
+    /// Payload formats keyed by property or action name.
+    #[serde(skip_serializing_if = "HashMap::is_empty", default)]
+    pub payloads: HashMap<String, PayloadFormat>,
+    /// Built-in topic layout to use, `adafruit` unless `topics` is set.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub topic_scheme: Option<String>,
+    /// Custom topic templates, taking precedence over `topic_scheme`.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub topics: Option<TopicScheme>,
+    /// Value for `{base}`. Defaults to the scheme's base, or the broker
+    /// username for schemes without one.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub base: Option<String>,
+    /// Value for `{device}`. Defaults to `id`.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub topic: Option<String>,
+    /// Property values sent to the device when the adapter starts. Nothing
+    /// is sent at startup otherwise.
+    #[serde(skip_serializing_if = "HashMap::is_empty", default)]
+    pub boot_values: HashMap<String, Value>,
+    /// Name of the broker the device is on, defaulting to the first one.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub broker: Option<String>,
+}
+
+impl DeviceConfig {
+    /// A definition with no properties on the default topic scheme.
+    pub fn new(id: &str, name: &str) -> DeviceConfig {
+        DeviceConfig {
+            id: id.to_string(),
+            name: name.to_string(),
+            capability: None,
+            types: Vec::new(),
+            properties: Vec::new(),
+            actions: Vec::new(),
+            payloads: HashMap::new(),
+            topic_scheme: None,
+            topics: None,
+            base: None,
+            topic: None,
+            boot_values: HashMap::new(),
+            broker: None,
+        }
+    }
+
+    pub fn topic_scheme(&self) -> Result<TopicScheme, io::Error> {
+        match self.topics {
+            Some(ref scheme) => Ok(scheme.clone()),
+            None => TopicScheme::preset(self.topic_scheme.as_ref().map_or("adafruit", |s| s.as_str())),
+        }
+    }
+}
+
+/// 16 hex digits that differ between calls and processes. Not suitable for
+/// cryptography.
+pub fn random_hex() -> String {
+    // Hashers are seeded randomly, and differently for each `RandomState`.
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(::queue::now());
+    format!("{:016x}", hasher.finish())
+}
+
+/// 32 hex digits read from the operating system's random source, for
+/// passwords.
+pub fn random_password() -> Result<String, io::Error> {
+    let mut bytes = [0; 16];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// The add-on's data directory in the gateway profile, which survives
+/// add-on updates.
+pub fn data_dir() -> PathBuf {
+    let profile = match env::var_os("MOZIOT_HOME") {
+        Some(home) => PathBuf::from(home),
+        None => PathBuf::from(env::var_os("HOME").unwrap_or_default()).join(".mozilla-iot"),
+    };
+    profile.join("data").join(PACKAGE_NAME)
+}
+
+/// Reads the configuration file, returning the defaults if it is missing.
+pub fn load(path: &str) -> Result<Config, io::Error> {
+    let config: Config = match File::open(path) {
+        Ok(file) => serde_json::from_reader(file).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path, e))
+        })?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Config::default(),
+        Err(e) => return Err(e),
+    };
+    config.validate()?;
+    Ok(config)
+}
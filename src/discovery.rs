@@ -0,0 +1,81 @@
+use std::io;
+
+use serde_json::Value;
+
+use config::DeviceConfig;
+use gateway::{Property, PropertyDescription};
+use mqtt;
+use payload::PayloadFormat;
+
+/// A change to the set of devices found by a `Discovery` profile.
+pub enum DiscoveryEvent {
+    /// A device appeared or its definition changed.
+    Added(Box<DeviceConfig>),
+    /// The device with this `DeviceConfig::id` went away.
+    Removed(String),
+    /// New values for properties of a discovered device, for profiles that
+    /// decode device state themselves.
+    Updated(String, Vec<Property>),
+}
+
+/// Builds devices from the announcements an ecosystem publishes on the
+/// broker, e.g. Zigbee2MQTT's `bridge/devices`.
+pub trait Discovery {
+    /// Topic filters carrying the announcements.
+    fn topics(&self) -> Vec<String>;
+
+    /// Inspects an inbound publish. Publishes on topics the profile does not
+    /// care about are ignored.
+    fn handle_publish(&mut self, topic: &str, payload: &[u8]) -> Vec<DiscoveryEvent>;
+
+    /// Takes note of a device found in an earlier run and restored from the
+    /// registry, so that it can be removed if it went away in the meantime.
+    /// The device may belong to another profile.
+    fn restore(&mut self, _config: &DeviceConfig) {}
+
+    fn start_pairing(&mut self, _mqtt: &mqtt::MQTT, _timeout: f64) -> Result<(), io::Error> {
+        Ok(())
+    }
+
+    fn cancel_pairing(&mut self, _mqtt: &mqtt::MQTT) -> Result<(), io::Error> {
+        Ok(())
+    }
+
+    /// The payload setting `property` of the device with this
+    /// `DeviceConfig::id` to `value`, for profiles with a wire format of
+    /// their own. `Ok(None)` leaves the payload to the property's
+    /// `PayloadFormat`.
+    fn encode_command(&self, _config_id: &str, _property: &str, _value: &Value)
+                      -> Result<Option<Vec<u8>>, io::Error> {
+        Ok(None)
+    }
+}
+
+/// Adds a property to a discovered device, with how its value travels.
+pub fn add_property(config: &mut DeviceConfig, descr: PropertyDescription, format: PayloadFormat) {
+    config.payloads.insert(descr.name.clone(), format);
+    config.properties.push(descr);
+}
+
+/// Adds a capability `@type` to a discovered device, unless it has it.
+pub fn push_type(config: &mut DeviceConfig, typ: &str) {
+    if !config.types.iter().any(|t| t == typ) {
+        config.types.push(typ.to_string());
+    }
+}
+
+/// Translates common unit symbols to Web Thing unit names.
+pub fn unit_name(unit: &str) -> String {
+    match unit {
+        "°C" => "degree celsius",
+        "°F" => "degree fahrenheit",
+        "%" => "percent",
+        "W" => "watt",
+        "V" => "volt",
+        "A" => "ampere",
+        "kWh" => "kilowatt_hour",
+        "lx" => "lux",
+        "hPa" => "hectopascal",
+        other => other,
+    }.to_string()
+}
@@ -0,0 +1,350 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde_json::{self, Map, Value};
+
+use config::DeviceConfig;
+use discovery::{add_property, push_type, unit_name, Discovery, DiscoveryEvent};
+use gateway::{ActionDescription, PropertyDescription};
+use payload::{PayloadFormat, ValueMapping};
+
+/// Builds one device per ESPHome node from the Home Assistant style
+/// discovery messages it publishes on
+/// `<prefix>/<component>/<node>/<object_id>/config`.
+pub struct ESPHome {
+    prefix: String,
+    /// Entity configs of each node, keyed by component and object id.
+    nodes: HashMap<String, BTreeMap<(String, String), Value>>,
+}
+
+impl ESPHome {
+    pub fn new(prefix: &str) -> ESPHome {
+        ESPHome {
+            prefix: prefix.to_string(),
+            nodes: HashMap::new(),
+        }
+    }
+}
+
+impl Discovery for ESPHome {
+    fn topics(&self) -> Vec<String> {
+        vec![format!("{}/+/+/+/config", self.prefix)]
+    }
+
+    fn handle_publish(&mut self, topic: &str, payload: &[u8]) -> Vec<DiscoveryEvent> {
+        if !topic.starts_with(&self.prefix) {
+            return Vec::new();
+        }
+        let parts: Vec<&str> = topic[self.prefix.len()..].split('/').collect();
+        let (component, node, object_id) = match parts.as_slice() {
+            &["", component, node, object_id, "config"] => (component, node, object_id),
+            _ => return Vec::new(),
+        };
+        let id = format!("esphome-{}", node);
+        let key = (component.to_string(), object_id.to_string());
+
+        // An empty retained config removes the entity.
+        if payload.is_empty() {
+            let now_empty = match self.nodes.get_mut(node) {
+                Some(entities) => entities.remove(&key).is_some() && entities.is_empty(),
+                None => return Vec::new(),
+            };
+            if now_empty {
+                self.nodes.remove(node);
+                return vec![DiscoveryEvent::Removed(id)];
+            }
+        } else {
+            let entity = match serde_json::from_slice::<Value>(payload) {
+                Ok(Value::Object(entity)) => expand_base(entity),
+                _ => {
+                    warn!(topic = topic; "unreadable entity");
+                    return Vec::new();
+                }
+            };
+            let software = entity.get("dev").and_then(|d| d.get("sw")).and_then(Value::as_str);
+            if !software.is_some_and(|sw| sw.starts_with("esphome")) {
+                return Vec::new();
+            }
+            let entities = self.nodes.entry(node.to_string()).or_default();
+            if entities.get(&key) == Some(&entity) {
+                return Vec::new();
+            }
+            entities.insert(key, entity);
+        }
+
+        match self.nodes.get(node) {
+            Some(entities) => vec![DiscoveryEvent::Added(Box::new(node_config(&id, node, entities)))],
+            None => Vec::new(),
+        }
+    }
+}
+
+/// Expands the `~` abbreviation used in topic fields.
+fn expand_base(mut entity: Map<String, Value>) -> Value {
+    if let Some(base) = entity.get("~").and_then(Value::as_str).map(|b| b.to_string()) {
+        for (_, value) in entity.iter_mut() {
+            let expanded = match *value {
+                Value::String(ref s) if s.starts_with('~') => format!("{}{}", base, &s[1..]),
+                Value::String(ref s) if s.ends_with('~') => format!("{}{}", &s[..s.len() - 1], base),
+                _ => continue,
+            };
+            *value = Value::String(expanded);
+        }
+    }
+    Value::Object(entity)
+}
+
+/// Merges the entities of one node into a single device definition. The
+/// node's availability topic becomes a read-only `available` property.
+///
+/// Every property gets the topics of its entity, so `{device}` in them is
+/// the node and the device's topic scheme is never used.
+fn node_config(id: &str, node: &str, entities: &BTreeMap<(String, String), Value>) -> DeviceConfig {
+    let name = entities.values()
+        .filter_map(|e| e.get("dev").and_then(|d| d.get("name")).and_then(Value::as_str))
+        .next()
+        .unwrap_or(node);
+    let mut config = DeviceConfig::new(id, name);
+    config.topic = Some(node.to_string());
+
+    let mut availability = None;
+    for ((component, object_id), entity) in entities {
+        add_entity(&mut config, component, object_id, entity);
+        if availability.is_none() {
+            availability = entity.get("avty_t").and_then(Value::as_str).map(|t| {
+                (t.to_string(),
+                 text(entity, "pl_avail", "online"),
+                 text(entity, "pl_not_avail", "offline"))
+            });
+        }
+    }
+
+    if let Some((topic, online, offline)) = availability {
+        let mut format = state(Some(&topic), None);
+        format.value_map = on_off_map(&online, &offline);
+        add_property(&mut config, PropertyDescription {
+            title: Some("Available".to_string()),
+            read_only: Some(true),
+            ..PropertyDescription::new("available", "boolean", Value::Bool(false))
+        }, format);
+    }
+    config
+}
+
+/// Adds the properties and actions of one entity. Entities without a state
+/// topic are only commanded, and read-only ones without it are left out.
+fn add_entity(config: &mut DeviceConfig, component: &str, object_id: &str, entity: &Value) {
+    let title = entity.get("name").and_then(Value::as_str).map(|n| n.to_string());
+    let state_topic = entity.get("stat_t").and_then(Value::as_str);
+    let command_topic = entity.get("cmd_t").and_then(Value::as_str);
+    // ESPHome's own layout, for entities that leave their command topic out.
+    let default_command = format!("{{device}}/{}/{}/command", component, object_id);
+    let command = Some(command_topic.unwrap_or(&default_command));
+    let on = text(entity, "pl_on", "ON");
+    let off = text(entity, "pl_off", "OFF");
+
+    match component {
+        "switch" | "fan" => {
+            let mut format = state(state_topic, command);
+            format.value_map = on_off_map(&on, &off);
+            add_property(config, PropertyDescription {
+                at_type: Some("OnOffProperty".to_string()),
+                title,
+                ..PropertyDescription::new(object_id, "boolean", Value::Bool(false))
+            }, format);
+            push_type(config, "OnOffSwitch");
+        },
+        "binary_sensor" if state_topic.is_some() => {
+            let motion = entity.get("dev_cla").and_then(Value::as_str) == Some("motion");
+            let mut format = state(state_topic, None);
+            format.value_map = on_off_map(&on, &off);
+            add_property(config, PropertyDescription {
+                at_type: Some(if motion { "MotionProperty" } else { "BooleanProperty" }.to_string()),
+                title,
+                read_only: Some(true),
+                ..PropertyDescription::new(object_id, "boolean", Value::Bool(false))
+            }, format);
+            push_type(config, if motion { "MotionSensor" } else { "BinarySensor" });
+        },
+        "sensor" if state_topic.is_some() => {
+            let (at_type, capability) = match entity.get("dev_cla").and_then(Value::as_str) {
+                Some("temperature") => (Some("TemperatureProperty"), "TemperatureSensor"),
+                Some("humidity") => (Some("HumidityProperty"), "HumiditySensor"),
+                Some("power") => (Some("InstantaneousPowerProperty"), "EnergyMonitor"),
+                Some("voltage") => (Some("VoltageProperty"), "EnergyMonitor"),
+                Some("current") => (Some("CurrentProperty"), "EnergyMonitor"),
+                _ => (None, "MultiLevelSensor"),
+            };
+            push_type(config, capability);
+            add_property(config, PropertyDescription {
+                at_type: at_type.map(|t| t.to_string()),
+                title,
+                unit: entity.get("unit_of_meas").and_then(Value::as_str).map(unit_name),
+                read_only: Some(true),
+                ..PropertyDescription::new(object_id, "number", Value::from(0))
+            }, state(state_topic, None));
+        },
+        "text_sensor" if state_topic.is_some() => {
+            add_property(config, PropertyDescription {
+                title,
+                read_only: Some(true),
+                ..PropertyDescription::new(object_id, "string", Value::String(String::new()))
+            }, state(state_topic, None));
+        },
+        "number" => {
+            add_property(config, PropertyDescription {
+                title,
+                minimum: entity.get("min").and_then(Value::as_f64),
+                maximum: entity.get("max").and_then(Value::as_f64),
+                multiple_of: entity.get("step").and_then(Value::as_f64),
+                unit: entity.get("unit_of_meas").and_then(Value::as_str).map(unit_name),
+                ..PropertyDescription::new(object_id, "number", Value::from(0))
+            }, state(state_topic, command));
+        },
+        "light" => {
+            // ESPHome lights use the JSON schema for state and commands.
+            let mut format = state(state_topic, command);
+            format.state_path = Some("/state".to_string());
+            format.command_template = Some("{\"state\":\"{{value}}\"}".to_string());
+            format.value_map = on_off_map("ON", "OFF");
+            add_property(config, PropertyDescription {
+                at_type: Some("OnOffProperty".to_string()),
+                title: title.clone(),
+                ..PropertyDescription::new(object_id, "boolean", Value::Bool(false))
+            }, format);
+            if entity.get("brightness").and_then(Value::as_bool) == Some(true) {
+                let scale = entity.get("bri_scl").and_then(Value::as_f64).unwrap_or(255.0);
+                let mut format = state(state_topic, command);
+                format.state_path = Some("/brightness".to_string());
+                format.command_template = Some("{\"state\":\"ON\",\"brightness\":{{json}}}".to_string());
+                add_property(config, PropertyDescription {
+                    at_type: Some("BrightnessProperty".to_string()),
+                    title: title.map(|t| format!("{} Brightness", t)),
+                    minimum: Some(0.0),
+                    maximum: Some(scale),
+                    ..PropertyDescription::new(&format!("{}_brightness", object_id), "integer",
+                                                Value::from(0))
+                }, format);
+            }
+            push_type(config, "OnOffSwitch");
+            push_type(config, "Light");
+        },
+        "cover" => {
+            if let Some(position_topic) = entity.get("pos_t").and_then(Value::as_str) {
+                let set_topic = entity.get("set_pos_t").and_then(Value::as_str);
+                add_property(config, PropertyDescription {
+                    title: title.clone(),
+                    unit: Some("percent".to_string()),
+                    minimum: Some(0.0),
+                    maximum: Some(100.0),
+                    ..PropertyDescription::new(&format!("{}_position", object_id), "integer",
+                                                Value::from(0))
+                }, state(Some(position_topic), set_topic));
+            }
+            if state_topic.is_some() {
+                add_property(config, PropertyDescription {
+                    title,
+                    read_only: Some(true),
+                    ..PropertyDescription::new(object_id, "string", Value::String(String::new()))
+                }, state(state_topic, None));
+            }
+            if let Some(command_topic) = command_topic {
+                for &(action, payload) in &[("open", "OPEN"), ("close", "CLOSE"), ("stop", "STOP")] {
+                    add_action(config, &format!("{}_{}", object_id, action), command_topic, payload);
+                }
+            }
+        },
+        "button" => {
+            if let Some(command_topic) = command_topic {
+                add_action(config, object_id, command_topic, &text(entity, "pl_prs", "PRESS"));
+            }
+        },
+        _ => {},
+    }
+}
+
+fn text(entity: &Value, key: &str, default: &str) -> String {
+    entity.get(key).and_then(Value::as_str).unwrap_or(default).to_string()
+}
+
+fn state(state_topic: Option<&str>, command_topic: Option<&str>) -> PayloadFormat {
+    let mut format = PayloadFormat::default();
+    format.state_topics = state_topic.into_iter().map(|t| t.to_string()).collect();
+    format.write_only = state_topic.is_none();
+    format.command_topic = command_topic.map(|t| t.to_string());
+    format.command_template = Some("{{value}}".to_string());
+    format
+}
+
+fn on_off_map(on: &str, off: &str) -> Vec<ValueMapping> {
+    vec![
+        ValueMapping { value: Value::Bool(true), payload: on.to_string() },
+        ValueMapping { value: Value::Bool(false), payload: off.to_string() },
+    ]
+}
+
+fn add_action(config: &mut DeviceConfig, name: &str, topic: &str, payload: &str) {
+    let mut format = PayloadFormat::default();
+    format.command_topic = Some(topic.to_string());
+    format.command_template = Some(payload.to_string());
+    config.payloads.insert(name.to_string(), format);
+    config.actions.push(ActionDescription::new(name));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn discover(esphome: &mut ESPHome, topic: &str, entity: Value) -> DeviceConfig {
+        match esphome.handle_publish(topic, entity.to_string().as_bytes()).pop() {
+            Some(DiscoveryEvent::Added(config)) => *config,
+            _ => panic!("no device for {}", topic),
+        }
+    }
+
+    #[test]
+    fn merges_entities_of_a_node() {
+        let mut esphome = ESPHome::new("homeassistant");
+        let dev = json!({ "name": "Kitchen", "sw": "esphome v2023.1" });
+        discover(&mut esphome, "homeassistant/switch/kitchen/relay/config", json!({
+            "name": "Relay",
+            "stat_t": "kitchen/switch/relay/state",
+            "cmd_t": "kitchen/switch/relay/command",
+            "dev": dev,
+        }));
+        let config = discover(&mut esphome, "homeassistant/sensor/kitchen/temp/config", json!({
+            "~": "kitchen/sensor/temp",
+            "stat_t": "~/state",
+            "dev_cla": "temperature",
+            "unit_of_meas": "°C",
+            "dev": dev,
+        }));
+        assert_eq!(config.id, "esphome-kitchen");
+        assert_eq!(config.name, "Kitchen");
+        assert_eq!(config.types, vec!["TemperatureSensor", "OnOffSwitch"]);
+        assert_eq!(config.payloads["relay"].state_topics, vec!["kitchen/switch/relay/state"]);
+        assert_eq!(config.payloads["temp"].state_topics, vec!["kitchen/sensor/temp/state"]);
+        let temp = config.properties.iter().find(|p| p.name == "temp").unwrap();
+        assert_eq!(temp.unit, Some("degree celsius".to_string()));
+
+        let removed = esphome.handle_publish("homeassistant/switch/kitchen/relay/config", b"");
+        match removed.as_slice() {
+            &[DiscoveryEvent::Added(ref config)] => assert_eq!(config.properties.len(), 1),
+            _ => panic!("node not updated"),
+        }
+    }
+
+    #[test]
+    fn entities_without_state_topic_are_only_commanded() {
+        let mut esphome = ESPHome::new("homeassistant");
+        let dev = json!({ "sw": "esphome v2023.1" });
+        esphome.handle_publish("homeassistant/binary_sensor/hall/motion/config",
+                               json!({ "dev": dev }).to_string().as_bytes());
+        let config = discover(&mut esphome, "homeassistant/switch/hall/light/config",
+                              json!({ "dev": dev }));
+        assert_eq!(config.properties.len(), 1);
+        let format = &config.payloads["light"];
+        assert!(format.write_only);
+        assert!(format.state_topics.is_empty());
+        assert_eq!(format.command_topic, Some("{device}/switch/light/command".to_string()));
+    }
+}
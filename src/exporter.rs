@@ -0,0 +1,231 @@
+use std::fmt::Write as FmtWrite;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::time::Duration;
+
+use config::MetricsExporterConfig;
+use metrics::Metrics;
+use supervisor;
+
+const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Seconds a client has to send its request.
+const REQUEST_TIMEOUT: u64 = 5;
+
+/// Samples of a metric family: labels and value.
+type Samples = Vec<(Vec<(&'static str, String)>, f64)>;
+
+/// Serves the adapter's metrics at `/metrics` in the OpenMetrics text
+/// format, for Prometheus to scrape.
+pub struct Exporter {
+    address: SocketAddr,
+}
+
+impl Exporter {
+    /// Starts listening and serving scrapes in the background.
+    pub fn start(config: &MetricsExporterConfig, metrics: Metrics) -> Result<Exporter, io::Error> {
+        let listener = TcpListener::bind((&config.bind[..], config.port))?;
+        let address = listener.local_addr()?;
+        supervisor::spawn("metrics exporter", move || {
+            // Scrapes are few and quick, so they are answered one at a time.
+            for stream in listener.incoming() {
+                match stream.and_then(|stream| respond(stream, &metrics)) {
+                    Ok(()) => {},
+                    Err(e) => info!("scrape failed: {}", e),
+                }
+            }
+            Ok(())
+        });
+        Ok(Exporter {
+            address,
+        })
+    }
+
+    /// The address scrapers connect to.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.address
+    }
+}
+
+fn respond(mut stream: TcpStream, metrics: &Metrics) -> Result<(), io::Error> {
+    stream.set_read_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // The headers make no difference to the answer.
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+    let mut parts = request.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(path)) if path.split('?').next() == Some("/metrics") => {
+            ("200 OK", CONTENT_TYPE, render(metrics))
+        },
+        (Some("GET"), _) => ("404 Not Found", "text/plain; charset=utf-8", "Not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "text/plain; charset=utf-8", "Method not allowed\n".to_string()),
+    };
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+           status, content_type, body.len(), body)?;
+    stream.flush()
+}
+
+/// The metrics in the OpenMetrics text format.
+pub fn render(metrics: &Metrics) -> String {
+    let connections = metrics.connections();
+    let broker = |name: &str| vec![("broker", name.to_string())];
+    let per_broker = |value: &dyn Fn(&::metrics::ConnectionStats) -> Option<f64>| -> Samples {
+        connections.iter()
+            .filter_map(|(name, stats)| value(stats).map(|value| (broker(name), value)))
+            .collect()
+    };
+
+    let mut out = String::new();
+    family(&mut out, "mqtt_adapter_broker_connected", "gauge",
+           "Whether the connection to the broker is up.",
+           per_broker(&|stats| Some(stats.connected as u8 as f64)));
+    family(&mut out, "mqtt_adapter_broker_reconnects", "counter",
+           "Connections to the broker re-established after being lost.",
+           per_broker(&|stats| Some(stats.reconnects as f64)));
+    family(&mut out, "mqtt_adapter_broker_messages_received", "counter",
+           "Publishes received from the broker.",
+           per_broker(&|stats| Some(stats.messages_in as f64)));
+    family(&mut out, "mqtt_adapter_broker_messages_sent", "counter",
+           "Publishes written to the broker, including resent ones.",
+           per_broker(&|stats| Some(stats.messages_out as f64)));
+    family(&mut out, "mqtt_adapter_publish_failures", "counter",
+           "Publishes that could not be written and were queued instead.",
+           per_broker(&|stats| Some(stats.publish_failures as f64)));
+    family(&mut out, "mqtt_adapter_publish_latency_seconds", "gauge",
+           "How long the broker took to acknowledge the latest publish.",
+           per_broker(&|stats| stats.publish_latency.map(seconds)));
+    family(&mut out, "mqtt_adapter_inflight_messages", "gauge",
+           "QoS 1 publishes written but not yet acknowledged.",
+           per_broker(&|stats| Some(stats.inflight as f64)));
+    family(&mut out, "mqtt_adapter_offline_queue_depth", "gauge",
+           "Publishes waiting for the broker to be reachable.",
+           per_broker(&|stats| Some(stats.queue_depth as f64)));
+
+    let mut devices = Samples::new();
+    for (device_id, stats) in metrics.devices() {
+        devices.push((vec![("device", device_id.clone()), ("direction", "in".to_string())],
+                      stats.messages_in as f64));
+        devices.push((vec![("device", device_id), ("direction", "out".to_string())],
+                      stats.messages_out as f64));
+    }
+    family(&mut out, "mqtt_adapter_device_messages", "counter",
+           "Publishes that updated a device, and commands published for it.", devices);
+
+    let gateway = metrics.gateway();
+    family(&mut out, "mqtt_adapter_gateway_connected", "gauge",
+           "Whether the plugin is registered with the gateway.",
+           vec![(Vec::new(), gateway.connected as u8 as f64)]);
+    family(&mut out, "mqtt_adapter_gateway_messages", "counter",
+           "Messages exchanged with the gateway.",
+           vec![(vec![("direction", "in".to_string())], gateway.messages_in as f64),
+                (vec![("direction", "out".to_string())], gateway.messages_out as f64)]);
+    family(&mut out, "mqtt_adapter_gateway_latency_seconds", "gauge",
+           "How long the latest message to the gateway waited to be written.",
+           gateway.latency.map(seconds).into_iter().map(|value| (Vec::new(), value)).collect());
+    out.push_str("# EOF\n");
+    out
+}
+
+fn family(out: &mut String, name: &str, kind: &str, help: &str, samples: Samples) {
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let suffix = match kind {
+        "counter" => "_total",
+        _ => "",
+    };
+    for (labels, value) in samples {
+        let labels: Vec<String> = labels.iter()
+            .map(|&(key, ref value)| format!("{}=\"{}\"", key, escape(value)))
+            .collect();
+        match labels.is_empty() {
+            true => writeln!(out, "{}{} {}", name, suffix, value),
+            false => writeln!(out, "{}{}{{{}}} {}", name, suffix, labels.join(","), value),
+        }.unwrap_or(());
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn metrics() -> Metrics {
+        let metrics = Metrics::new();
+        metrics.connection("0").update(|stats| {
+            stats.connected = true;
+            stats.messages_in = 3;
+            stats.publish_failures = 1;
+            stats.publish_latency = Some(Duration::from_millis(250));
+            stats.inflight = 2;
+        });
+        metrics.connection("backup");
+        metrics.update_device("mqtt-0-\"lamp\"", |stats| stats.messages_out += 1);
+        metrics.update_gateway(|stats| stats.messages_in = 4);
+        metrics
+    }
+
+    #[test]
+    fn renders_families() {
+        let text = render(&metrics());
+        for line in &[
+            "# TYPE mqtt_adapter_broker_connected gauge",
+            "mqtt_adapter_broker_connected{broker=\"0\"} 1",
+            "mqtt_adapter_broker_connected{broker=\"backup\"} 0",
+            "# TYPE mqtt_adapter_broker_messages_received counter",
+            "mqtt_adapter_broker_messages_received_total{broker=\"0\"} 3",
+            "mqtt_adapter_publish_failures_total{broker=\"0\"} 1",
+            "mqtt_adapter_publish_latency_seconds{broker=\"0\"} 0.25",
+            "mqtt_adapter_inflight_messages{broker=\"0\"} 2",
+            "mqtt_adapter_device_messages_total{device=\"mqtt-0-\\\"lamp\\\"\",direction=\"out\"} 1",
+            "mqtt_adapter_gateway_connected 0",
+            "mqtt_adapter_gateway_messages_total{direction=\"in\"} 4",
+        ] {
+            assert!(text.lines().any(|l| l == *line), "{} missing from\n{}", line, text);
+        }
+        // Latencies not measured yet are left out.
+        assert!(!text.contains("mqtt_adapter_publish_latency_seconds{broker=\"backup\"}"));
+        assert!(!text.lines().any(|l| l.starts_with("mqtt_adapter_gateway_latency_seconds")));
+        assert!(text.ends_with("# EOF\n"));
+    }
+
+    fn get(exporter: &Exporter, request: &str) -> String {
+        let mut stream = TcpStream::connect(exporter.local_addr()).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn serves_scrapes() {
+        let exporter = Exporter::start(&MetricsExporterConfig {
+            bind: "127.0.0.1".to_string(),
+            port: 0,
+        }, metrics()).unwrap();
+
+        let response = get(&exporter, "GET /metrics HTTP/1.1\r\nHost: localhost\r\nAccept: */*\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains(&format!("Content-Type: {}\r\n", CONTENT_TYPE)));
+        assert!(response.contains("\r\n\r\n# TYPE mqtt_adapter_broker_connected gauge\n"));
+
+        let response = get(&exporter, "GET / HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        let response = get(&exporter, "POST /metrics HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+    }
+}
@@ -3,13 +3,16 @@ use std::collections::HashMap;
 use std::io::{self, Read, Write};
 use std::sync::mpsc::{channel, Sender, Receiver};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use nanomsg::{Protocol, Socket};
 use serde_json::{self, Value};
 
-const BASE_URL: &'static str = "ipc:///tmp";
-const ADAPTER_MANAGER_URL: &'static str = "ipc:///tmp/gateway.addonManager";
+use metrics::Metrics;
+
+const BASE_URL: &str = "ipc:///tmp";
+const ADAPTER_MANAGER_URL: &str = "ipc:///tmp/gateway.addonManager";
+pub const SCHEMA_CONTEXT: &str = "https://iot.mozilla.org/schemas";
 
 #[derive(Serialize)]
 #[serde(tag = "messageType", content = "data", rename_all = "camelCase")]
@@ -77,15 +80,24 @@ pub enum GatewayMessage {
         adapter_id: String,
         device_id: String,
     },
+    /// The user gave up on removing a Thing. Its `pluginId` is left unread,
+    /// as there is nothing to undo.
     #[serde(rename_all = "camelCase")]
     CancelRemoveThing {
+        adapter_id: String,
+        device_id: String,
+    },
+    /// The user renamed a Thing in the gateway.
+    #[serde(rename_all = "camelCase")]
+    SetTitle {
         plugin_id: String,
         adapter_id: String,
         device_id: String,
+        title: String,
     },
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Clone, Debug, Serialize)]
 #[serde(tag = "messageType", content = "data", rename_all = "camelCase")]
 pub enum PluginMessage {
     #[serde(rename_all = "camelCase")]
@@ -113,6 +125,10 @@ pub enum PluginMessage {
         name: String,
         #[serde(rename = "type")]
         typ: String,
+        #[serde(rename = "@context")]
+        context: String,
+        #[serde(rename = "@type")]
+        capabilities: Vec<String>,
         properties: HashMap<String, PropertyDescription>,
         actions: HashMap<String, ActionDescription>,
     },
@@ -123,6 +139,11 @@ pub enum PluginMessage {
         id: String,
     },
     #[serde(rename_all = "camelCase")]
+    PluginError {
+        plugin_id: String,
+        message: String,
+    },
+    #[serde(rename_all = "camelCase")]
     PropertyChanged {
         plugin_id: String,
         adapter_id: String,
@@ -131,24 +152,153 @@ pub enum PluginMessage {
     },
 }
 
+/// Describes a property following the Web Thing property schema.
 #[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct PropertyDescription {
     pub name: String,
+    #[serde(default)]
     pub value: Value,
+    #[serde(rename = "@type", skip_serializing_if = "Option::is_none", default)]
+    pub at_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub title: Option<String>,
     #[serde(rename = "type")]
     pub typ: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(skip_serializing_if = "Option::is_none", default)]
     pub unit: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(skip_serializing_if = "Option::is_none", default)]
     pub description: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub min: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub max: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub minimum: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub maximum: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub multiple_of: Option<f64>,
+    #[serde(rename = "enum", skip_serializing_if = "Option::is_none", default)]
+    pub enumeration: Option<Vec<Value>>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub read_only: Option<bool>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub links: Vec<Link>,
+    #[serde(default = "default_visible")]
     pub visible: bool,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+fn default_visible() -> bool {
+    true
+}
+
+impl PropertyDescription {
+    /// A visible property with no optional schema fields set.
+    pub fn new(name: &str, typ: &str, value: Value) -> PropertyDescription {
+        PropertyDescription {
+            name: name.to_string(),
+            value,
+            at_type: None,
+            title: None,
+            typ: typ.to_string(),
+            unit: None,
+            description: None,
+            minimum: None,
+            maximum: None,
+            multiple_of: None,
+            enumeration: None,
+            read_only: None,
+            links: Vec::new(),
+            visible: true,
+        }
+    }
+
+    /// Checks `value` against this description and returns it coerced to
+    /// the property's schema: integers are rounded, numbers are snapped to
+    /// `multipleOf` and clamped to `minimum`/`maximum`. Rejected values are
+    /// reported as `InvalidInput` errors.
+    pub fn validate(&self, value: &Value) -> Result<Value, io::Error> {
+        if self.read_only == Some(true) {
+            return Err(invalid_value(format!("Property {} is read-only", self.name)));
+        }
+        self.coerce(value)
+    }
+
+    /// Like `validate`, but also for read-only properties, whose values
+    /// come from the device rather than from the gateway.
+    pub fn coerce(&self, value: &Value) -> Result<Value, io::Error> {
+        let coerced = match self.typ.as_str() {
+            "boolean" => match *value {
+                Value::Bool(_) => value.clone(),
+                _ => return Err(self.type_mismatch(value)),
+            },
+            "integer" | "number" => {
+                let mut n = match value.as_f64() {
+                    Some(n) => n,
+                    None => return Err(self.type_mismatch(value)),
+                };
+                if let Some(step) = self.multiple_of {
+                    if step > 0.0 {
+                        n = (n / step).round() * step;
+                    }
+                }
+                if let Some(min) = self.minimum {
+                    n = n.max(min);
+                }
+                if let Some(max) = self.maximum {
+                    n = n.min(max);
+                }
+                if self.typ == "integer" {
+                    Value::from(n.round() as i64)
+                } else {
+                    Value::from(n)
+                }
+            },
+            "string" => match *value {
+                Value::String(_) => value.clone(),
+                _ => return Err(self.type_mismatch(value)),
+            },
+            "object" => match *value {
+                Value::Object(_) => value.clone(),
+                _ => return Err(self.type_mismatch(value)),
+            },
+            "array" => match *value {
+                Value::Array(_) => value.clone(),
+                _ => return Err(self.type_mismatch(value)),
+            },
+            _ => value.clone(),
+        };
+
+        if let Some(ref allowed) = self.enumeration {
+            let matches = allowed.iter().any(|a| {
+                *a == coerced || (a.as_f64().is_some() && a.as_f64() == coerced.as_f64())
+            });
+            if !matches {
+                return Err(invalid_value(format!("{} is not an allowed value for {}",
+                                                 coerced, self.name)));
+            }
+        }
+
+        Ok(coerced)
+    }
+
+    fn type_mismatch(&self, value: &Value) -> io::Error {
+        invalid_value(format!("Expected {} for {}, got {}", self.typ, self.name, value))
+    }
+}
+
+fn invalid_value(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Link {
+    pub href: String,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub rel: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub media_type: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Property {
     pub name: String,
     pub value: Value,
@@ -157,33 +307,96 @@ pub struct Property {
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct ActionDescription {
     pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub description: Option<String>,
+    /// JSON schema of the action's input, if it takes one.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub input: Option<Value>,
+}
+
+impl ActionDescription {
+    /// An action without input.
+    pub fn new(name: &str) -> ActionDescription {
+        ActionDescription {
+            name: name.to_string(),
+            title: None,
+            description: None,
+            input: None,
+        }
+    }
 }
 
 pub struct GatewayBridge {
     id: String,
+    manager_url: String,
     msg_sender: Sender<GatewayMessage>,
-    msg_receiver: Receiver<PluginMessage>
+    msg_receiver: Receiver<(PluginMessage, Instant)>,
+    observers: Vec<Sender<PluginMessage>>,
+    /// The adapters and devices the gateway was told about, announced again
+    /// when the bridge registers anew after failing.
+    announced: Vec<PluginMessage>,
+    metrics: Metrics,
 }
 
 impl GatewayBridge {
-    pub fn new(id: &str) -> (GatewayBridge, Sender<PluginMessage>, Receiver<GatewayMessage>) {
+    pub fn new(id: &str, metrics: Metrics) -> (GatewayBridge, PluginSender, Receiver<GatewayMessage>) {
+        GatewayBridge::with_manager(id, ADAPTER_MANAGER_URL, metrics)
+    }
+
+    /// A bridge registering with the add-on manager listening at
+    /// `manager_url` rather than the gateway's.
+    pub fn with_manager(id: &str, manager_url: &str, metrics: Metrics)
+                        -> (GatewayBridge, PluginSender, Receiver<GatewayMessage>) {
         let (gp_sender, gp_receiver) = channel();
         let (pg_sender, pg_receiver) = channel();
         (
             GatewayBridge {
                 id: id.to_string(),
+                manager_url: manager_url.to_string(),
                 msg_sender: gp_sender,
                 msg_receiver: pg_receiver,
+                observers: Vec::new(),
+                announced: Vec::new(),
+                metrics,
+            },
+            PluginSender {
+                sender: pg_sender,
             },
-            pg_sender,
             gp_receiver
         )
     }
 
+    /// Receives a copy of every message the plugin sends to the gateway.
+    pub fn observe(&mut self) -> Receiver<PluginMessage> {
+        let (sender, receiver) = channel();
+        self.observers.push(sender);
+        receiver
+    }
+
+    /// Delivers messages to the plugin as if the gateway had sent them.
+    pub fn gateway_sender(&self) -> Sender<GatewayMessage> {
+        self.msg_sender.clone()
+    }
+
+    /// Registers with the gateway and relays messages until the plugin is
+    /// unloaded.
     pub fn run_forever(&mut self) -> Result<(), io::Error> {
+        let result = self.serve();
+        self.metrics.update_gateway(|stats| {
+            stats.connected = false;
+            if let Err(ref e) = result {
+                stats.last_error = Some(e.to_string());
+            }
+        });
+        result
+    }
+
+    fn serve(&mut self) -> Result<(), io::Error> {
         let ipc_base_addr = {
             let mut socket = Socket::new(Protocol::Req)?;
-            let mut endpoint = socket.connect(ADAPTER_MANAGER_URL)?;
+            let mut endpoint = socket.connect(&self.manager_url)?;
             let req = PluginRegisterMessage::RegisterPlugin {
                 plugin_id: self.id.to_string()
             };
@@ -205,14 +418,12 @@ impl GatewayBridge {
             // connect to ipcBaseAddr as pair
             // then handle everything
 
-            match msg {
-                GatewayRegisterMessage::RegisterPluginReply {plugin_id, ipc_base_addr} => {
-                    if plugin_id != self.id {
-                        panic!("mismatched plugin id on channel")
-                    }
-                    ipc_base_addr
-                },
+            let GatewayRegisterMessage::RegisterPluginReply {plugin_id, ipc_base_addr} = msg;
+            if plugin_id != self.id {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("registered as {} instead of {}", plugin_id, self.id)));
             }
+            ipc_base_addr
         };
 
         let mut socket_pair = Socket::new(Protocol::Pair)?;
@@ -220,56 +431,197 @@ impl GatewayBridge {
         socket_pair.set_receive_timeout(33)?;
         let mut endpoint_pair = socket_pair.connect(&addr)?;
         thread::sleep(Duration::from_millis(33));
-
+        self.metrics.update_gateway(|stats| stats.connected = true);
+        if !self.announced.is_empty() {
+            info!("registered again, announcing {} adapters and devices", self.announced.len());
+        }
+        for msg in &self.announced {
+            socket_pair.write_all(serde_json::to_string(msg)?.as_bytes())?;
+        }
 
         loop {
             let mut buf = Vec::new();
-            match socket_pair.read_to_end(&mut buf) {
-                Ok(_) => {
-                    match serde_json::from_slice(&buf) {
-                        Ok(msg) => self.msg_sender.send(msg).unwrap(),
-                        Err(e) => println!("parse fail {:?}", e),
-                    }
-                },
-                Err(_) => {
+            if socket_pair.read_to_end(&mut buf).is_ok() {
+                self.metrics.update_gateway(|stats| stats.messages_in += 1);
+                match serde_json::from_slice(&buf) {
+                    Ok(msg) => self.msg_sender.send(msg).map_err(to_io_error)?,
+                    Err(e) => {
+                        warn!("unreadable message from the gateway: {}", e);
+                        self.metrics.update_gateway(|stats| stats.last_error = Some(e.to_string()));
+                    },
                 }
             }
 
 
-            if let Ok(msg_to_send) = self.msg_receiver.try_recv() {
-                socket_pair.write_all(serde_json::to_string(&msg_to_send)?.as_bytes()).unwrap();
-                match msg_to_send {
-                    PluginMessage::PluginUnloaded {..} => {
-                        println!("run_forever exiting");
-                        endpoint_pair.shutdown()?;
-                        return Ok(());
-                    }
-                    _ => {}
+            if let Ok((msg_to_send, sent)) = self.msg_receiver.try_recv() {
+                self.remember(&msg_to_send);
+                socket_pair.write_all(serde_json::to_string(&msg_to_send)?.as_bytes())?;
+                self.metrics.update_gateway(|stats| {
+                    stats.messages_out += 1;
+                    stats.latency = Some(sent.elapsed());
+                });
+                self.observers.retain(|observer| observer.send(msg_to_send.clone()).is_ok());
+                if let PluginMessage::PluginUnloaded {..} = msg_to_send {
+                    info!("plugin unloaded, closing the gateway connection");
+                    endpoint_pair.shutdown()?;
+                    return Ok(());
                 }
             }
 
             thread::sleep(Duration::from_millis(33));
         }
     }
+
+    /// Keeps `announced` in step with a message for the gateway.
+    fn remember(&mut self, msg: &PluginMessage) {
+        match *msg {
+            PluginMessage::AddAdapter { ref adapter_id, .. } |
+            PluginMessage::AdapterUnloaded { ref adapter_id, .. } => {
+                self.announced.retain(|announced| announced_adapter(announced) != Some(adapter_id));
+            },
+            PluginMessage::HandleDeviceAdded { ref id, .. } |
+            PluginMessage::HandleDeviceRemoved { ref id, .. } => {
+                self.announced.retain(|announced| announced_device(announced) != Some(id));
+            },
+            PluginMessage::PropertyChanged { ref device_id, ref property, .. } => {
+                for announced in &mut self.announced {
+                    if let PluginMessage::HandleDeviceAdded { ref id, ref mut properties, .. } = *announced {
+                        if let (true, Some(descr)) = (id == device_id, properties.get_mut(&property.name)) {
+                            descr.value = property.value.clone();
+                        }
+                    }
+                }
+            },
+            _ => {},
+        }
+        match *msg {
+            PluginMessage::AddAdapter { .. } | PluginMessage::HandleDeviceAdded { .. } => {
+                self.announced.push(msg.clone());
+            },
+            _ => {},
+        }
+    }
+}
+
+/// The adapter an announcement is about, its devices included.
+fn announced_adapter(msg: &PluginMessage) -> Option<&String> {
+    match *msg {
+        PluginMessage::AddAdapter { ref adapter_id, .. } |
+        PluginMessage::HandleDeviceAdded { ref adapter_id, .. } => Some(adapter_id),
+        _ => None,
+    }
+}
+
+fn announced_device(msg: &PluginMessage) -> Option<&String> {
+    match *msg {
+        PluginMessage::HandleDeviceAdded { ref id, .. } => Some(id),
+        _ => None,
+    }
+}
+
+/// What a message is about, as `adapter/device` when it concerns a device.
+fn subject(msg: &GatewayMessage) -> String {
+    match *msg {
+        GatewayMessage::SetProperty { ref adapter_id, ref device_id, .. } |
+        GatewayMessage::RequestAction { ref adapter_id, ref device_id, .. } |
+        GatewayMessage::RemoveThing { ref adapter_id, ref device_id, .. } |
+        GatewayMessage::CancelRemoveThing { ref adapter_id, ref device_id, .. } |
+        GatewayMessage::SetTitle { ref adapter_id, ref device_id, .. } => {
+            format!("{}/{}", adapter_id, device_id)
+        },
+        GatewayMessage::UnloadAdapter { ref adapter_id, .. } |
+        GatewayMessage::StartPairing { ref adapter_id, .. } |
+        GatewayMessage::CancelPairing { ref adapter_id, .. } => adapter_id.clone(),
+        GatewayMessage::UnloadPlugin { ref plugin_id } => plugin_id.clone(),
+    }
 }
 
 fn to_io_error<E>(err: E) -> io::Error
-    where E: Into<Box<std::error::Error+Send+Sync>> {
-    io::Error::new(io::ErrorKind::Other, err)
+    where E: Into<Box<dyn std::error::Error+Send+Sync>> {
+    io::Error::other(err)
+}
+
+/// Hands messages for the gateway to the `GatewayBridge`, noting when each
+/// was sent so that the bridge can tell how long it waited.
+#[derive(Clone)]
+pub struct PluginSender {
+    sender: Sender<(PluginMessage, Instant)>,
+}
+
+impl PluginSender {
+    pub fn send(&self, msg: PluginMessage) -> Result<(), io::Error> {
+        self.sender.send((msg, Instant::now())).map_err(to_io_error)
+    }
+}
+
+/// Lets an adapter announce device additions and removals to the gateway at
+/// any point after it has been registered, not just when the plugin starts.
+#[derive(Clone)]
+pub struct AdapterHandle {
+    plugin_id: String,
+    adapter_id: String,
+    sender: PluginSender,
+}
+
+impl AdapterHandle {
+    pub fn adapter_id(&self) -> &str {
+        &self.adapter_id
+    }
+
+    pub fn handle_device_added<D:Device + ?Sized>(&self, device_id: &str, device: &D) -> Result<(), io::Error> {
+        self.sender.send(PluginMessage::HandleDeviceAdded {
+            plugin_id: self.plugin_id.clone(),
+            adapter_id: self.adapter_id.clone(),
+            id: device_id.to_string(),
+            name: device.get_name(),
+            typ: device.get_type(),
+            context: device.get_context(),
+            capabilities: device.get_capabilities(),
+            actions: device.get_actions(),
+            properties: device.get_properties(),
+        })
+    }
+
+    pub fn property_changed(&self, device_id: &str, property: Property) -> Result<(), io::Error> {
+        self.sender.send(PluginMessage::PropertyChanged {
+            plugin_id: self.plugin_id.clone(),
+            adapter_id: self.adapter_id.clone(),
+            device_id: device_id.to_string(),
+            property,
+        })
+    }
+
+    pub fn handle_device_removed(&self, device_id: &str) -> Result<(), io::Error> {
+        self.sender.send(PluginMessage::HandleDeviceRemoved {
+            plugin_id: self.plugin_id.clone(),
+            adapter_id: self.adapter_id.clone(),
+            id: device_id.to_string(),
+        })
+    }
 }
 
 pub trait Device {
     fn set_property(&mut self, property: Property) -> Result<Property, io::Error>;
-    fn request_action(&mut self, name: String) -> Result<(), io::Error>;
+    fn request_action(&mut self, name: String, input: Value) -> Result<(), io::Error>;
 
     fn get_name(&self) -> String {
         "Unknown Device".to_string()
     }
 
+    /// Legacy device type, kept for gateways that predate capability schemas.
     fn get_type(&self) -> String {
         "thing".to_string()
     }
 
+    fn get_context(&self) -> String {
+        SCHEMA_CONTEXT.to_string()
+    }
+
+    /// Capability `@type` annotations, e.g. `["OnOffSwitch", "Light"]`.
+    fn get_capabilities(&self) -> Vec<String> {
+        Vec::new()
+    }
+
     fn get_actions(&self) -> HashMap<String, ActionDescription> {
         HashMap::new()
     }
@@ -285,31 +637,57 @@ pub trait Adapter<T:Device> {
     }
     fn get_devices(&self) -> &HashMap<String, Box<T>>;
 
-    fn start_pairing(&mut self) -> Result<(), io::Error>;
+    /// Starts looking for new devices for up to `timeout` seconds.
+    fn start_pairing(&mut self, timeout: f64) -> Result<(), io::Error>;
 
     fn cancel_pairing(&mut self) -> Result<(), io::Error>;
 
     fn set_property(&mut self, device_id: &str, property: Property) -> Result<Property, io::Error>;
-    fn request_action(&mut self, device_id: &str, name: String) -> Result<(), io::Error>;
+    fn request_action(&mut self, device_id: &str, name: String, input: Value) -> Result<(), io::Error>;
+
+    /// Removes a device at the gateway's request. Implementations are
+    /// expected to drop it from `get_devices` and report the removal through
+    /// their `AdapterHandle`.
+    fn remove_thing(&mut self, _device_id: &str) -> Result<(), io::Error> {
+        Err(io::Error::other("Removal not supported"))
+    }
+
+    /// Takes note of the name the user gave a device in the gateway.
+    fn set_title(&mut self, _device_id: &str, _title: &str) -> Result<(), io::Error> {
+        Ok(())
+    }
+
+    /// Called before the adapter is unloaded, to write out what it still
+    /// holds.
+    fn unload(&mut self) -> Result<(), io::Error> {
+        Ok(())
+    }
+
+    /// Called on every pass of `Plugin::run_forever` so the adapter can
+    /// process work that does not come from the gateway, such as inbound
+    /// device state.
+    fn poll(&mut self) -> Result<(), io::Error> {
+        Ok(())
+    }
 }
 
 pub struct Plugin<D:Device, A:Adapter<D>> {
     package_name: String,
     plugin_id: String,
     adapters: HashMap<String, Box<A>>,
-    sender: Sender<PluginMessage>,
+    sender: PluginSender,
     receiver: Receiver<GatewayMessage>,
     _marker: std::marker::PhantomData<D>,
 }
 
 impl<D:Device, A:Adapter<D>> Plugin<D, A> {
-    pub fn new(package_name: &str, plugin_id: &str, sender: Sender<PluginMessage>,
+    pub fn new(package_name: &str, plugin_id: &str, sender: PluginSender,
                receiver: Receiver<GatewayMessage>) -> Plugin<D, A> {
         Plugin {
             package_name: package_name.to_string(),
             plugin_id: plugin_id.to_string(),
-            sender: sender,
-            receiver: receiver,
+            sender,
+            receiver,
             adapters: HashMap::new(),
             _marker: std::marker::PhantomData,
         }
@@ -328,7 +706,7 @@ impl<D:Device, A:Adapter<D>> Plugin<D, A> {
                     return Ok(())
                 }
 
-                let set_prop = match self.adapters.get_mut(&adapter_id) {
+                let prop = match self.adapters.get_mut(&adapter_id) {
                     Some(adapter) => {
                         let property = Property {
                             name: property_name,
@@ -336,15 +714,14 @@ impl<D:Device, A:Adapter<D>> Plugin<D, A> {
                         };
                         adapter.set_property(&device_id, property)
                     }
-                    None => Err(io::Error::new(io::ErrorKind::Other, "Adapter not found"))
-                };
-                let prop = set_prop?;
+                    None => Err(io::Error::other("Adapter not found"))
+                }?;
                 self.sender.send(PluginMessage::PropertyChanged {
                     plugin_id,
                     adapter_id,
                     device_id,
                     property: prop
-                }).map_err(to_io_error)
+                })
             },
             GatewayMessage::RequestAction {
                 plugin_id,
@@ -352,7 +729,7 @@ impl<D:Device, A:Adapter<D>> Plugin<D, A> {
                 device_id,
                 action_id: _action_id,
                 action_name,
-                input: _input,
+                input,
             } => {
                 if plugin_id != self.plugin_id {
                     return Ok(())
@@ -360,29 +737,55 @@ impl<D:Device, A:Adapter<D>> Plugin<D, A> {
 
                 match self.adapters.get_mut(&adapter_id) {
                     Some(adapter) => {
-                        return adapter.request_action(&device_id, action_name);
+                        adapter.request_action(&device_id, action_name, input)
                     }
-                    None => Err(io::Error::new(io::ErrorKind::Other, "Adapter not found"))
+                    None => Err(io::Error::other("Adapter not found"))
                 }
             },
-            GatewayMessage::UnloadPlugin {..} => {
-                Ok(())
+            GatewayMessage::UnloadPlugin { plugin_id } => {
+                if plugin_id != self.plugin_id {
+                    return Ok(())
+                }
+
+                for (adapter_id, adapter) in self.adapters.iter_mut() {
+                    if let Err(e) = adapter.unload() {
+                        warn!(adapter_id = adapter_id.as_str(); "unload failed: {}", e);
+                    }
+                }
+                self.sender.send(PluginMessage::PluginUnloaded {
+                    plugin_id,
+                })
             },
-            GatewayMessage::UnloadAdapter {..} => {
-                Ok(())
+            GatewayMessage::UnloadAdapter {
+                plugin_id,
+                adapter_id,
+            } => {
+                if plugin_id != self.plugin_id {
+                    return Ok(())
+                }
+
+                if let Some(mut adapter) = self.adapters.remove(&adapter_id) {
+                    if let Err(e) = adapter.unload() {
+                        warn!(adapter_id = adapter_id.as_str(); "unload failed: {}", e);
+                    }
+                }
+                self.sender.send(PluginMessage::AdapterUnloaded {
+                    plugin_id,
+                    adapter_id,
+                })
             },
             GatewayMessage::StartPairing {
                 plugin_id,
                 adapter_id,
-                timeout: _,
+                timeout,
             } => {
                 if plugin_id != self.plugin_id {
                     return Ok(())
                 }
 
                 match self.adapters.get_mut(&adapter_id) {
-                    Some(adapter) => adapter.start_pairing(),
-                    None => Err(io::Error::new(io::ErrorKind::Other, "Adapter not found")),
+                    Some(adapter) => adapter.start_pairing(timeout),
+                    None => Err(io::Error::other("Adapter not found")),
                 }
             },
             GatewayMessage::CancelPairing {
@@ -395,22 +798,71 @@ impl<D:Device, A:Adapter<D>> Plugin<D, A> {
 
                 match self.adapters.get_mut(&adapter_id) {
                     Some(adapter) => adapter.cancel_pairing(),
-                    None => Err(io::Error::new(io::ErrorKind::Other, "Adapter not found")),
+                    None => Err(io::Error::other("Adapter not found")),
                 }
             },
-            GatewayMessage::RemoveThing { .. } => {
-                Ok(())
+            GatewayMessage::RemoveThing {
+                plugin_id,
+                adapter_id,
+                device_id,
+            } => {
+                if plugin_id != self.plugin_id {
+                    return Ok(())
+                }
+
+                match self.adapters.get_mut(&adapter_id) {
+                    Some(adapter) => adapter.remove_thing(&device_id),
+                    None => Err(io::Error::other("Adapter not found")),
+                }
             },
             GatewayMessage::CancelRemoveThing { .. } => {
                 Ok(())
+            },
+            GatewayMessage::SetTitle {
+                plugin_id,
+                adapter_id,
+                device_id,
+                title,
+            } => {
+                if plugin_id != self.plugin_id {
+                    return Ok(())
+                }
+
+                match self.adapters.get_mut(&adapter_id) {
+                    Some(adapter) => adapter.set_title(&device_id, &title),
+                    None => Err(io::Error::other("Adapter not found")),
+                }
             }
         }
     }
 
+    /// Logs a request that failed and reports it to the gateway.
+    fn report(&self, subject: &str, error: io::Error) -> Result<(), io::Error> {
+        warn!("{}: {}", subject, error);
+        self.sender.send(PluginMessage::PluginError {
+            plugin_id: self.plugin_id.clone(),
+            message: format!("{}: {}", subject, error),
+        })
+    }
+
+    /// Creates the handle an adapter uses to add or remove devices after
+    /// `run_forever` has announced the initial set.
+    pub fn adapter_handle(&self, adapter_id: &str) -> AdapterHandle {
+        AdapterHandle {
+            plugin_id: self.plugin_id.clone(),
+            adapter_id: adapter_id.to_string(),
+            sender: self.sender.clone(),
+        }
+    }
+
     pub fn add_adapter(&mut self, adapter_id: &str, adapter: Box<A>) {
         self.adapters.insert(adapter_id.to_string(), adapter);
     }
 
+    /// Announces the adapters and their devices, then serves the gateway
+    /// until it unloads the plugin. A request that fails is reported to the
+    /// gateway and does not stop the plugin; neither does an adapter failing
+    /// to poll.
     pub fn run_forever(&mut self) -> Result<(), io::Error> {
         for (adapter_id, adapter) in &self.adapters {
             self.sender.send(PluginMessage::AddAdapter {
@@ -418,24 +870,33 @@ impl<D:Device, A:Adapter<D>> Plugin<D, A> {
                 package_name: self.package_name.clone(),
                 adapter_id: adapter_id.clone(),
                 name: adapter.get_name()
-            }).map_err(to_io_error)?;
+            })?;
+            let handle = self.adapter_handle(adapter_id);
             for (device_id, device) in adapter.get_devices() {
-                self.sender.send(PluginMessage::HandleDeviceAdded {
-                    plugin_id: self.plugin_id.clone(),
-                    adapter_id: adapter_id.clone(),
-                    id: device_id.clone(),
-                    name: device.get_name(),
-                    typ: device.get_type(),
-                    actions: device.get_actions(),
-                    properties: device.get_properties(),
-                }).map_err(to_io_error)?;
+                handle.handle_device_added(device_id, device.as_ref())?;
             }
         }
 
         loop {
+            for (adapter_id, adapter) in self.adapters.iter_mut() {
+                if let Err(e) = adapter.poll() {
+                    warn!(adapter_id = adapter_id.as_str(); "poll failed: {}", e);
+                }
+            }
+
             match self.receiver.try_recv() {
                 Ok(msg) => {
-                    self.handle_msg(msg)?;
+                    let unload = match msg {
+                        GatewayMessage::UnloadPlugin { ref plugin_id } => *plugin_id == self.plugin_id,
+                        _ => false,
+                    };
+                    let subject = subject(&msg);
+                    if let Err(e) = self.handle_msg(msg) {
+                        self.report(&subject, e)?;
+                    }
+                    if unload {
+                        return Ok(());
+                    }
                 },
                 _ => {
                     thread::sleep(Duration::from_millis(33));
@@ -444,3 +905,129 @@ impl<D:Device, A:Adapter<D>> Plugin<D, A> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device_added(adapter_id: &str, id: &str) -> PluginMessage {
+        let mut properties = HashMap::new();
+        properties.insert("on".to_string(), PropertyDescription::new("on", "boolean", Value::Bool(false)));
+        PluginMessage::HandleDeviceAdded {
+            plugin_id: "plugin".to_string(),
+            adapter_id: adapter_id.to_string(),
+            id: id.to_string(),
+            name: id.to_string(),
+            typ: String::new(),
+            context: String::new(),
+            capabilities: Vec::new(),
+            properties,
+            actions: HashMap::new(),
+        }
+    }
+
+    fn announced(bridge: &GatewayBridge) -> Vec<String> {
+        bridge.announced.iter().map(|msg| match *msg {
+            PluginMessage::AddAdapter { ref adapter_id, .. } => adapter_id.clone(),
+            PluginMessage::HandleDeviceAdded { ref id, ref properties, .. } => {
+                format!("{}={}", id, properties["on"].value)
+            },
+            _ => panic!("unexpected announcement"),
+        }).collect()
+    }
+
+    #[test]
+    fn bridge_remembers_announcements() {
+        let (mut bridge, _, _) = GatewayBridge::new("plugin", Metrics::new());
+        bridge.remember(&PluginMessage::AddAdapter {
+            plugin_id: "plugin".to_string(),
+            adapter_id: "a".to_string(),
+            name: "A".to_string(),
+            package_name: "plugin".to_string(),
+        });
+        bridge.remember(&device_added("a", "lamp"));
+        bridge.remember(&device_added("a", "door"));
+        bridge.remember(&PluginMessage::PropertyChanged {
+            plugin_id: "plugin".to_string(),
+            adapter_id: "a".to_string(),
+            device_id: "lamp".to_string(),
+            property: Property { name: "on".to_string(), value: Value::Bool(true) },
+        });
+        bridge.remember(&PluginMessage::HandleDeviceRemoved {
+            plugin_id: "plugin".to_string(),
+            adapter_id: "a".to_string(),
+            id: "door".to_string(),
+        });
+        assert_eq!(announced(&bridge), vec!["a", "lamp=true"]);
+
+        bridge.remember(&PluginMessage::AdapterUnloaded {
+            plugin_id: "plugin".to_string(),
+            adapter_id: "a".to_string(),
+        });
+        assert!(announced(&bridge).is_empty());
+    }
+
+    fn level() -> PropertyDescription {
+        PropertyDescription {
+            minimum: Some(0.0),
+            maximum: Some(100.0),
+            multiple_of: Some(5.0),
+            ..PropertyDescription::new("level", "integer", Value::from(0))
+        }
+    }
+
+    #[test]
+    fn validate_coerces_numbers() {
+        let level = level();
+        assert_eq!(level.validate(&json!(42)).unwrap(), json!(40));
+        assert_eq!(level.validate(&json!(43.2)).unwrap(), json!(45));
+        assert_eq!(level.validate(&json!(-10)).unwrap(), json!(0));
+        assert_eq!(level.validate(&json!(250)).unwrap(), json!(100));
+
+        let ratio = PropertyDescription::new("ratio", "number", Value::from(0.0));
+        assert_eq!(ratio.validate(&json!(0.25)).unwrap(), json!(0.25));
+    }
+
+    #[test]
+    fn validate_rejects_wrong_types() {
+        let cases = vec![
+            (level(), json!("50")),
+            (PropertyDescription::new("on", "boolean", Value::Bool(false)), json!(1)),
+            (PropertyDescription::new("name", "string", Value::from("")), json!(true)),
+            (PropertyDescription::new("state", "object", json!({})), json!([])),
+            (PropertyDescription::new("list", "array", json!([])), json!({})),
+        ];
+        for (descr, value) in cases {
+            let err = descr.validate(&value).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{} for {}", value, descr.typ);
+        }
+    }
+
+    #[test]
+    fn validate_checks_enumerations() {
+        let mode = PropertyDescription {
+            enumeration: Some(vec![json!("heat"), json!("cool")]),
+            ..PropertyDescription::new("mode", "string", Value::from("heat"))
+        };
+        assert_eq!(mode.validate(&json!("cool")).unwrap(), json!("cool"));
+        assert!(mode.validate(&json!("dry")).is_err());
+
+        // Allowed numbers match whatever their JSON representation.
+        let speed = PropertyDescription {
+            enumeration: Some(vec![json!(1.0), json!(2.0)]),
+            ..PropertyDescription::new("speed", "integer", Value::from(1))
+        };
+        assert_eq!(speed.validate(&json!(2)).unwrap(), json!(2));
+    }
+
+    #[test]
+    fn read_only_properties_are_only_coerced() {
+        let temperature = PropertyDescription {
+            read_only: Some(true),
+            ..PropertyDescription::new("temperature", "integer", Value::from(0))
+        };
+        assert!(temperature.validate(&json!(21)).is_err());
+        assert_eq!(temperature.coerce(&json!(21.4)).unwrap(), json!(21));
+        assert!(temperature.coerce(&json!("warm")).is_err());
+    }
+}
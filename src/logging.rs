@@ -0,0 +1,186 @@
+//! Leveled logging behind the `log` facade, filtered per module as set in
+//! `LoggingConfig`.
+//!
+//! Records may carry fields, as in `info!(device_id = id; "removed")`, which
+//! are printed after the message as `key=value`. Secrets registered with
+//! `redact` are masked wherever they appear, and fields named like
+//! credentials are never printed.
+
+use std::cmp::Reverse;
+use std::fmt::Write as FmtWrite;
+use std::io::{self, Write};
+use std::sync::RwLock;
+
+use log::{self, Level, LevelFilter, Log, Metadata, Record};
+use log::kv::{self, Key, Value, VisitSource};
+
+use config::LoggingConfig;
+
+const REDACTED: &str = "***";
+
+/// Fields whose values are masked whatever they hold.
+const SECRET_FIELDS: &[&str] = &["password", "secret", "token"];
+
+/// Longest first, so that a secret containing another is masked whole.
+static SECRETS: RwLock<Vec<String>> = RwLock::new(Vec::new());
+
+struct Logger {
+    level: LevelFilter,
+    /// Levels of modules and their submodules, most specific first.
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl Logger {
+    fn level_for(&self, target: &str) -> LevelFilter {
+        let module = module(target);
+        for &(ref path, level) in &self.modules {
+            if module.starts_with(path.as_str()) &&
+                (module.len() == path.len() || module[path.len()..].starts_with("::")) {
+                return level;
+            }
+        }
+        self.level
+    }
+
+    fn format(&self, record: &Record) -> String {
+        let mut line = format!("{:<5} {}: {}", record.level(), module(record.target()), record.args());
+        let _ = record.key_values().visit(&mut Fields(&mut line));
+        mask(&line)
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = self.format(record);
+        let _ = match record.level() {
+            Level::Error | Level::Warn => writeln!(io::stderr(), "{}", line),
+            _ => writeln!(io::stdout(), "{}", line),
+        };
+    }
+
+    fn flush(&self) {
+        let _ = io::stdout().flush();
+    }
+}
+
+/// Appends the fields of a record to its line.
+struct Fields<'a>(&'a mut String);
+
+impl<'a, 'kvs> VisitSource<'kvs> for Fields<'a> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let name = key.as_str().to_lowercase();
+        let value = match SECRET_FIELDS.iter().any(|secret| name.contains(secret)) {
+            true => REDACTED.to_string(),
+            false => value.to_string(),
+        };
+        if value.is_empty() || value.contains(|c: char| c.is_whitespace() || c == '=') {
+            let _ = write!(self.0, " {}={:?}", key, value);
+        } else {
+            let _ = write!(self.0, " {}={}", key, value);
+        }
+        Ok(())
+    }
+}
+
+/// Strips this crate's name from `target`, so that modules are configured
+/// as `mqtt` rather than `mqtt_adapter::mqtt`.
+fn module(target: &str) -> &str {
+    let root = module_path!().split("::").next().unwrap_or("");
+    match target.starts_with(root) && target[root.len()..].starts_with("::") {
+        true => &target[root.len() + 2..],
+        false => target,
+    }
+}
+
+fn mask(line: &str) -> String {
+    let mut line = line.to_string();
+    for secret in SECRETS.read().unwrap().iter() {
+        line = line.replace(secret.as_str(), REDACTED);
+    }
+    line
+}
+
+/// Masks `secret` in everything logged from now on.
+pub fn redact(secret: &str) {
+    if secret.is_empty() {
+        return;
+    }
+    let mut secrets = SECRETS.write().unwrap();
+    if !secrets.iter().any(|s| s == secret) {
+        secrets.push(secret.to_string());
+        secrets.sort_by_key(|s| Reverse(s.len()));
+    }
+}
+
+/// Installs the logger. Anything logged before is dropped.
+pub fn init(config: &LoggingConfig) -> Result<(), io::Error> {
+    let level = config.level()?;
+    let mut modules = config.module_levels()?;
+    modules.sort_by_key(|m| Reverse(m.0.len()));
+    let max = modules.iter().fold(level, |max, &(_, level)| max.max(level));
+    log::set_boxed_logger(Box::new(Logger {
+        level,
+        modules,
+    })).map_err(io::Error::other)?;
+    log::set_max_level(max);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn logger() -> Logger {
+        Logger {
+            level: LevelFilter::Info,
+            modules: vec![
+                ("discovery::shelly".to_string(), LevelFilter::Off),
+                ("mqtt".to_string(), LevelFilter::Debug),
+            ],
+        }
+    }
+
+    #[test]
+    fn module_levels() {
+        let logger = logger();
+        assert_eq!(logger.level_for("mqtt_adapter::mqtt"), LevelFilter::Debug);
+        assert_eq!(logger.level_for("mqtt_adapter::mqtt::session"), LevelFilter::Debug);
+        assert_eq!(logger.level_for("mqtt_adapter::mqtt_extra"), LevelFilter::Info);
+        assert_eq!(logger.level_for("mqtt_adapter::discovery::shelly"), LevelFilter::Off);
+        assert_eq!(logger.level_for("mqtt_adapter::discovery"), LevelFilter::Info);
+        assert_eq!(logger.level_for("mqtt_adapter"), LevelFilter::Info);
+    }
+
+    #[test]
+    fn fields_follow_message() {
+        let fields = [("device_id", "mqtt-0-lamp"), ("topic", "a b")];
+        let line = logger().format(&Record::builder()
+            .level(Level::Warn)
+            .target("mqtt_adapter::queue")
+            .args(format_args!("dropped {}", 1))
+            .key_values(&fields)
+            .build());
+        assert_eq!(line, "WARN  queue: dropped 1 device_id=mqtt-0-lamp topic=\"a b\"");
+    }
+
+    #[test]
+    fn secrets_are_masked() {
+        redact("hunter2");
+        redact("hunter22");
+        let fields = [("server", "hunter22@example.com"), ("Password", "letmein")];
+        let line = logger().format(&Record::builder()
+            .level(Level::Info)
+            .target("mqtt_adapter::mqtt")
+            .args(format_args!("logging in with hunter2"))
+            .key_values(&fields)
+            .build());
+        assert_eq!(line, "INFO  mqtt: logging in with *** server=***@example.com Password=***");
+    }
+}
@@ -1,77 +1,345 @@
+#[macro_use]
+extern crate log;
 extern crate mqtt3;
 extern crate nanomsg;
+extern crate native_tls;
+extern crate regex;
 #[macro_use]
 extern crate serde_derive;
+#[macro_use]
 extern crate serde_json;
+#[cfg(test)]
+extern crate proptest;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::io;
-use std::thread;
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
 
 use serde_json::Value;
 
+mod broker;
+mod capabilities;
+mod color;
 mod config;
+mod discovery;
+mod esphome;
+mod exporter;
 mod mqtt;
 mod gateway;
+mod logging;
+mod metrics;
+mod mirror;
+mod payload;
+mod protobuf;
+mod queue;
+mod registry;
+mod router;
+mod shelly;
+mod sparkplug;
+mod status;
+mod supervisor;
+mod tasmota;
+#[cfg(test)]
+mod tests;
+mod topics;
+mod transport;
+mod zigbee2mqtt;
+
+use capabilities::Capability;
+use config::{Config, DeviceConfig};
+use discovery::{Discovery, DiscoveryEvent};
+use esphome::ESPHome;
+use mqtt::mqtt_error;
+use payload::PayloadFormat;
+use queue::OfflineQueue;
+use broker::Broker;
+use registry::{Registry, RegisteredDevice};
+use router::Router;
+use shelly::Shelly;
+use sparkplug::Sparkplug;
+use status::Status;
+use tasmota::Tasmota;
+use topics::{DeviceTopics, TopicScheme};
+use zigbee2mqtt::Zigbee2Mqtt;
+use exporter::Exporter;
+use metrics::Metrics;
+use mirror::ThingMirror;
+use gateway::{Device, Adapter, AdapterHandle, Plugin, GatewayBridge, Property, PropertyDescription, ActionDescription};
 
-use gateway::{Device, Adapter, Plugin, GatewayBridge, Property, PropertyDescription, ActionDescription};
+/// How long startup waits for the broker to deliver retained state before
+/// the devices are announced.
+const RETAINED_WAIT: u64 = 500;
 
 struct MQTTDevice {
+    name: String,
+    typ: String,
+    capabilities: Vec<String>,
     prop_descrs: HashMap<String, PropertyDescription>,
     action_descrs: HashMap<String, ActionDescription>,
     props: HashMap<String, Value>,
-    mqtt: mqtt::MQTT
+    formats: HashMap<String, PayloadFormat>,
+    topics: DeviceTopics,
+    mqtt: mqtt::MQTT,
+    /// Properties are set by the adapter rather than reported on the broker.
+    local: bool,
 }
 
 impl MQTTDevice {
-    fn new(mqtt: mqtt::MQTT) -> MQTTDevice {
-        let mut props = HashMap::new();
-        let mut prop_descrs = HashMap::new();
-        props.insert("on".to_string(), Value::Bool(true));
-        prop_descrs.insert("on".to_string(), PropertyDescription {
-            name: "on".to_string(),
-            value: Value::Bool(true),
-            typ: "boolean".to_string(),
-            description: None,
-            unit: None,
-            min: None,
-            max: None,
-            visible: true,
-        });
-        let mut action_descrs = HashMap::new();
-        action_descrs.insert("forward".to_string(), ActionDescription {
-            name: "forward".to_string(),
-        });
-        action_descrs.insert("backward".to_string(), ActionDescription {
-            name: "backward".to_string(),
-        });
+    fn new(name: &str, mqtt: mqtt::MQTT) -> Result<MQTTDevice, io::Error> {
+        let topics = DeviceTopics::new(TopicScheme::preset("adafruit")?, mqtt.username(), "");
+        let mut device = MQTTDevice::from_capability(name, Capability::OnOffSwitch, topics, mqtt);
+        device.add_action(ActionDescription::new("forward"));
+        device.add_action(ActionDescription::new("backward"));
+        Ok(device)
+    }
+
+    /// Builds a device annotated with the `@type`s and properties of a
+    /// capability preset.
+    fn from_capability(name: &str, capability: Capability, topics: DeviceTopics,
+                       mqtt: mqtt::MQTT) -> MQTTDevice {
+        let mut device = MQTTDevice {
+            name: name.to_string(),
+            typ: capability.legacy_type().to_string(),
+            capabilities: capability.types(),
+            props: HashMap::new(),
+            prop_descrs: HashMap::new(),
+            action_descrs: HashMap::new(),
+            formats: HashMap::new(),
+            topics,
+            mqtt,
+            local: false,
+        };
+        for descr in capability.properties() {
+            device.add_property(descr);
+        }
+        device
+    }
+
+    fn from_config(config: &DeviceConfig, mqtt: mqtt::MQTT) -> Result<MQTTDevice, io::Error> {
+        let scheme = config.topic_scheme()?;
+        let base = match config.base {
+            Some(ref base) => base.clone(),
+            None if scheme.base.is_empty() => mqtt.username().to_string(),
+            None => scheme.base.clone(),
+        };
+        let layout_payloads = scheme.payloads.clone();
+        let topics = DeviceTopics::new(scheme, &base, config.topic.as_ref().unwrap_or(&config.id));
+        let mut device = match config.capability {
+            Some(ref name) => match Capability::from_name(name) {
+                Some(capability) => MQTTDevice::from_capability(&config.name, capability,
+                                                                topics, mqtt),
+                None => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                                  format!("Unknown capability {}", name))),
+            },
+            None => MQTTDevice {
+                name: config.name.clone(),
+                typ: "thing".to_string(),
+                capabilities: Vec::new(),
+                props: HashMap::new(),
+                prop_descrs: HashMap::new(),
+                action_descrs: HashMap::new(),
+                formats: HashMap::new(),
+                topics,
+                mqtt,
+                local: false,
+            },
+        };
+        if !config.types.is_empty() {
+            device.capabilities = config.types.clone();
+            if config.capability.is_none() {
+                device.typ = capabilities::legacy_type_for(&config.types).to_string();
+            }
+        }
+        for descr in &config.properties {
+            device.add_property(descr.clone());
+        }
+        for descr in &config.actions {
+            device.add_action(descr.clone());
+        }
+        for (name, format) in layout_payloads.iter().chain(&config.payloads) {
+            device.set_payload_format(name, format.clone())?;
+        }
+        Ok(device)
+    }
+
+    /// A device whose properties the adapter sets itself, without any
+    /// topics on the broker.
+    fn local(name: &str, properties: Vec<PropertyDescription>,
+             mqtt: mqtt::MQTT) -> Result<MQTTDevice, io::Error> {
+        let mut device = MQTTDevice {
+            name: name.to_string(),
+            typ: "thing".to_string(),
+            capabilities: Vec::new(),
+            props: HashMap::new(),
+            prop_descrs: HashMap::new(),
+            action_descrs: HashMap::new(),
+            formats: HashMap::new(),
+            topics: DeviceTopics::new(TopicScheme::preset("generic")?, "", ""),
+            mqtt,
+            local: true,
+        };
+        for descr in properties {
+            device.add_property(descr);
+        }
+        Ok(device)
+    }
+
+    fn set_payload_format(&mut self, prop: &str, mut format: PayloadFormat) -> Result<(), io::Error> {
+        format.prepare()?;
+        self.formats.insert(prop.to_string(), format);
+        Ok(())
+    }
+
+    fn format(&self, prop: &str) -> PayloadFormat {
+        self.formats.get(prop).cloned().unwrap_or_default()
+    }
+
+    fn command_topic(&self, prop: &str) -> String {
+        match self.formats.get(prop).and_then(|f| f.command_topic.as_ref()) {
+            Some(template) => self.topics.property_topic(template, prop),
+            None => self.topics.command(prop),
+        }
+    }
+
+    fn property_state_topics(&self, prop: &str) -> Vec<String> {
+        match self.formats.get(prop) {
+            Some(format) if format.write_only => Vec::new(),
+            Some(format) if !format.state_topics.is_empty() => format.state_topics.iter()
+                .map(|template| self.topics.property_topic(template, prop))
+                .collect(),
+            _ => vec![self.topics.state(prop)],
+        }
+    }
+
+    /// Topics carrying this device's reported state.
+    fn state_topics(&self) -> Vec<String> {
+        if self.local {
+            return Vec::new();
+        }
+        let mut topics: Vec<String> = self.prop_descrs.keys()
+            .flat_map(|name| self.property_state_topics(name))
+            .collect();
+        topics.sort();
+        topics.dedup();
+        topics
+    }
 
-        MQTTDevice {
-            props: props,
-            prop_descrs: prop_descrs,
-            action_descrs: action_descrs,
-            mqtt: mqtt
+    /// Applies an inbound publish, returning the properties whose value
+    /// changed as a result.
+    fn handle_publish(&mut self, topic: &str, payload: &[u8]) -> Vec<Property> {
+        let names: Vec<String> = self.prop_descrs.keys()
+            .filter(|name| self.property_state_topics(name).iter().any(|t| router::matches(t, topic)))
+            .cloned()
+            .collect();
+        names.into_iter()
+            .filter_map(|name| {
+                let value = self.format(&name).decode(payload)?;
+                let value = match self.prop_descrs[&name].coerce(&value) {
+                    Ok(value) => value,
+                    Err(e) => {
+                        warn!(device = self.name.as_str(), topic = topic; "ignoring value: {}", e);
+                        return None;
+                    },
+                };
+                self.update_property(&name, value)
+            })
+            .collect()
+    }
+
+    /// Applies what the registry remembers about this device.
+    fn restore(&mut self, registered: &RegisteredDevice) {
+        if let Some(ref name) = registered.name {
+            self.name = name.clone();
+        }
+        for (name, value) in &registered.values {
+            if self.prop_descrs.contains_key(name) {
+                self.props.insert(name.clone(), value.clone());
+            }
+        }
+    }
+
+    /// Records a reported value, returning the property if it changed.
+    fn update_property(&mut self, name: &str, value: Value) -> Option<Property> {
+        if !self.prop_descrs.contains_key(name) || self.props.get(name) == Some(&value) {
+            return None;
+        }
+        self.props.insert(name.to_string(), value.clone());
+        Some(Property {
+            name: name.to_string(),
+            value,
+        })
+    }
+
+    /// The value a property would be set to, or why it cannot be.
+    fn validate(&self, property: &Property) -> Result<Value, io::Error> {
+        match self.prop_descrs.get(&property.name) {
+            Some(descr) => descr.validate(&property.value),
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                       format!("Unknown property {}", property.name)))
         }
     }
 
+    /// Publishes the command setting a property, with its payload already
+    /// encoded.
+    fn send_property(&mut self, name: &str, value: Value, payload: Vec<u8>) -> Result<Property, io::Error> {
+        let topic = self.command_topic(name);
+        self.mqtt.publish_value(&format!("{} {}", topic, name), &topic, payload)
+            .map_err(mqtt_error)?;
+        self.props.insert(name.to_string(), value.clone());
+        Ok(Property {
+            name: name.to_string(),
+            value,
+        })
+    }
+
+    fn add_action(&mut self, descr: ActionDescription) {
+        self.action_descrs.insert(descr.name.clone(), descr);
+    }
+
+    /// Adds or replaces a property from a full description, seeding its
+    /// current value from the description's `value`.
+    fn add_property(&mut self, descr: PropertyDescription) {
+        self.props.insert(descr.name.clone(), descr.value.clone());
+        self.prop_descrs.insert(descr.name.clone(), descr);
+    }
+
 }
 
 impl Device for MQTTDevice {
     fn set_property(&mut self, property: Property) -> Result<Property, io::Error> {
-        self.mqtt.publish_value(&property.name, &property.value)
-            .map_err(|_| return io::Error::new(io::ErrorKind::Other, "mqtt3 error"))?;
-        self.props.insert(property.name.clone(), property.value.clone());
-        Ok(property)
+        let value = self.validate(&property)?;
+        let payload = self.format(&property.name).encode(&value);
+        self.send_property(&property.name, value, payload)
     }
 
-    fn request_action(&mut self, name: String) -> Result<(), io::Error> {
-        self.mqtt.publish_action(&name)
-            .map_err(|_| return io::Error::new(io::ErrorKind::Other, "mqtt3 error"))?;
+    fn request_action(&mut self, name: String, input: Value) -> Result<(), io::Error> {
+        if self.local {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Unknown action {}", name)));
+        }
+        let (topic, payload) = match self.formats.get(&name) {
+            Some(format) => {
+                let topic = match format.command_topic {
+                    Some(ref template) => self.topics.property_topic(template, &name),
+                    None => self.topics.action(&name),
+                };
+                (topic, format.encode(&input))
+            },
+            None => (self.topics.action(&name), self.topics.action_payload(&name)),
+        };
+        self.mqtt.publish(&topic, payload)
+            .map_err(mqtt_error)?;
         Ok(())
     }
 
     fn get_properties(&self) -> HashMap<String, PropertyDescription> {
-        self.prop_descrs.clone()
+        self.prop_descrs.iter().map(|(name, descr)| {
+            let mut descr = descr.clone();
+            if let Some(value) = self.props.get(name) {
+                descr.value = value.clone();
+            }
+            (name.clone(), descr)
+        }).collect()
     }
 
     fn get_actions(&self) -> HashMap<String, ActionDescription> {
@@ -79,58 +347,365 @@ impl Device for MQTTDevice {
     }
 
     fn get_name(&self) -> String {
-        "ESP8266 LED".to_string()
+        self.name.clone()
     }
 
     fn get_type(&self) -> String {
-        "onOffSwitch".to_string()
+        self.typ.clone()
     }
+
+    fn get_capabilities(&self) -> Vec<String> {
+        self.capabilities.clone()
+    }
+}
+
+/// Where an inbound publish is dispatched to.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Route {
+    /// Index into `MQTTAdapter::discoveries`.
+    Discovery(usize),
+    Device(String),
 }
 
 struct MQTTAdapter {
-    devices: HashMap<String, Box<MQTTDevice>>
+    devices: HashMap<String, Box<MQTTDevice>>,
+    handle: AdapterHandle,
+    mqtt: mqtt::MQTT,
+    inbox: Receiver<mqtt3::Publish>,
+    /// Publishes received while waiting for retained state, not yet seen by
+    /// the discoveries.
+    backlog: VecDeque<mqtt3::Publish>,
+    discoveries: Vec<Box<dyn Discovery>>,
+    routes: Router<Route>,
+    registry: Registry,
+    name: String,
+    /// Reports the health of the adapter through the device with this id.
+    status: (String, Status),
+    metrics: Metrics,
 }
 
 impl MQTTAdapter {
-    fn new(id: &str, mqtt: mqtt::MQTT) -> MQTTAdapter {
+    /// An adapter for the devices on `broker`.
+    fn new(handle: AdapterHandle, mqtt: mqtt::MQTT, inbox: Receiver<mqtt3::Publish>,
+           config: &Config, broker: &str, mut registry: Registry) -> Result<MQTTAdapter, io::Error> {
+        let primary = config.is_primary(broker);
+        let device_configs = config.devices_on(broker);
         let mut devices = HashMap::new();
-        let device_id = format!("{}-0", id);
-        devices.insert(device_id.to_string(), Box::new(MQTTDevice::new(mqtt)));
-        MQTTAdapter {
-            devices: devices
+        if config.devices.is_empty() && primary {
+            let device_id = format!("{}-0", handle.adapter_id());
+            let name = config.default_device_name.as_ref().map_or(config::DEFAULT_DEVICE_NAME,
+                                                                   |n| n.as_str());
+            devices.insert(device_id.to_string(), Box::new(MQTTDevice::new(name, mqtt.clone())?));
+        }
+        for device_config in &device_configs {
+            let device_id = format!("{}-{}", handle.adapter_id(), device_config.id);
+            let device = MQTTDevice::from_config(device_config, mqtt.clone())?;
+            devices.insert(device_id, Box::new(device));
+        }
+        // Devices discovered in earlier runs are announced right away rather
+        // than when their ecosystem next publishes its announcements.
+        for (device_id, device_config) in registry.discovered() {
+            if devices.contains_key(&device_id) {
+                continue;
+            }
+            match MQTTDevice::from_config(&device_config, mqtt.clone()) {
+                Ok(device) => {
+                    devices.insert(device_id, Box::new(device));
+                },
+                Err(e) => warn!(adapter_id = handle.adapter_id(), device_id = device_id.as_str();
+                                 "dropping registered device: {}", e),
+            }
+        }
+        let mut routes = Router::new();
+        for (device_id, device) in devices.iter_mut() {
+            if let Some(registered) = registry.get(device_id) {
+                device.restore(registered);
+            }
+            let topics = device.state_topics();
+            for topic in &topics {
+                routes.insert(topic, Route::Device(device_id.clone()))?;
+            }
+            mqtt.subscribe(&topics).map_err(mqtt_error)?;
+        }
+
+        let mut discoveries = match primary {
+            true => discoveries(config, &mqtt),
+            false => Vec::new(),
+        };
+        for (_, device_config) in registry.discovered() {
+            for discovery in discoveries.iter_mut() {
+                discovery.restore(&device_config);
+            }
+        }
+        for (i, discovery) in discoveries.iter().enumerate() {
+            let topics = discovery.topics();
+            for topic in &topics {
+                routes.insert(topic, Route::Discovery(i))?;
+            }
+            mqtt.subscribe(&topics).map_err(mqtt_error)?;
+        }
+
+        let backlog = seed_from_retained(&mut devices, &routes, &inbox, &mut registry,
+                                         config.session.receive_maximum);
+        for device_config in &device_configs {
+            let device_id = format!("{}-{}", handle.adapter_id(), device_config.id);
+            let device = devices.get_mut(&device_id).unwrap();
+            for (name, value) in &device_config.boot_values {
+                let property = device.set_property(Property {
+                    name: name.clone(),
+                    value: value.clone(),
+                })?;
+                registry.record_value(&device_id, &property.name, &property.value);
+            }
+        }
+
+        let suffix = match broker {
+            config::DEFAULT_BROKER => String::new(),
+            broker => format!(" ({})", broker),
+        };
+        let metrics = mqtt.metrics().metrics().clone();
+        let status = Status::new(mqtt.metrics().clone());
+        let status_id = format!("{}-adapter-status", handle.adapter_id());
+        let status_device = MQTTDevice::local(&format!("{}{}", status::STATUS_DEVICE_NAME, suffix),
+                                              status.properties(), mqtt.clone())?;
+        devices.insert(status_id.clone(), Box::new(status_device));
+
+        Ok(MQTTAdapter {
+            devices,
+            handle,
+            mqtt,
+            inbox,
+            backlog,
+            discoveries,
+            routes,
+            registry,
+            name: format!("MQTT Adapter{}", suffix),
+            status: (status_id, status),
+            metrics,
+        })
+    }
+
+    fn device_id(&self, config_id: &str) -> String {
+        format!("{}-{}", self.handle.adapter_id(), config_id)
+    }
+
+    /// The `DeviceConfig::id` a device id was built from by `device_id`.
+    fn config_id<'a>(&self, device_id: &'a str) -> &'a str {
+        device_id.strip_prefix(self.handle.adapter_id())
+            .and_then(|rest| rest.strip_prefix('-'))
+            .unwrap_or(device_id)
+    }
+
+    fn handle_discovery(&mut self, event: DiscoveryEvent) -> Result<(), io::Error> {
+        match event {
+            DiscoveryEvent::Added(config) => {
+                let device = MQTTDevice::from_config(&config, self.mqtt.clone())?;
+                let device_id = self.device_id(&config.id);
+                self.registry.record_device(&device_id, &config)?;
+                self.add_device(&device_id, device)
+            },
+            DiscoveryEvent::Removed(config_id) => {
+                let device_id = self.device_id(&config_id);
+                if self.devices.contains_key(&device_id) {
+                    self.remove_device(&device_id)?;
+                }
+                self.registry.forget(&device_id)
+            },
+            DiscoveryEvent::Updated(config_id, properties) => {
+                let device_id = self.device_id(&config_id);
+                if let Some(device) = self.devices.get_mut(&device_id) {
+                    for property in properties {
+                        if let Some(property) = device.update_property(&property.name, property.value) {
+                            self.registry.record_value(&device_id, &property.name, &property.value);
+                            self.handle.property_changed(&device_id, property)?;
+                        }
+                    }
+                }
+                Ok(())
+            },
+        }
+    }
+
+    /// Adds a device after startup and announces it to the gateway. A device
+    /// already registered under `device_id` is removed first, as the gateway
+    /// would otherwise keep properties and actions it no longer has.
+    fn add_device(&mut self, device_id: &str, mut device: MQTTDevice) -> Result<(), io::Error> {
+        if self.devices.contains_key(device_id) {
+            self.remove_device(device_id)?;
+        }
+        if let Some(registered) = self.registry.get(device_id) {
+            device.restore(registered);
+        }
+        let topics = device.state_topics();
+        for topic in &topics {
+            self.routes.insert(topic, Route::Device(device_id.to_string()))?;
+        }
+        self.mqtt.subscribe(&topics).map_err(mqtt_error)?;
+        self.handle.handle_device_added(device_id, &device)?;
+        self.devices.insert(device_id.to_string(), Box::new(device));
+        Ok(())
+    }
+
+    /// Unsubscribes from those of `topics` that no device or discovery
+    /// listens to any more.
+    fn unsubscribe_unused(&self, mut topics: Vec<String>) -> Result<(), io::Error> {
+        let in_use: HashSet<String> = self.devices.values().flat_map(|device| device.state_topics())
+            .chain(self.discoveries.iter().flat_map(|discovery| discovery.topics()))
+            .collect();
+        topics.retain(|topic| !in_use.contains(topic));
+        topics.sort();
+        topics.dedup();
+        self.mqtt.unsubscribe(&topics).map_err(mqtt_error)
+    }
+
+    fn unroute(&mut self, device_id: &str) {
+        self.routes.retain(|route| match *route {
+            Route::Device(ref id) => id != device_id,
+            Route::Discovery(_) => true,
+        });
+    }
+
+    /// Publishes are first shown to the discoveries they are routed to, so
+    /// that devices they add see the publish too.
+    fn dispatch(&mut self, publish: &mqtt3::Publish) -> Result<(), io::Error> {
+        let mut events = Vec::new();
+        for route in routes_for(&self.routes, &publish.topic_name) {
+            if let Route::Discovery(i) = route {
+                events.extend(self.discoveries[i].handle_publish(&publish.topic_name, &publish.payload));
+            }
+        }
+        for event in events {
+            self.handle_discovery(event)?;
+        }
+
+        for route in routes_for(&self.routes, &publish.topic_name) {
+            if let Route::Device(device_id) = route {
+                if let Some(device) = self.devices.get_mut(&device_id) {
+                    self.metrics.update_device(&device_id, |stats| stats.messages_in += 1);
+                    for property in device.handle_publish(&publish.topic_name, &publish.payload) {
+                        self.registry.record_value(&device_id, &property.name, &property.value);
+                        self.handle.property_changed(&device_id, property)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Brings the status device up to date with the metrics.
+    fn report_status(&mut self) -> Result<(), io::Error> {
+        let (ref device_id, ref mut status) = self.status;
+        let properties = match status.poll() {
+            Some(properties) => properties,
+            None => return Ok(()),
+        };
+        if let Some(device) = self.devices.get_mut(device_id) {
+            for property in properties {
+                if let Some(property) = device.update_property(&property.name, property.value) {
+                    self.handle.property_changed(device_id, property)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes a device and tells the gateway it is gone.
+    fn remove_device(&mut self, device_id: &str) -> Result<MQTTDevice, io::Error> {
+        match self.devices.remove(device_id) {
+            Some(device) => {
+                self.unroute(device_id);
+                self.unsubscribe_unused(device.state_topics())?;
+                self.handle.handle_device_removed(device_id)?;
+                Ok(*device)
+            },
+            None => Err(io::Error::other("Device not found"))
         }
     }
 }
 
 impl Adapter<MQTTDevice> for MQTTAdapter {
-    fn start_pairing(&mut self) -> Result<(), io::Error> {
-        println!("start_pairing");
+    fn start_pairing(&mut self, timeout: f64) -> Result<(), io::Error> {
+        info!(adapter_id = self.handle.adapter_id(); "start pairing for {}s", timeout);
+        for discovery in self.discoveries.iter_mut() {
+            discovery.start_pairing(&self.mqtt, timeout)?;
+        }
         Ok(())
     }
 
     fn cancel_pairing(&mut self) -> Result<(), io::Error> {
-        println!("cancel_pairing");
+        info!(adapter_id = self.handle.adapter_id(); "cancel pairing");
+        for discovery in self.discoveries.iter_mut() {
+            discovery.cancel_pairing(&self.mqtt)?;
+        }
         Ok(())
     }
 
     fn set_property(&mut self, device_id: &str, property: Property) -> Result<Property, io::Error> {
-        println!("set_property {} {:?}", device_id, property);
+        info!(adapter_id = self.handle.adapter_id(), device_id = device_id;
+              "set property {} to {}", property.name, property.value);
+        let config_id = self.config_id(device_id);
+        let device = match self.devices.get_mut(device_id) {
+            Some(device) => device,
+            None => return Err(io::Error::other("Device not found"))
+        };
+        let value = device.validate(&property)?;
+        let mut payload = None;
+        for discovery in &self.discoveries {
+            payload = discovery.encode_command(config_id, &property.name, &value)?;
+            if payload.is_some() {
+                break;
+            }
+        }
+        let payload = payload.unwrap_or_else(|| device.format(&property.name).encode(&value));
+        let property = device.send_property(&property.name, value, payload)?;
+        self.metrics.update_device(device_id, |stats| stats.messages_out += 1);
+        self.registry.record_value(device_id, &property.name, &property.value);
+        Ok(property)
+    }
+
+    fn request_action(&mut self, device_id: &str, name: String, input: Value) -> Result<(), io::Error> {
+        info!(adapter_id = self.handle.adapter_id(), device_id = device_id; "request action {}", name);
         match self.devices.get_mut(device_id) {
-            Some(device) => device.set_property(property),
-            None => return Err(io::Error::new(io::ErrorKind::Other, "Device not found"))
+            Some(device) => device.request_action(name, input)?,
+            None => return Err(io::Error::other("Device not found"))
         }
+        self.metrics.update_device(device_id, |stats| stats.messages_out += 1);
+        Ok(())
     }
 
-    fn request_action(&mut self, device_id: &str, name: String) -> Result<(), io::Error> {
-        println!("request_action {} {}", device_id, name);
+    fn remove_thing(&mut self, device_id: &str) -> Result<(), io::Error> {
+        info!(adapter_id = self.handle.adapter_id(), device_id = device_id; "remove thing");
+        self.remove_device(device_id)?;
+        self.registry.forget(device_id)
+    }
+
+    fn set_title(&mut self, device_id: &str, title: &str) -> Result<(), io::Error> {
+        info!(adapter_id = self.handle.adapter_id(), device_id = device_id; "renamed to {}", title);
         match self.devices.get_mut(device_id) {
-            Some(device) => device.request_action(name),
-            None => return Err(io::Error::new(io::ErrorKind::Other, "Device not found"))
+            Some(device) => device.name = title.to_string(),
+            None => return Err(io::Error::other("Device not found"))
         }
+        self.registry.record_name(device_id, title)
+    }
+
+    fn unload(&mut self) -> Result<(), io::Error> {
+        self.registry.flush_now()
+    }
+
+    fn poll(&mut self) -> Result<(), io::Error> {
+        while let Some(publish) = self.backlog.pop_front().or_else(|| self.inbox.try_recv().ok()) {
+            // One bad publish must not hold up the others.
+            if let Err(e) = self.dispatch(&publish) {
+                warn!(adapter_id = self.handle.adapter_id(), topic = publish.topic_name.as_str();
+                      "could not handle publish: {}", e);
+            }
+        }
+        self.report_status()?;
+        self.registry.flush()
     }
 
     fn get_name(&self) -> String {
-        "MQTT Adapter".to_string()
+        self.name.clone()
     }
 
     fn get_devices(&self) -> &HashMap<String, Box<MQTTDevice>> {
@@ -138,17 +713,146 @@ impl Adapter<MQTTDevice> for MQTTAdapter {
     }
 }
 
+/// The discovery profiles enabled in `config`.
+fn discoveries(config: &Config, mqtt: &mqtt::MQTT) -> Vec<Box<dyn Discovery>> {
+    let mut discoveries: Vec<Box<dyn Discovery>> = Vec::new();
+    if let Some(ref z2m) = config.zigbee2mqtt {
+        discoveries.push(Box::new(Zigbee2Mqtt::new(&z2m.base_topic)));
+    }
+    if let Some(ref tasmota) = config.tasmota {
+        discoveries.push(Box::new(Tasmota::new(&tasmota.discovery_prefix)));
+    }
+    if let Some(ref shelly) = config.shelly {
+        discoveries.push(Box::new(Shelly::new(&shelly.prefix)));
+    }
+    if let Some(ref esphome) = config.esphome {
+        discoveries.push(Box::new(ESPHome::new(&esphome.discovery_prefix)));
+    }
+    if let Some(ref sparkplug) = config.sparkplug {
+        discoveries.push(Box::new(Sparkplug::new(&sparkplug.group_id, mqtt.clone())));
+    }
+    discoveries
+}
+
+/// Each destination of a publish on `topic`, once.
+fn routes_for(routes: &Router<Route>, topic: &str) -> Vec<Route> {
+    let mut found: Vec<Route> = routes.matches(topic).into_iter().cloned().collect();
+    found.sort();
+    found.dedup();
+    found
+}
+
+/// Applies the state the broker delivers right after subscribing, mostly
+/// retained messages, so that devices are announced with their actual values
+/// instead of those remembered from the last run. Returns the publishes
+/// received that the discoveries still have to see.
+///
+/// Seeding stops early once `limit` such publishes are held, leaving the
+/// rest in the inbox, which the broker is not read past.
+fn seed_from_retained(devices: &mut HashMap<String, Box<MQTTDevice>>, routes: &Router<Route>,
+                      inbox: &Receiver<mqtt3::Publish>, registry: &mut Registry,
+                      limit: usize) -> VecDeque<mqtt3::Publish> {
+    let mut backlog = VecDeque::new();
+    let deadline = Instant::now() + Duration::from_millis(RETAINED_WAIT);
+    loop {
+        let now = Instant::now();
+        if now >= deadline || backlog.len() >= limit {
+            break;
+        }
+        let publish = match inbox.recv_timeout(deadline - now) {
+            Ok(publish) => publish,
+            Err(_) => break,
+        };
+        let mut for_discovery = false;
+        for route in routes_for(routes, &publish.topic_name) {
+            match route {
+                Route::Device(device_id) => if let Some(device) = devices.get_mut(&device_id) {
+                    for property in device.handle_publish(&publish.topic_name, &publish.payload) {
+                        registry.record_value(&device_id, &property.name, &property.value);
+                    }
+                },
+                Route::Discovery(_) => for_discovery = true,
+            }
+        }
+        if for_discovery {
+            backlog.push_back(publish);
+        }
+    }
+    backlog
+}
+
 fn main() {
-    let mut mqtt = mqtt::MQTT::new(config::MQTT_SERVER, config::MQTT_USERNAME,
-                                   config::MQTT_PASSWORD);
-    mqtt.send_connect().unwrap();
-    mqtt.publish_value("on", &Value::Bool(true)).unwrap();
-
-    let (mut gateway_bridge, msg_sender, msg_receiver) = GatewayBridge::new("mqtt-adapter");
-    thread::spawn(move || {
-        gateway_bridge.run_forever().unwrap();
+    let config = config::load(config::CONFIG_PATH).unwrap();
+    logging::init(&config.logging).unwrap();
+    for secret in config.secrets() {
+        logging::redact(&secret);
+    }
+    // The adapter logs in to its embedded broker with a password of its own,
+    // unless the broker lets everyone on this host in.
+    let embedded = config.embedded_broker.as_ref().map(|embedded| {
+        let broker = Broker::start(embedded).unwrap();
+        let password = match embedded.users.is_empty() {
+            true => None,
+            false => {
+                let password = config::random_password().unwrap();
+                logging::redact(&password);
+                broker.add_user(config::PACKAGE_NAME, &password);
+                Some(password)
+            },
+        };
+        (broker, password)
     });
+    let metrics = Metrics::new();
+    if let Some(ref exporter) = config.metrics_exporter {
+        let exporter = Exporter::start(exporter, metrics.clone()).unwrap();
+        info!("serving metrics on http://{}/metrics", exporter.local_addr());
+    }
+    let mut connections = Vec::new();
+    for mut broker in config.brokers() {
+        if let (true, Some((embedded, password))) = (broker.embedded, embedded.as_ref()) {
+            broker.server = format!("127.0.0.1:{}", embedded.local_addr().port());
+            broker.username = password.as_ref().map(|_| config::PACKAGE_NAME.to_string());
+            broker.password = password.clone();
+            broker.tls = None;
+        }
+        let queue_path = match config.offline_queue.persist {
+            true => Some(broker.data_file(config::QUEUE_FILE)),
+            false => None,
+        };
+        let queue = OfflineQueue::new(config.offline_queue.expiry, config.offline_queue.capacity,
+                                      queue_path).unwrap();
+        let client_id = match broker.client_id {
+            Some(ref id) => id.clone(),
+            None => config.session.client_id().unwrap(),
+        };
+        let options = mqtt::Options {
+            server: broker.server.clone(),
+            username: broker.username.clone(),
+            password: broker.password.clone(),
+            tls: broker.tls.clone(),
+            client_id,
+            clean_session: !config.session.persistent,
+            receive_maximum: config.session.receive_maximum,
+        };
+        let (mqtt, inbox) = mqtt::MQTT::connect(options, queue, metrics.connection(&broker.name));
+        connections.push((broker, mqtt, inbox));
+    }
+
+    let (mut gateway_bridge, msg_sender, msg_receiver) = GatewayBridge::new("mqtt-adapter", metrics.clone());
+    if let Some(ref mirror) = config.mirror {
+        let observed = gateway_bridge.observe();
+        let mut mirror = ThingMirror::new(&mirror.prefix, "mqtt-adapter", connections[0].1.clone(),
+                                          gateway_bridge.gateway_sender()).unwrap();
+        supervisor::spawn("mirror", move || mirror.run_forever(&observed));
+    }
+    supervisor::spawn("gateway bridge", move || gateway_bridge.run_forever());
     let mut plugin = Plugin::new("mqtt", "mqtt-adapter", msg_sender, msg_receiver);
-    plugin.add_adapter("mqtt-0", Box::new(MQTTAdapter::new("mqtt-0", mqtt)));
-    plugin.run_forever().unwrap();
+    for (broker, mqtt, inbox) in connections {
+        let adapter_id = format!("mqtt-{}", broker.name);
+        let handle = plugin.adapter_handle(&adapter_id);
+        let registry = Registry::load(&broker.data_file(config::REGISTRY_FILE)).unwrap();
+        let adapter = MQTTAdapter::new(handle, mqtt, inbox, &config, &broker.name, registry).unwrap();
+        plugin.add_adapter(&adapter_id, Box::new(adapter));
+    }
+    supervisor::supervise("plugin", || plugin.run_forever());
 }
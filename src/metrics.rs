@@ -0,0 +1,120 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Health of one broker connection.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionStats {
+    pub connected: bool,
+    /// Connections re-established after being lost.
+    pub reconnects: u64,
+    /// Publishes received from the broker.
+    pub messages_in: u64,
+    /// Publishes written to the broker, including resent ones.
+    pub messages_out: u64,
+    /// Publishes that could not be written and were queued instead.
+    pub publish_failures: u64,
+    /// How long the broker took to acknowledge the latest publish.
+    pub publish_latency: Option<Duration>,
+    /// Publishes waiting in the offline queue.
+    pub queue_depth: usize,
+    /// Publishes written but not yet acknowledged.
+    pub inflight: usize,
+    pub last_error: Option<String>,
+}
+
+/// Health of the plugin's link with the gateway.
+#[derive(Clone, Debug, Default)]
+pub struct GatewayStats {
+    /// Whether the plugin is registered with the gateway.
+    pub connected: bool,
+    pub messages_in: u64,
+    pub messages_out: u64,
+    /// How long the latest message to the gateway waited to be written.
+    pub latency: Option<Duration>,
+    pub last_error: Option<String>,
+}
+
+/// Traffic of one device.
+#[derive(Clone, Debug, Default)]
+pub struct DeviceStats {
+    /// Publishes that updated the device.
+    pub messages_in: u64,
+    /// Commands and actions published for the device.
+    pub messages_out: u64,
+}
+
+#[derive(Default)]
+struct Registry {
+    connections: BTreeMap<String, ConnectionStats>,
+    gateway: GatewayStats,
+    devices: BTreeMap<String, DeviceStats>,
+}
+
+/// Statistics about the adapter itself, recorded by the broker connections
+/// and the gateway bridge and read by whatever reports them. Clones share
+/// the same statistics.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    registry: Arc<Mutex<Registry>>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    /// A handle recording the statistics of the connection to `broker`.
+    pub fn connection(&self, broker: &str) -> Connection {
+        self.registry.lock().unwrap().connections.entry(broker.to_string()).or_default();
+        Connection {
+            metrics: self.clone(),
+            broker: broker.to_string(),
+        }
+    }
+
+    /// Statistics of every broker connection, by broker name.
+    pub fn connections(&self) -> BTreeMap<String, ConnectionStats> {
+        self.registry.lock().unwrap().connections.clone()
+    }
+
+    /// Statistics of every device that has seen traffic, by device id.
+    pub fn devices(&self) -> BTreeMap<String, DeviceStats> {
+        self.registry.lock().unwrap().devices.clone()
+    }
+
+    pub fn update_device<F: FnOnce(&mut DeviceStats)>(&self, device_id: &str, update: F) {
+        let mut registry = self.registry.lock().unwrap();
+        update(registry.devices.entry(device_id.to_string()).or_default());
+    }
+
+    pub fn gateway(&self) -> GatewayStats {
+        self.registry.lock().unwrap().gateway.clone()
+    }
+
+    pub fn update_gateway<F: FnOnce(&mut GatewayStats)>(&self, update: F) {
+        update(&mut self.registry.lock().unwrap().gateway);
+    }
+}
+
+/// Records the statistics of one broker connection.
+#[derive(Clone)]
+pub struct Connection {
+    metrics: Metrics,
+    broker: String,
+}
+
+impl Connection {
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn stats(&self) -> ConnectionStats {
+        self.metrics.registry.lock().unwrap().connections[&self.broker].clone()
+    }
+
+    pub fn update<F: FnOnce(&mut ConnectionStats)>(&self, update: F) {
+        let mut registry = self.metrics.registry.lock().unwrap();
+        update(registry.connections.get_mut(&self.broker).unwrap());
+    }
+}
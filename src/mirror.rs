@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::mpsc::{Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+
+use mqtt3;
+use serde_json::{self, Value};
+
+use gateway::{GatewayMessage, PluginMessage};
+use mqtt::{self, mqtt_error};
+
+/// A Thing published by the mirror.
+struct MirroredThing {
+    adapter_id: String,
+    properties: Vec<String>,
+}
+
+/// Mirrors the Things this plugin announces to the gateway onto the broker,
+/// so that other MQTT clients can follow and control them.
+///
+/// Property values are retained on `<prefix>/<device>/<property>`. A publish
+/// on `<prefix>/<device>/<property>/set` is handed to the plugin as if the
+/// gateway had asked to set the property.
+pub struct ThingMirror {
+    prefix: String,
+    plugin_id: String,
+    mqtt: mqtt::MQTT,
+    commands: Receiver<mqtt3::Publish>,
+    gateway: Sender<GatewayMessage>,
+    things: HashMap<String, MirroredThing>,
+}
+
+impl ThingMirror {
+    pub fn new(prefix: &str, plugin_id: &str, mqtt: mqtt::MQTT,
+               gateway: Sender<GatewayMessage>) -> Result<ThingMirror, io::Error> {
+        let commands = mqtt.divert(&format!("{}/#", prefix))?;
+        mqtt.subscribe(&[format!("{}/+/+/set", prefix)]).map_err(mqtt_error)?;
+        Ok(ThingMirror {
+            prefix: prefix.to_string(),
+            plugin_id: plugin_id.to_string(),
+            mqtt,
+            commands,
+            gateway,
+            things: HashMap::new(),
+        })
+    }
+
+    /// Mirrors `observed`, the messages sent to the gateway, until the
+    /// plugin is unloaded.
+    pub fn run_forever(&mut self, observed: &Receiver<PluginMessage>) -> Result<(), io::Error> {
+        loop {
+            let mut idle = true;
+            while let Ok(msg) = observed.try_recv() {
+                idle = false;
+                if let PluginMessage::PluginUnloaded { .. } = msg {
+                    return Ok(());
+                }
+                if let Err(e) = self.handle_plugin_message(msg) {
+                    warn!("could not mirror a plugin message: {}", e);
+                }
+            }
+            while let Ok(publish) = self.commands.try_recv() {
+                idle = false;
+                self.handle_command(&publish.topic_name, &publish.payload);
+            }
+            if idle {
+                thread::sleep(Duration::from_millis(33));
+            }
+        }
+    }
+
+    fn handle_plugin_message(&mut self, msg: PluginMessage) -> Result<(), io::Error> {
+        match msg {
+            PluginMessage::HandleDeviceAdded { adapter_id, id, properties, .. } => {
+                for (name, descr) in &properties {
+                    self.publish_value(&id, name, &descr.value)?;
+                }
+                // Clear values of properties the device no longer has.
+                if let Some(old) = self.things.get(&id) {
+                    for name in old.properties.iter().filter(|n| !properties.contains_key(*n)) {
+                        self.mqtt.publish_retained(&self.topic(&id, name), Vec::new())
+                            .map_err(mqtt_error)?;
+                    }
+                }
+                self.things.insert(id, MirroredThing {
+                    adapter_id,
+                    properties: properties.keys().cloned().collect(),
+                });
+            },
+            PluginMessage::PropertyChanged { device_id, property, .. } => {
+                self.publish_value(&device_id, &property.name, &property.value)?;
+            },
+            PluginMessage::HandleDeviceRemoved { id, .. } => {
+                if let Some(thing) = self.things.remove(&id) {
+                    for name in &thing.properties {
+                        self.mqtt.publish_retained(&self.topic(&id, name), Vec::new())
+                            .map_err(mqtt_error)?;
+                    }
+                }
+            },
+            _ => {},
+        }
+        Ok(())
+    }
+
+    fn handle_command(&self, topic: &str, payload: &[u8]) {
+        let parts: Vec<&str> = topic[self.prefix.len()..].split('/').collect();
+        let (device_id, property_name) = match parts.as_slice() {
+            &["", device_id, property_name, "set"] => (device_id, property_name),
+            _ => return,
+        };
+        let thing = match self.things.get(device_id) {
+            Some(thing) if thing.properties.iter().any(|p| p == property_name) => thing,
+            _ => {
+                warn!(device_id = device_id; "no property {}", property_name);
+                return;
+            }
+        };
+        let text = String::from_utf8_lossy(payload);
+        let value = serde_json::from_str(&text)
+            .unwrap_or_else(|_| Value::String(text.trim().to_string()));
+        let msg = GatewayMessage::SetProperty {
+            plugin_id: self.plugin_id.clone(),
+            adapter_id: thing.adapter_id.clone(),
+            device_id: device_id.to_string(),
+            property_name: property_name.to_string(),
+            property_value: value,
+        };
+        if self.gateway.send(msg).is_err() {
+            warn!(topic = topic; "plugin gone, dropping command");
+        }
+    }
+
+    fn publish_value(&self, device_id: &str, name: &str, value: &Value) -> Result<(), io::Error> {
+        self.mqtt.publish_retained(&self.topic(device_id, name), value.to_string().into_bytes())
+            .map_err(mqtt_error)
+    }
+
+    fn topic(&self, device_id: &str, name: &str) -> String {
+        format!("{}/{}/{}", self.prefix, device_id, name)
+    }
+}
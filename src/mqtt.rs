@@ -1,70 +1,570 @@
-use std::sync::Arc;
-use std::net::TcpStream;
-use std::io::{Write, BufReader, BufWriter};
+use std::cmp;
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Write, BufReader, BufWriter};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use mqtt3::{self, MqttRead, MqttWrite};
-use serde_json::Value;
 
+use config::TlsConfig;
+use metrics;
+use queue::OfflineQueue;
+use router::Router;
+use supervisor;
+use transport::Stream;
+
+/// Longest wait between reconnection attempts, in seconds.
+const MAX_RECONNECT_DELAY: u64 = 60;
+
+/// Seconds the broker waits for a packet from us before it considers the
+/// connection dead. We ping twice as often, and give up on a broker that
+/// stays silent half as long again.
+const KEEP_ALIVE: u16 = 60;
+
+/// Receivers of publishes, by the topic filter they were diverted with.
+type Routes = Arc<Mutex<Router<SyncSender<mqtt3::Publish>>>>;
+
+/// What is needed to open a session with the broker.
+#[derive(Clone)]
+pub struct Options {
+    /// `host:port` of the broker.
+    pub server: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Connects over TLS when set.
+    pub tls: Option<TlsConfig>,
+    /// Must be unique among the broker's clients, or they disconnect each
+    /// other.
+    pub client_id: String,
+    /// With `false` the broker keeps our subscriptions and the QoS 1
+    /// messages sent to them while we are disconnected.
+    pub clean_session: bool,
+    /// Inbound publishes that may be waiting to be handled before we stop
+    /// reading from the broker.
+    pub receive_maximum: usize,
+}
+
+/// Delivery state of publishes received from the broker, kept for as long
+/// as the session.
+struct Inbound {
+    /// QoS 2 publishes delivered but not yet released by the broker.
+    unreleased: HashSet<u16>,
+    /// Recently acknowledged QoS 1 publishes, so that redeliveries of them
+    /// can be recognised.
+    acknowledged: VecDeque<(u16, u64)>,
+    maximum: usize,
+}
+
+impl Inbound {
+    fn new(maximum: usize) -> Inbound {
+        Inbound {
+            unreleased: HashSet::new(),
+            acknowledged: VecDeque::new(),
+            maximum,
+        }
+    }
+
+    /// Whether `publish` is new and should be delivered, as opposed to a
+    /// redelivery of one that already was.
+    fn accept(&mut self, publish: &mqtt3::Publish) -> Result<bool, mqtt3::Error> {
+        let pid = match publish.pid {
+            Some(pid) => pid.0,
+            None => return Ok(true),
+        };
+        match publish.qos {
+            mqtt3::QoS::AtMostOnce => Ok(true),
+            mqtt3::QoS::AtLeastOnce => {
+                let mut hasher = DefaultHasher::new();
+                publish.topic_name.hash(&mut hasher);
+                publish.payload.hash(&mut hasher);
+                let seen = (pid, hasher.finish());
+                if publish.dup && self.acknowledged.contains(&seen) {
+                    return Ok(false);
+                }
+                if self.acknowledged.len() >= self.maximum {
+                    self.acknowledged.pop_front();
+                }
+                self.acknowledged.push_back(seen);
+                Ok(true)
+            },
+            mqtt3::QoS::ExactlyOnce => {
+                if self.unreleased.contains(&pid) {
+                    return Ok(false);
+                }
+                if self.unreleased.len() >= self.maximum {
+                    return Err(mqtt3::Error::Io(io::Error::other("receive maximum exceeded")));
+                }
+                self.unreleased.insert(pid);
+                Ok(true)
+            },
+        }
+    }
+
+    /// Forgets a QoS 2 publish the broker has released.
+    fn release(&mut self, pid: u16) {
+        self.unreleased.remove(&pid);
+    }
+}
+
+/// A shared handle to one broker connection. Clones publish over the same
+/// socket; inbound publishes are delivered on the receiver returned by
+/// `connect` unless they have been diverted elsewhere.
+///
+/// Inbound publishes are acknowledged once they have been handed to their
+/// receiver, and redeliveries are dropped. At most `receive_maximum` of them
+/// wait in each receiver; beyond that the broker is not read from until the
+/// receiver catches up.
+///
+/// A lost connection, or one that could not be opened in the first place, is
+/// re-established in the background. Publishes made in the meantime are held in an `OfflineQueue` and sent, in order, once
+/// the subscriptions have been restored. Publishes the broker had not yet
+/// acknowledged are sent again.
+#[derive(Clone)]
+#[allow(clippy::upper_case_acronyms)]
 pub struct MQTT {
-    writer: BufWriter<TcpStream>,
-    reader: BufReader<TcpStream>,
+    writer: Arc<Mutex<Option<BufWriter<Stream>>>>,
+    next_pid: Arc<Mutex<u16>>,
+    routes: Routes,
+    subscriptions: Arc<Mutex<Vec<String>>>,
+    /// Outbound QoS 1 publishes awaiting their PUBACK, by packet identifier,
+    /// with when they were sent.
+    inflight: Arc<Mutex<BTreeMap<u16, (mqtt3::Publish, Instant)>>>,
+    queue: Arc<Mutex<OfflineQueue>>,
+    subscription_qos: mqtt3::QoS,
+    receive_maximum: usize,
     username: String,
-    password: String,
+    metrics: metrics::Connection,
 }
 
 impl MQTT {
-    pub fn new(server: &str, username: &str, password: &str) -> MQTT {
-        let stream = TcpStream::connect(server).unwrap();
-        let reader = BufReader::new(stream.try_clone().unwrap());
-        let writer = BufWriter::new(stream.try_clone().unwrap());
-        MQTT {
-            reader,
-            writer,
-            username: username.to_string(),
-            password: password.to_string()
-        }
-    }
-
-    pub fn send_connect(&mut self) -> Result<mqtt3::Packet, mqtt3::Error> {
-        let connect = mqtt3::Packet::Connect(Box::new(mqtt3::Connect {
-            protocol: mqtt3::Protocol::MQTT(4),
-            keep_alive: 65535, // TODO properly fix
-            client_id: "rust-mq-example-pub".to_string(),
-            clean_session: true,
-            last_will: None,
-            username: Some(self.username.clone()),
-            password: Some(self.password.clone()),
-        }));
-        self.writer.write_packet(&connect)?;
-        self.writer.flush()?;
-        self.reader.read_packet()
+    pub fn connect(options: Options, queue: OfflineQueue, metrics: metrics::Connection)
+                   -> (MQTT, Receiver<mqtt3::Publish>) {
+        let (reader, writer) = match open(&options) {
+            Ok((reader, writer, session_present)) => {
+                if session_present {
+                    info!("resuming session of {}", options.client_id);
+                }
+                metrics.update(|stats| stats.connected = true);
+                (Some(reader), Some(writer))
+            },
+            Err(e) => {
+                warn!("connection to {} failed: {:?}", options.server, e);
+                metrics.update(|stats| stats.last_error = Some(describe(&e)));
+                (None, None)
+            },
+        };
+
+        let (sender, receiver) = sync_channel(options.receive_maximum);
+        let mqtt = MQTT {
+            writer: Arc::new(Mutex::new(writer)),
+            next_pid: Arc::new(Mutex::new(0)),
+            routes: Arc::new(Mutex::new(Router::new())),
+            subscriptions: Arc::new(Mutex::new(Vec::new())),
+            inflight: Arc::new(Mutex::new(BTreeMap::new())),
+            queue: Arc::new(Mutex::new(queue)),
+            // Only subscriptions above QoS 0 get messages stored while we
+            // are away.
+            subscription_qos: match options.clean_session {
+                true => mqtt3::QoS::AtMostOnce,
+                false => mqtt3::QoS::AtLeastOnce,
+            },
+            receive_maximum: options.receive_maximum,
+            username: options.username.clone().unwrap_or_default(),
+            metrics,
+        };
+        // Publishes queued by an earlier run.
+        if reader.is_some() {
+            mqtt.drain_queue();
+        }
+        let connection = mqtt.clone();
+        let mut reader = reader;
+        supervisor::spawn("mqtt connection", move || {
+            connection.run(reader.take(), &options, &sender);
+            Ok(())
+        });
+        let pinger = mqtt.clone();
+        supervisor::spawn("mqtt keep-alive", move || {
+            pinger.keep_alive();
+            Ok(())
+        });
+        (mqtt, receiver)
     }
 
-    pub fn publish_value(&mut self, prop: &str, value: &Value) -> Result<mqtt3::Packet, mqtt3::Error> {
-        let publish = mqtt3::Packet::Publish(Box::new(mqtt3::Publish {
-                dup: false,
-                qos: mqtt3::QoS::AtLeastOnce,
-                retain: false,
-                topic_name: format!("{}/feeds/{}", self.username, prop).to_owned(),
-                pid: Some(mqtt3::PacketIdentifier(10)),
-                payload: Arc::new(value.to_string().into_bytes())
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    pub fn metrics(&self) -> &metrics::Connection {
+        &self.metrics
+    }
+
+    /// Delivers inbound publishes matching `filter` on the returned receiver
+    /// instead of the one returned by `connect`.
+    pub fn divert(&self, filter: &str) -> Result<Receiver<mqtt3::Publish>, io::Error> {
+        let (sender, receiver) = sync_channel(self.receive_maximum);
+        self.routes.lock().unwrap().insert(filter, sender)?;
+        Ok(receiver)
+    }
+
+    /// Subscribes to `topics`, now and after every reconnection. Topics
+    /// already subscribed to are subscribed to again, for the broker to
+    /// deliver their retained messages once more.
+    pub fn subscribe(&self, topics: &[String]) -> Result<(), mqtt3::Error> {
+        if topics.is_empty() {
+            return Ok(());
+        }
+        {
+            let mut subscriptions = self.subscriptions.lock().unwrap();
+            for topic in topics {
+                if !subscriptions.contains(topic) {
+                    subscriptions.push(topic.clone());
+                }
+            }
+        }
+        match self.send_subscribe(topics) {
+            Err(mqtt3::Error::Io(ref e)) if e.kind() == io::ErrorKind::NotConnected => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Stops receiving publishes on `topics`.
+    pub fn unsubscribe(&self, topics: &[String]) -> Result<(), mqtt3::Error> {
+        if topics.is_empty() {
+            return Ok(());
+        }
+        self.subscriptions.lock().unwrap().retain(|topic| !topics.contains(topic));
+        let unsubscribe = mqtt3::Packet::Unsubscribe(Box::new(mqtt3::Unsubscribe {
+            pid: self.next_pid(),
+            topics: topics.to_vec(),
         }));
-        self.writer.write_packet(&publish)?;
-        self.writer.flush()?;
-        self.reader.read_packet()
+        match self.write(&unsubscribe) {
+            Err(mqtt3::Error::Io(ref e)) if e.kind() == io::ErrorKind::NotConnected => Ok(()),
+            result => result,
+        }
+    }
+
+    pub fn publish(&self, topic: &str, payload: Vec<u8>) -> Result<(), mqtt3::Error> {
+        self.send_publish(topic, payload, false, None)
+    }
+
+    /// Publishes a message the broker keeps for future subscribers. An empty
+    /// payload clears the retained message.
+    pub fn publish_retained(&self, topic: &str, payload: Vec<u8>) -> Result<(), mqtt3::Error> {
+        self.send_publish(topic, payload, true, None)
+    }
+
+    /// Publishes the value of a property. While offline only the latest
+    /// value published under `key` is kept.
+    pub fn publish_value(&self, key: &str, topic: &str, payload: Vec<u8>) -> Result<(), mqtt3::Error> {
+        self.send_publish(topic, payload, false, Some(key))
+    }
+
+    fn send_publish(&self, topic: &str, payload: Vec<u8>, retain: bool,
+                    key: Option<&str>) -> Result<(), mqtt3::Error> {
+        debug!(topic = topic; "publishing {} bytes", payload.len());
+        // Publishes still waiting in the queue go first.
+        let mut queue = self.queue.lock().unwrap();
+        if queue.is_empty() {
+            match self.send(self.publish_packet(topic, payload.clone(), retain)) {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    warn!(topic = topic; "publish queued: {:?}", e);
+                    self.metrics.update(|stats| {
+                        stats.publish_failures += 1;
+                        stats.last_error = Some(describe(&e));
+                    });
+                },
+            }
+        }
+        queue.push(topic, payload, retain, key);
+        let depth = queue.len();
+        self.metrics.update(|stats| stats.queue_depth = depth);
+        Ok(())
     }
 
-    pub fn publish_action(&mut self, name: &str) -> Result<mqtt3::Packet, mqtt3::Error> {
-        let publish = mqtt3::Packet::Publish(Box::new(mqtt3::Publish {
+    fn publish_packet(&self, topic: &str, payload: Vec<u8>, retain: bool) -> mqtt3::Publish {
+        mqtt3::Publish {
                 dup: false,
                 qos: mqtt3::QoS::AtLeastOnce,
-                retain: false,
-                topic_name: format!("{}/feeds/actions", self.username).to_owned(),
-                pid: Some(mqtt3::PacketIdentifier(10)),
-                payload: Arc::new(name.to_string().into_bytes())
+                retain,
+                topic_name: topic.to_owned(),
+                pid: Some(self.next_pid()),
+                payload: Arc::new(payload)
+        }
+    }
+
+    /// Writes a publish and keeps it until the broker acknowledges it.
+    fn send(&self, publish: mqtt3::Publish) -> Result<(), mqtt3::Error> {
+        // The publish is kept before it is written, or its PUBACK could be
+        // read before there is anything for it to clear.
+        let mut inflight = self.inflight.lock().unwrap();
+        if let Some(pid) = publish.pid {
+            inflight.insert(pid.0, (publish.clone(), Instant::now()));
+        }
+        if let Err(e) = self.write(&mqtt3::Packet::Publish(Box::new(publish.clone()))) {
+            if let Some(pid) = publish.pid {
+                inflight.remove(&pid.0);
+            }
+            return Err(e);
+        }
+        let pending = inflight.len();
+        self.metrics.update(|stats| {
+            stats.messages_out += 1;
+            stats.inflight = pending;
+        });
+        Ok(())
+    }
+
+    fn send_subscribe(&self, topics: &[String]) -> Result<(), mqtt3::Error> {
+        let subscribe = mqtt3::Packet::Subscribe(Box::new(mqtt3::Subscribe {
+            pid: self.next_pid(),
+            topics: topics.iter().map(|topic| mqtt3::SubscribeTopic {
+                topic_path: topic.clone(),
+                qos: self.subscription_qos,
+            }).collect(),
         }));
-        self.writer.write_packet(&publish)?;
-        self.writer.flush()?;
-        self.reader.read_packet()
+        self.write(&subscribe)
+    }
+
+    /// Sends queued publishes in order until the queue is empty or the
+    /// connection fails again.
+    fn drain_queue(&self) {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.is_empty() {
+            return;
+        }
+        while let Some(queued) = queue.pop() {
+            let publish = self.publish_packet(&queued.topic, queued.payload.clone(), queued.retain);
+            if let Err(e) = self.send(publish) {
+                warn!(topic = queued.topic.as_str(); "queue drain stopped: {:?}", e);
+                queue.requeue(queued);
+                break;
+            }
+        }
+        queue.persist();
+        let depth = queue.len();
+        self.metrics.update(|stats| stats.queue_depth = depth);
+    }
+
+    fn next_pid(&self) -> mqtt3::PacketIdentifier {
+        let mut pid = self.next_pid.lock().unwrap();
+        // Packet identifiers must be non-zero.
+        *pid = pid.wrapping_add(1).max(1);
+        mqtt3::PacketIdentifier(*pid)
+    }
+
+    /// Writes a packet, dropping the connection if that fails so that the
+    /// reader notices and reconnects.
+    fn write(&self, packet: &mqtt3::Packet) -> Result<(), mqtt3::Error> {
+        let mut slot = self.writer.lock().unwrap();
+        let result = match *slot {
+            Some(ref mut writer) => writer.write_packet(packet)
+                .and_then(|_| writer.flush().map_err(mqtt3::Error::from)),
+            None => return Err(mqtt3::Error::Io(io::Error::new(io::ErrorKind::NotConnected,
+                                                               "broker unreachable"))),
+        };
+        if result.is_err() {
+            if let Some(writer) = slot.take() {
+                writer.get_ref().shutdown();
+            }
+        }
+        result
+    }
+
+    /// Pings the broker every half `KEEP_ALIVE` while connected, so that it
+    /// keeps the connection open and its answers show ours is still alive.
+    fn keep_alive(&self) {
+        loop {
+            thread::sleep(Duration::from_secs(u64::from(KEEP_ALIVE / 2)));
+            match self.write(&mqtt3::Packet::Pingreq) {
+                Err(mqtt3::Error::Io(ref e)) if e.kind() == io::ErrorKind::NotConnected => {},
+                Err(e) => warn!("ping failed: {:?}", e),
+                Ok(()) => {},
+            }
+        }
+    }
+
+    /// Picks up after a reconnection. Unless the broker kept our session,
+    /// subscriptions are made again and unacknowledged publishes are sent
+    /// as new ones.
+    fn resume(&self, session_present: bool) {
+        if !session_present {
+            let subscriptions = self.subscriptions.lock().unwrap().clone();
+            if !subscriptions.is_empty() {
+                if let Err(e) = self.send_subscribe(&subscriptions) {
+                    warn!("resubscribe failed: {:?}", e);
+                }
+            }
+        }
+
+        let inflight: Vec<mqtt3::Publish> = {
+            let mut inflight = self.inflight.lock().unwrap();
+            let pending = inflight.values().map(|(publish, _)| publish.clone()).collect();
+            inflight.clear();
+            pending
+        };
+        for mut publish in inflight {
+            if session_present {
+                publish.dup = true;
+            } else {
+                publish.pid = Some(self.next_pid());
+            }
+            if let Err(e) = self.send(publish) {
+                warn!("resend failed: {:?}", e);
+                return;
+            }
+        }
+        self.drain_queue();
+    }
+
+    /// Reads from the broker, reconnecting with increasing delays whenever
+    /// the connection drops. Without a `reader` it starts by reconnecting.
+    fn run(&self, mut reader: Option<BufReader<Stream>>, options: &Options,
+           sender: &SyncSender<mqtt3::Publish>) {
+        let mut inbound = Inbound::new(options.receive_maximum);
+        let mut was_connected = reader.is_some();
+        loop {
+            if let Some(ref mut reader) = reader {
+                if let Err(e) = self.read_forever(reader, sender, &mut inbound) {
+                    error!("connection to {} lost: {:?}", options.server, e);
+                    self.metrics.update(|stats| stats.last_error = Some(describe(&e)));
+                }
+            }
+            self.metrics.update(|stats| stats.connected = false);
+            if let Some(writer) = self.writer.lock().unwrap().take() {
+                writer.get_ref().shutdown();
+            }
+
+            let mut delay = 1;
+            let session_present = loop {
+                thread::sleep(Duration::from_secs(delay));
+                match open(options) {
+                    Ok((new_reader, writer, session_present)) => {
+                        reader = Some(new_reader);
+                        *self.writer.lock().unwrap() = Some(writer);
+                        break session_present;
+                    },
+                    Err(e) => {
+                        warn!("reconnect to {} failed: {:?}", options.server, e);
+                        self.metrics.update(|stats| stats.last_error = Some(describe(&e)));
+                        delay = cmp::min(delay * 2, MAX_RECONNECT_DELAY);
+                    },
+                }
+            };
+            info!("connected to {}", options.server);
+            self.metrics.update(|stats| {
+                stats.connected = true;
+                if was_connected {
+                    stats.reconnects += 1;
+                }
+            });
+            was_connected = true;
+            if !session_present {
+                inbound = Inbound::new(options.receive_maximum);
+            }
+            self.resume(session_present);
+        }
+    }
+
+    /// Forwards inbound publishes until the connection fails.
+    fn read_forever(&self, reader: &mut BufReader<Stream>, sender: &SyncSender<mqtt3::Publish>,
+                    inbound: &mut Inbound) -> Result<(), mqtt3::Error> {
+        loop {
+            match reader.read_packet()? {
+                mqtt3::Packet::Publish(publish) => {
+                    let (qos, pid) = (publish.qos, publish.pid);
+                    if inbound.accept(&publish)? {
+                        self.deliver(*publish, sender);
+                    }
+                    match (qos, pid) {
+                        (mqtt3::QoS::AtLeastOnce, Some(pid)) => self.write(&mqtt3::Packet::Puback(pid))?,
+                        (mqtt3::QoS::ExactlyOnce, Some(pid)) => self.write(&mqtt3::Packet::Pubrec(pid))?,
+                        _ => {},
+                    }
+                },
+                mqtt3::Packet::Pubrel(pid) => {
+                    inbound.release(pid.0);
+                    self.write(&mqtt3::Packet::Pubcomp(pid))?;
+                },
+                mqtt3::Packet::Puback(pid) => {
+                    let mut inflight = self.inflight.lock().unwrap();
+                    let sent = inflight.remove(&pid.0).map(|(_, sent)| sent);
+                    let pending = inflight.len();
+                    self.metrics.update(|stats| {
+                        stats.publish_latency = sent.map(|sent| sent.elapsed()).or(stats.publish_latency);
+                        stats.inflight = pending;
+                    });
+                },
+                _ => {},
+            }
+        }
+    }
+
+    /// Hands a publish to the receiver its topic is routed to, waiting while
+    /// that receiver is full.
+    fn deliver(&self, publish: mqtt3::Publish, sender: &SyncSender<mqtt3::Publish>) {
+        debug!(topic = publish.topic_name.as_str(); "received {} bytes", publish.payload.len());
+        self.metrics.update(|stats| stats.messages_in += 1);
+        let route = self.routes.lock().unwrap().matches(&publish.topic_name)
+            .first().map(|route| (*route).clone());
+        // A receiver that went away only loses its publishes; the
+        // connection is still needed for publishing.
+        let _ = match route {
+            Some(route) => route.send(publish),
+            None => sender.send(publish),
+        };
+    }
+}
+
+/// Opens a connection and completes the CONNECT handshake, returning
+/// whether the broker still had a session for our client id.
+fn open(options: &Options)
+        -> Result<(BufReader<Stream>, BufWriter<Stream>, bool), mqtt3::Error> {
+    let stream = Stream::connect(&options.server, options.tls.as_ref())?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    // The broker answers our pings, so silence means the connection is gone.
+    let silence = u64::from(KEEP_ALIVE) * 3 / 2;
+    reader.get_mut().set_read_timeout(Some(Duration::from_secs(silence)))?;
+    let mut writer = BufWriter::new(stream);
+
+    let connect = mqtt3::Packet::Connect(Box::new(mqtt3::Connect {
+        protocol: mqtt3::Protocol::MQTT(4),
+        keep_alive: KEEP_ALIVE,
+        client_id: options.client_id.clone(),
+        clean_session: options.clean_session,
+        last_will: None,
+        username: options.username.clone(),
+        password: options.password.clone(),
+    }));
+    writer.write_packet(&connect)?;
+    writer.flush()?;
+    match reader.read_packet()? {
+        mqtt3::Packet::Connack(connack) => {
+            if connack.code != mqtt3::ConnectReturnCode::Accepted {
+                return Err(mqtt3::Error::Io(io::Error::new(io::ErrorKind::ConnectionRefused,
+                                                           format!("{:?}", connack.code))));
+            }
+            Ok((reader, writer, connack.session_present))
+        },
+        _ => Err(mqtt3::Error::IncorrectPacketFormat),
+    }
+}
+
+/// A readable account of `err`, for reporting.
+fn describe(err: &mqtt3::Error) -> String {
+    match *err {
+        mqtt3::Error::Io(ref e) => e.to_string(),
+        ref e => format!("{:?}", e),
+    }
+}
+
+pub fn mqtt_error(err: mqtt3::Error) -> io::Error {
+    match err {
+        mqtt3::Error::Io(e) => e,
+        e => io::Error::other(format!("mqtt3 error: {:?}", e)),
     }
 }
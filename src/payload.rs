@@ -0,0 +1,333 @@
+use std::io;
+
+use regex::Regex;
+use serde_json::{self, Value};
+
+use color;
+
+/// How a property's value is written to and read from MQTT payloads.
+///
+/// With the default format values are sent as their JSON text and inbound
+/// payloads are parsed as JSON, falling back to a plain string.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PayloadFormat {
+    /// Outgoing payload with `{{value}}` replaced by the mapped value, e.g.
+    /// `{"state":"{{value}}"}`, or `{{json}}` by its JSON encoding, e.g.
+    /// `{"state":{{json}}}`. In templates starting with `{` or `[` the
+    /// value is JSON-escaped, so `{{value}}` belongs inside quotes there;
+    /// use `{{json}}` for values that are not strings.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub command_template: Option<String>,
+    /// Where the value sits in an inbound JSON payload, either as a JSON
+    /// pointer (`/state/brightness`) or a dotted path (`state.brightness`).
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub state_path: Option<String>,
+    /// Regular expression applied to inbound payloads. The first capture
+    /// group, or the whole match without groups, is used as the value.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub state_pattern: Option<String>,
+    /// Translations between property values and payload text, applied in
+    /// both directions, e.g. `true` <-> `"ON"`.
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub value_map: Vec<ValueMapping>,
+    /// Wraps the extracted text before it becomes the value, e.g.
+    /// `#{{value}}` to turn `FF0000` into a color.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub state_template: Option<String>,
+    /// Topic template replacing the topic scheme's `command` topic for this
+    /// property.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub command_topic: Option<String>,
+    /// Topic templates replacing the topic scheme's `state` topic for this
+    /// property. The value is looked for in publishes on any of them.
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub state_topics: Vec<String>,
+    /// No state topic is subscribed to for this property. Its value is the
+    /// last one set, or what the discovery profile of the device reports.
+    #[serde(skip_serializing_if = "is_false", default)]
+    pub write_only: bool,
+    /// Converts values to the unit or color space the device uses before
+    /// they are sent, and back after they are read.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub conversion: Option<Conversion>,
+    #[serde(skip)]
+    compiled_pattern: Option<Regex>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ValueMapping {
+    pub value: Value,
+    pub payload: String,
+}
+
+/// How the value of a property relates to the one a device uses.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Conversion {
+    /// Color temperatures in kelvin, sent as mireds.
+    Mired,
+    /// `#rrggbb` colors, sent as CIE chromaticity `{"x":0.7,"y":0.3}`.
+    ColorXy,
+    /// `#rrggbb` colors, sent as `{"hue":0,"saturation":100}`, hue in
+    /// degrees and saturation in percent.
+    ColorHs,
+}
+
+impl Conversion {
+    fn to_device(self, value: &Value) -> Value {
+        match self {
+            Conversion::Mired => match value.as_f64() {
+                Some(kelvin) if kelvin > 0.0 => Value::from((1e6 / kelvin).round() as u64),
+                _ => value.clone(),
+            },
+            Conversion::ColorXy | Conversion::ColorHs => {
+                let rgb = match value.as_str().and_then(color::parse_hex) {
+                    Some(rgb) => rgb,
+                    None => return value.clone(),
+                };
+                match self {
+                    Conversion::ColorXy => {
+                        let (x, y) = color::rgb_to_xy(rgb);
+                        json!({ "x": round(x, 4), "y": round(y, 4) })
+                    },
+                    _ => {
+                        let (hue, saturation) = color::rgb_to_hs(rgb);
+                        json!({ "hue": hue.round(), "saturation": saturation.round() })
+                    },
+                }
+            },
+        }
+    }
+
+    /// The property value for what the device reported, or `None` if the
+    /// report cannot be converted.
+    fn to_property(self, value: &Value) -> Option<Value> {
+        match self {
+            Conversion::Mired => match value.as_f64() {
+                Some(mired) if mired > 0.0 => Some(Value::from((1e6 / mired).round() as u64)),
+                _ => None,
+            },
+            Conversion::ColorXy => {
+                let (x, y) = (value.get("x")?.as_f64()?, value.get("y")?.as_f64()?);
+                Some(Value::String(color::to_hex(color::xy_to_rgb(x, y))))
+            },
+            Conversion::ColorHs => {
+                let hue = value.get("hue")?.as_f64()?;
+                let saturation = value.get("saturation")?.as_f64()?;
+                Some(Value::String(color::to_hex(color::hs_to_rgb(hue, saturation))))
+            },
+        }
+    }
+}
+
+impl PayloadFormat {
+    /// Compiles `state_pattern` so that configuration mistakes surface when
+    /// the device is set up rather than on the first inbound message.
+    pub fn prepare(&mut self) -> Result<(), io::Error> {
+        self.compiled_pattern = match self.state_pattern {
+            Some(ref pattern) => Some(Regex::new(pattern).map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidInput,
+                               format!("invalid state pattern {:?}: {}", pattern, e))
+            })?),
+            None => None,
+        };
+        Ok(())
+    }
+
+    pub fn encode(&self, value: &Value) -> Vec<u8> {
+        let value = match self.conversion {
+            Some(conversion) => conversion.to_device(value),
+            None => value.clone(),
+        };
+        let mapped = match self.value_map.iter().find(|m| m.value == value) {
+            Some(mapping) => Value::String(mapping.payload.clone()),
+            None => value.clone(),
+        };
+        let text = match mapped {
+            Value::String(ref s) => s.clone(),
+            ref other => other.to_string(),
+        };
+        match self.command_template {
+            // In a JSON template `{{value}}` sits inside a string, so the text
+            // is escaped to keep quotes in it from ending that string.
+            Some(ref template) if is_json(template) => template
+                .replace("{{value}}", &escape_json(&text))
+                .replace("{{json}}", &mapped.to_string())
+                .into_bytes(),
+            Some(ref template) => template
+                .replace("{{value}}", &text)
+                .replace("{{json}}", &mapped.to_string())
+                .into_bytes(),
+            None if mapped == value => value.to_string().into_bytes(),
+            None => text.into_bytes(),
+        }
+    }
+
+    /// Extracts a property value from an inbound payload, or `None` if the
+    /// payload does not carry one.
+    pub fn decode(&self, payload: &[u8]) -> Option<Value> {
+        let text = String::from_utf8_lossy(payload);
+        let mut text = text.trim().to_string();
+
+        if let Some(ref re) = self.compiled_pattern {
+            let captures = re.captures(&text)?;
+            let matched = captures.get(1).or_else(|| captures.get(0))?;
+            text = matched.as_str().to_string();
+        }
+
+        let mut value = match self.state_path {
+            Some(ref path) => {
+                let json: Value = serde_json::from_str(&text).ok()?;
+                json.pointer(&to_pointer(path))?.clone()
+            },
+            None => serde_json::from_str(&text).unwrap_or_else(|_| Value::String(text.clone())),
+        };
+
+        let raw = match value {
+            Value::String(ref s) => s.clone(),
+            ref other => other.to_string(),
+        };
+        if let Some(ref template) = self.state_template {
+            value = Value::String(template.replace("{{value}}", &raw));
+        }
+        if let Some(mapping) = self.value_map.iter().find(|m| m.payload == raw) {
+            value = mapping.value.clone();
+        }
+        match self.conversion {
+            Some(conversion) => conversion.to_property(&value),
+            None => Some(value),
+        }
+    }
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+
+fn round(value: f64, digits: i32) -> f64 {
+    let scale = 10f64.powi(digits);
+    (value * scale).round() / scale
+}
+
+fn is_json(template: &str) -> bool {
+    let template = template.trim();
+    template.starts_with('{') || template.starts_with('[')
+}
+
+/// `text` as the contents of a JSON string, without the quotes.
+fn escape_json(text: &str) -> String {
+    let quoted = Value::String(text.to_string()).to_string();
+    quoted[1..quoted.len() - 1].to_string()
+}
+
+fn to_pointer(path: &str) -> String {
+    if path.is_empty() || path.starts_with('/') {
+        return path.to_string();
+    }
+    path.split('.').fold(String::new(), |mut pointer, part| {
+        pointer.push('/');
+        pointer.push_str(&part.replace('~', "~0").replace('/', "~1"));
+        pointer
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(json: Value) -> PayloadFormat {
+        let mut format: PayloadFormat = serde_json::from_value(json).unwrap();
+        format.prepare().unwrap();
+        format
+    }
+
+    #[test]
+    fn escapes_values_in_json_templates() {
+        let format = format(json!({"commandTemplate": "{\"name\":\"{{value}}\"}"}));
+        let payload = format.encode(&json!("say \"hi\""));
+        let sent: Value = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(sent, json!({"name": "say \"hi\""}));
+
+        let format = self::format(json!({"commandTemplate": "name={{value}}"}));
+        assert_eq!(format.encode(&json!("say \"hi\"")), b"name=say \"hi\"".to_vec());
+    }
+
+    #[test]
+    fn encodes_mapped_values() {
+        let format = format(json!({
+            "commandTemplate": "{\"state\":\"{{value}}\",\"raw\":{{json}}}",
+            "valueMap": [{ "value": true, "payload": "ON" }],
+        }));
+        let sent: Value = serde_json::from_slice(&format.encode(&json!(true))).unwrap();
+        assert_eq!(sent, json!({"state": "ON", "raw": "ON"}));
+
+        let plain = self::format(json!({"valueMap": [{ "value": false, "payload": "off" }]}));
+        assert_eq!(plain.encode(&json!(false)), b"off".to_vec());
+        assert_eq!(plain.encode(&json!(true)), b"true".to_vec());
+        assert_eq!(plain.encode(&json!("text")), b"\"text\"".to_vec());
+    }
+
+    #[test]
+    fn decodes_json_and_plain_text() {
+        let plain = PayloadFormat::default();
+        assert_eq!(plain.decode(b" 21.5\n"), Some(json!(21.5)));
+        assert_eq!(plain.decode(b"{\"a\":1}"), Some(json!({"a": 1})));
+        assert_eq!(plain.decode(b"online"), Some(json!("online")));
+    }
+
+    #[test]
+    fn decodes_state_paths() {
+        let payload = br#"{"state":{"brightness":80,"a/b":true}}"#;
+        let dotted = format(json!({"statePath": "state.brightness"}));
+        assert_eq!(dotted.decode(payload), Some(json!(80)));
+        let escaped = format(json!({"statePath": "state.a/b"}));
+        assert_eq!(escaped.decode(payload), Some(json!(true)));
+        let pointer = format(json!({"statePath": "/state/brightness"}));
+        assert_eq!(pointer.decode(payload), Some(json!(80)));
+
+        assert_eq!(pointer.decode(br#"{"state":{}}"#), None);
+        assert_eq!(pointer.decode(b"not json"), None);
+    }
+
+    #[test]
+    fn decodes_patterns_templates_and_maps() {
+        let color = format(json!({
+            "statePattern": "\"Color\":\"([0-9A-F]{6})",
+            "stateTemplate": "#{{value}}",
+        }));
+        assert_eq!(color.decode(br#"{"Color":"FF0000","White":0}"#), Some(json!("#FF0000")));
+        assert_eq!(color.decode(br#"{"POWER":"ON"}"#), None);
+
+        let power = format(json!({
+            "statePattern": "^(?:\\{.*\"POWER\":\")?(ON|OFF)",
+            "valueMap": [
+                { "value": true, "payload": "ON" },
+                { "value": false, "payload": "OFF" },
+            ],
+        }));
+        assert_eq!(power.decode(b"ON"), Some(json!(true)));
+        assert_eq!(power.decode(br#"{"POWER":"OFF"}"#), Some(json!(false)));
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        let mut format: PayloadFormat = serde_json::from_value(json!({"statePattern": "("})).unwrap();
+        let err = format.prepare().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn converts_hue_and_saturation() {
+        let format = format(json!({
+            "commandTemplate": "{\"color\":{{json}}}",
+            "statePath": "color",
+            "conversion": "colorHs",
+        }));
+        let sent: Value = serde_json::from_slice(&format.encode(&json!("#0000ff"))).unwrap();
+        assert_eq!(sent, json!({ "color": { "hue": 240.0, "saturation": 100.0 } }));
+        assert_eq!(format.decode(br#"{"color":{"hue":120,"saturation":50}}"#), Some(json!("#80ff80")));
+        // Values that are not colors are sent as they are.
+        assert_eq!(format.encode(&json!("blue")), br#"{"color":"blue"}"#.to_vec());
+    }
+}
@@ -0,0 +1,215 @@
+use std::io;
+
+const VARINT: u8 = 0;
+const FIXED64: u8 = 1;
+const LENGTH_DELIMITED: u8 = 2;
+const FIXED32: u8 = 5;
+
+/// One field read from a message.
+pub enum Field<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    Bytes(&'a [u8]),
+    Fixed32(u32),
+}
+
+impl<'a> Field<'a> {
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            Field::Varint(v) | Field::Fixed64(v) => Some(v),
+            Field::Fixed32(v) => Some(v as u64),
+            Field::Bytes(_) => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&'a [u8]> {
+        match *self {
+            Field::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    pub fn as_string(&self) -> Option<String> {
+        self.as_bytes().map(|bytes| String::from_utf8_lossy(bytes).into_owned())
+    }
+}
+
+/// Iterates over the fields of an encoded protocol buffers message. Only the
+/// wire types Sparkplug B payloads use are supported.
+pub struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Reader<'a> {
+        Reader { buf }
+    }
+
+    /// The next field number and value, or `None` at the end of the message.
+    pub fn next_field(&mut self) -> Result<Option<(u32, Field<'a>)>, io::Error> {
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+        let key = self.varint()?;
+        let number = (key >> 3) as u32;
+        let field = match (key & 7) as u8 {
+            VARINT => Field::Varint(self.varint()?),
+            FIXED64 => {
+                let bytes = self.take(8)?;
+                Field::Fixed64(bytes.iter().rev().fold(0, |v, &b| (v << 8) | b as u64))
+            },
+            LENGTH_DELIMITED => {
+                let len = self.varint()? as usize;
+                Field::Bytes(self.take(len)?)
+            },
+            FIXED32 => {
+                let bytes = self.take(4)?;
+                Field::Fixed32(bytes.iter().rev().fold(0, |v, &b| (v << 8) | b as u32))
+            },
+            wire_type => return Err(malformed(&format!("unsupported wire type {}", wire_type))),
+        };
+        Ok(Some((number, field)))
+    }
+
+    fn varint(&mut self) -> Result<u64, io::Error> {
+        let mut value = 0u64;
+        for (i, &byte) in self.buf.iter().enumerate().take(10) {
+            value |= ((byte & 0x7f) as u64) << (7 * i);
+            if byte & 0x80 == 0 {
+                self.buf = &self.buf[i + 1..];
+                return Ok(value);
+            }
+        }
+        Err(malformed("truncated varint"))
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], io::Error> {
+        if len > self.buf.len() {
+            return Err(malformed("truncated field"));
+        }
+        let (field, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(field)
+    }
+}
+
+fn malformed(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("malformed protobuf: {}", reason))
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn write_key(buf: &mut Vec<u8>, number: u32, wire_type: u8) {
+    write_varint(buf, ((number as u64) << 3) | wire_type as u64);
+}
+
+pub fn write_uint(buf: &mut Vec<u8>, number: u32, value: u64) {
+    write_key(buf, number, VARINT);
+    write_varint(buf, value);
+}
+
+pub fn write_bool(buf: &mut Vec<u8>, number: u32, value: bool) {
+    write_uint(buf, number, value as u64);
+}
+
+pub fn write_float(buf: &mut Vec<u8>, number: u32, value: f32) {
+    write_key(buf, number, FIXED32);
+    let bits = value.to_bits();
+    buf.extend((0..4).map(|i| (bits >> (8 * i)) as u8));
+}
+
+pub fn write_double(buf: &mut Vec<u8>, number: u32, value: f64) {
+    write_key(buf, number, FIXED64);
+    let bits = value.to_bits();
+    buf.extend((0..8).map(|i| (bits >> (8 * i)) as u8));
+}
+
+pub fn write_bytes(buf: &mut Vec<u8>, number: u32, value: &[u8]) {
+    write_key(buf, number, LENGTH_DELIMITED);
+    write_varint(buf, value.len() as u64);
+    buf.extend_from_slice(value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// A field number with its value as a varint or as bytes.
+    type Decoded = (u32, Option<u64>, Option<Vec<u8>>);
+
+    fn fields(buf: &[u8]) -> Result<Vec<Decoded>, io::Error> {
+        let mut reader = Reader::new(buf);
+        let mut fields = Vec::new();
+        while let Some((number, field)) = reader.next_field()? {
+            fields.push((number, field.as_u64(), field.as_bytes().map(|b| b.to_vec())));
+        }
+        Ok(fields)
+    }
+
+    #[test]
+    fn reads_known_encodings() {
+        // Field 1 = 150 and field 2 = "testing", from the protocol buffers
+        // encoding guide.
+        let buf = [0x08, 0x96, 0x01, 0x12, 0x07, b't', b'e', b's', b't', b'i', b'n', b'g'];
+        assert_eq!(fields(&buf).unwrap(), vec![
+            (1, Some(150), None),
+            (2, None, Some(b"testing".to_vec())),
+        ]);
+    }
+
+    #[test]
+    fn rejects_truncated_messages() {
+        let mut buf = Vec::new();
+        write_bytes(&mut buf, 1, b"payload");
+        for len in 1..buf.len() {
+            assert!(fields(&buf[..len]).is_err(), "{:?} accepted", &buf[..len]);
+        }
+        assert!(fields(&[0x08, 0x80]).is_err());
+        // Groups (wire type 3) do not occur in Sparkplug B.
+        assert!(fields(&[0x0b]).is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn fields_round_trip(number in 1u32..(1 << 29), int in any::<u64>(), flag in any::<bool>(),
+                             float in any::<f32>(), double in any::<f64>(),
+                             bytes in proptest::collection::vec(any::<u8>(), 0..300)) {
+            let mut buf = Vec::new();
+            write_uint(&mut buf, number, int);
+            write_bool(&mut buf, number, flag);
+            write_float(&mut buf, number, float);
+            write_double(&mut buf, number, double);
+            write_bytes(&mut buf, number, &bytes);
+
+            let mut reader = Reader::new(&buf);
+            match reader.next_field().unwrap() {
+                Some((n, Field::Varint(v))) => prop_assert_eq!((n, v), (number, int)),
+                _ => prop_assert!(false, "no varint"),
+            }
+            match reader.next_field().unwrap() {
+                Some((n, Field::Varint(v))) => prop_assert_eq!((n, v), (number, flag as u64)),
+                _ => prop_assert!(false, "no bool"),
+            }
+            match reader.next_field().unwrap() {
+                Some((n, Field::Fixed32(v))) => prop_assert_eq!((n, v), (number, float.to_bits())),
+                _ => prop_assert!(false, "no float"),
+            }
+            match reader.next_field().unwrap() {
+                Some((n, Field::Fixed64(v))) => prop_assert_eq!((n, v), (number, double.to_bits())),
+                _ => prop_assert!(false, "no double"),
+            }
+            match reader.next_field().unwrap() {
+                Some((n, Field::Bytes(b))) => prop_assert_eq!((n, b), (number, &bytes[..])),
+                _ => prop_assert!(false, "no bytes"),
+            }
+            prop_assert!(reader.next_field().unwrap().is_none());
+        }
+    }
+}
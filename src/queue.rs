@@ -0,0 +1,207 @@
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json;
+
+/// A publish waiting for the broker to become reachable again.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Queued {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub retain: bool,
+    /// Publishes with the same key replace each other, e.g. successive
+    /// values of one property.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub key: Option<String>,
+    /// Seconds since the epoch after which the publish is dropped.
+    pub expires: u64,
+}
+
+/// Publishes held while disconnected, oldest first, optionally mirrored to a
+/// file so that they survive a restart.
+pub struct OfflineQueue {
+    entries: VecDeque<Queued>,
+    expiry: u64,
+    capacity: usize,
+    path: Option<PathBuf>,
+}
+
+impl OfflineQueue {
+    /// A queue keeping publishes for `expiry` seconds and at most `capacity`
+    /// of them. With a `path`, publishes queued by an earlier run are loaded
+    /// from it.
+    pub fn new(expiry: u64, capacity: usize, path: Option<PathBuf>) -> Result<OfflineQueue, io::Error> {
+        let entries = match path {
+            Some(ref path) => match File::open(path) {
+                Ok(file) => serde_json::from_reader(file).map_err(|e| {
+                    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e))
+                })?,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => VecDeque::new(),
+                Err(e) => return Err(e),
+            },
+            None => VecDeque::new(),
+        };
+        Ok(OfflineQueue {
+            entries,
+            expiry,
+            capacity,
+            path,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Queues a publish. One with the same key as a queued publish takes its
+    /// place in the queue, so that it goes out in the order the first of them
+    /// was made. The oldest publish is dropped when the queue is full.
+    pub fn push(&mut self, topic: &str, payload: Vec<u8>, retain: bool, key: Option<&str>) {
+        let queued = Queued {
+            topic: topic.to_string(),
+            payload,
+            retain,
+            key: key.map(|k| k.to_string()),
+            expires: now() + self.expiry,
+        };
+        let replaced = key.and_then(|key| {
+            self.entries.iter_mut().find(|queued| queued.key.as_deref() == Some(key))
+        });
+        match replaced {
+            Some(replaced) => *replaced = queued,
+            None => {
+                if self.entries.len() >= self.capacity {
+                    if let Some(dropped) = self.entries.pop_front() {
+                        warn!(topic = dropped.topic.as_str(); "queue full, dropping publish");
+                    }
+                }
+                self.entries.push_back(queued);
+            },
+        }
+        self.persist();
+    }
+
+    /// Puts back a publish that could not be sent, ahead of the others.
+    pub fn requeue(&mut self, queued: Queued) {
+        self.entries.push_front(queued);
+    }
+
+    /// Takes the oldest publish that has not expired. Like `requeue`, this
+    /// leaves the file alone: `persist` once done taking publishes.
+    pub fn pop(&mut self) -> Option<Queued> {
+        let now = now();
+        while let Some(queued) = self.entries.pop_front() {
+            if queued.expires > now {
+                return Some(queued);
+            }
+            info!(topic = queued.topic.as_str(); "queued publish expired");
+        }
+        None
+    }
+
+    /// Writes the queued publishes to the file, if there is one.
+    pub fn persist(&self) {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return,
+        };
+        let result = (|| -> Result<(), io::Error> {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            let tmp = path.with_extension("tmp");
+            serde_json::to_writer(File::create(&tmp)?, &self.entries)
+                .map_err(io::Error::other)?;
+            fs::rename(&tmp, path)
+        })();
+        if let Err(e) = result {
+            error!("could not write {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Seconds since the epoch.
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    fn topics(queue: &mut OfflineQueue) -> Vec<String> {
+        let mut topics = Vec::new();
+        while let Some(queued) = queue.pop() {
+            topics.push(queued.topic);
+        }
+        topics
+    }
+
+    #[test]
+    fn expired_publishes_are_dropped() {
+        let mut queue = OfflineQueue::new(0, 10, None).unwrap();
+        queue.push("a", b"1".to_vec(), false, None);
+        assert_eq!(queue.len(), 1);
+        assert!(queue.pop().is_none());
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn keyed_publishes_replace_each_other_in_place() {
+        let mut queue = OfflineQueue::new(60, 10, None).unwrap();
+        queue.push("lamp/on", b"true".to_vec(), false, Some("lamp/on"));
+        queue.push("door/open", b"true".to_vec(), false, None);
+        queue.push("lamp/on", b"false".to_vec(), true, Some("lamp/on"));
+        assert_eq!(queue.len(), 2);
+        let first = queue.pop().unwrap();
+        assert_eq!((first.topic.as_str(), &first.payload[..], first.retain), ("lamp/on", &b"false"[..], true));
+        assert_eq!(topics(&mut queue), vec!["door/open"]);
+    }
+
+    #[test]
+    fn full_queues_drop_the_oldest_publish() {
+        let mut queue = OfflineQueue::new(60, 2, None).unwrap();
+        queue.push("a", Vec::new(), false, None);
+        queue.push("b", Vec::new(), false, Some("b"));
+        queue.push("c", Vec::new(), false, None);
+        // Replacing a queued publish makes no room for another.
+        queue.push("b", Vec::new(), false, Some("b"));
+        assert_eq!(topics(&mut queue), vec!["b", "c"]);
+    }
+
+    #[test]
+    fn requeued_publishes_go_first() {
+        let mut queue = OfflineQueue::new(60, 10, None).unwrap();
+        queue.push("a", Vec::new(), false, None);
+        queue.push("b", Vec::new(), false, None);
+        let first = queue.pop().unwrap();
+        queue.requeue(first);
+        assert_eq!(topics(&mut queue), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn persisted_publishes_survive_a_restart() {
+        let path = env::temp_dir().join(format!("mqtt-adapter-queue-{}", process::id()))
+            .join("queue.json");
+        let mut queue = OfflineQueue::new(60, 10, Some(path.clone())).unwrap();
+        queue.push("a", b"1".to_vec(), true, Some("a"));
+        queue.push("b", b"2".to_vec(), false, None);
+
+        let mut restored = OfflineQueue::new(60, 10, Some(path.clone())).unwrap();
+        let first = restored.pop().unwrap();
+        assert_eq!((first.topic.as_str(), &first.payload[..], first.retain), ("a", &b"1"[..], true));
+        assert_eq!(first.key, Some("a".to_string()));
+        assert_eq!(topics(&mut restored), vec!["b"]);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
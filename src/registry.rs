@@ -0,0 +1,134 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use serde_json::{self, Value};
+
+use config::DeviceConfig;
+
+/// How long value changes may wait before being written out.
+const SAVE_INTERVAL: u64 = 10;
+
+/// What is remembered about one device between runs.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RegisteredDevice {
+    /// Definition of a discovered device. Devices from the configuration
+    /// file are rebuilt from it instead.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub config: Option<DeviceConfig>,
+    /// Name chosen by the user in the gateway, kept when the device is
+    /// rediscovered.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub name: Option<String>,
+    /// Last known property values.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty", default)]
+    pub values: BTreeMap<String, Value>,
+}
+
+/// Devices and their state persisted in the add-on data directory, keyed by
+/// device id, so that they can be announced again right after a restart.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Registry {
+    devices: BTreeMap<String, RegisteredDevice>,
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(skip)]
+    dirty: bool,
+    #[serde(skip)]
+    saved: Option<Instant>,
+}
+
+impl Registry {
+    /// Reads the registry at `path`, starting empty if there is none yet.
+    pub fn load(path: &Path) -> Result<Registry, io::Error> {
+        let mut registry = match File::open(path) {
+            Ok(file) => serde_json::from_reader(file).map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e))
+            })?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Registry::default(),
+            Err(e) => return Err(e),
+        };
+        registry.path = path.to_path_buf();
+        Ok(registry)
+    }
+
+    pub fn get(&self, device_id: &str) -> Option<&RegisteredDevice> {
+        self.devices.get(device_id)
+    }
+
+    /// Discovered devices as `(device_id, definition)` pairs.
+    pub fn discovered(&self) -> Vec<(String, DeviceConfig)> {
+        self.devices.iter()
+            .filter_map(|(id, device)| device.config.clone().map(|config| (id.clone(), config)))
+            .collect()
+    }
+
+    /// Remembers the definition of a discovered device and saves at once.
+    pub fn record_device(&mut self, device_id: &str, config: &DeviceConfig) -> Result<(), io::Error> {
+        self.entry(device_id).config = Some(config.clone());
+        self.save()
+    }
+
+    /// Forgets a removed device and saves at once.
+    pub fn forget(&mut self, device_id: &str) -> Result<(), io::Error> {
+        if self.devices.remove(device_id).is_some() {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// Remembers the name the user gave a device and saves at once.
+    pub fn record_name(&mut self, device_id: &str, name: &str) -> Result<(), io::Error> {
+        self.entry(device_id).name = Some(name.to_string());
+        self.save()
+    }
+
+    /// Remembers a property value. Values are written out by `flush`.
+    pub fn record_value(&mut self, device_id: &str, name: &str, value: &Value) {
+        let values = &mut self.entry(device_id).values;
+        if values.get(name) != Some(value) {
+            values.insert(name.to_string(), value.clone());
+            self.dirty = true;
+        }
+    }
+
+    /// Saves pending value changes if the last save was long enough ago.
+    pub fn flush(&mut self) -> Result<(), io::Error> {
+        let due = self.saved.is_none_or(|saved| saved.elapsed() >= Duration::from_secs(SAVE_INTERVAL));
+        if self.dirty && due {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// Saves pending value changes right away, e.g. before unloading.
+    pub fn flush_now(&mut self) -> Result<(), io::Error> {
+        if self.dirty {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the registry, replacing the previous file only once the new
+    /// one is complete.
+    pub fn save(&mut self) -> Result<(), io::Error> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp = self.path.with_extension("tmp");
+        serde_json::to_writer_pretty(File::create(&tmp)?, self).map_err(|e| {
+            io::Error::other(format!("{}: {}", tmp.display(), e))
+        })?;
+        fs::rename(&tmp, &self.path)?;
+        self.dirty = false;
+        self.saved = Some(Instant::now());
+        Ok(())
+    }
+
+    fn entry(&mut self, device_id: &str) -> &mut RegisteredDevice {
+        self.devices.entry(device_id.to_string()).or_default()
+    }
+}
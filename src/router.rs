@@ -0,0 +1,293 @@
+use std::collections::HashMap;
+use std::io;
+
+/// One topic level in a `Router`.
+struct Node<T> {
+    /// Values of the filters ending at this level.
+    values: Vec<T>,
+    children: HashMap<String, Node<T>>,
+}
+
+impl<T> Node<T> {
+    fn new() -> Node<T> {
+        Node {
+            values: Vec::new(),
+            children: HashMap::new(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.values.is_empty() && self.children.is_empty()
+    }
+
+    fn collect<'a>(&'a self, levels: &[&str], first: bool, out: &mut Vec<&'a T>) {
+        // `a/#` also matches `a` itself.
+        if let Some(all) = self.children.get("#") {
+            if !(first && levels[0].starts_with('$')) {
+                out.extend(all.values.iter());
+            }
+        }
+        let (level, rest) = match levels.split_first() {
+            Some(split) => split,
+            None => {
+                out.extend(self.values.iter());
+                return;
+            }
+        };
+        if let Some(child) = self.children.get(*level) {
+            child.collect(rest, false, out);
+        }
+        // Wildcards at the first level do not match topics starting with `$`,
+        // such as the broker's `$SYS` statistics.
+        if first && level.starts_with('$') {
+            return;
+        }
+        if let Some(child) = self.children.get("+") {
+            child.collect(rest, false, out);
+        }
+    }
+
+    fn retain<F: FnMut(&T) -> bool>(&mut self, keep: &mut F) {
+        self.values.retain(|value| keep(value));
+        for child in self.children.values_mut() {
+            child.retain(keep);
+        }
+        self.children.retain(|_, child| !child.is_empty());
+    }
+}
+
+/// Values registered under MQTT topic filters, found by the topics they
+/// match. Filters are kept in a trie of topic levels, so that a lookup only
+/// visits the filters that share a prefix with the topic or have wildcards
+/// along it, however many filters there are.
+pub struct Router<T> {
+    root: Node<T>,
+}
+
+impl<T> Router<T> {
+    pub fn new() -> Router<T> {
+        Router {
+            root: Node::new(),
+        }
+    }
+
+    /// Registers `value` under `filter`. Fails if the filter is not valid.
+    pub fn insert(&mut self, filter: &str, value: T) -> Result<(), io::Error> {
+        if !valid_filter(filter) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("invalid topic filter {:?}", filter)));
+        }
+        let mut node = &mut self.root;
+        for level in filter.split('/') {
+            node = node.children.entry(level.to_string()).or_insert_with(Node::new);
+        }
+        node.values.push(value);
+        Ok(())
+    }
+
+    /// Keeps only the values for which `keep` returns true.
+    pub fn retain<F: FnMut(&T) -> bool>(&mut self, mut keep: F) {
+        self.root.retain(&mut keep);
+    }
+
+    /// Values of the filters matching `topic`. A value registered under
+    /// several matching filters is returned once for each of them.
+    pub fn matches(&self, topic: &str) -> Vec<&T> {
+        let levels: Vec<&str> = topic.split('/').collect();
+        let mut out = Vec::new();
+        self.root.collect(&levels, true, &mut out);
+        out
+    }
+}
+
+/// Whether `filter` may be subscribed to: `#` only as the last level and
+/// wildcards only as whole levels.
+pub fn valid_filter(filter: &str) -> bool {
+    if filter.is_empty() {
+        return false;
+    }
+    let levels: Vec<&str> = filter.split('/').collect();
+    levels.iter().enumerate().all(|(i, level)| match *level {
+        "#" => i == levels.len() - 1,
+        "+" => true,
+        level => !level.contains('#') && !level.contains('+'),
+    })
+}
+
+/// Whether `filter` matches `topic`, level by level. This is what `Router`
+/// computes for many filters at once.
+pub fn matches(filter: &str, topic: &str) -> bool {
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+    let mut filter = filter.split('/');
+    let mut topic = topic.split('/');
+    loop {
+        match (filter.next(), topic.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {},
+            (Some(f), Some(t)) => if f != t {
+                return false;
+            },
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn routed(filters: &[&str], topic: &str) -> Vec<String> {
+        let mut router = Router::new();
+        for filter in filters {
+            router.insert(filter, filter.to_string()).unwrap();
+        }
+        let mut found: Vec<String> = router.matches(topic).into_iter().cloned().collect();
+        found.sort();
+        found
+    }
+
+    #[test]
+    fn exact_filters() {
+        assert_eq!(routed(&["a/b", "a/c", "a"], "a/b"), vec!["a/b"]);
+        assert!(routed(&["a/b"], "a/b/c").is_empty());
+        assert!(routed(&["a/b/c"], "a/b").is_empty());
+        assert!(routed(&["A/b"], "a/b").is_empty());
+    }
+
+    #[test]
+    fn single_level_wildcard() {
+        // Examples from section 4.7.1.3 of the MQTT 3.1.1 specification.
+        assert!(matches("sport/tennis/+", "sport/tennis/player1"));
+        assert!(matches("sport/tennis/+", "sport/tennis/player2"));
+        assert!(!matches("sport/tennis/+", "sport/tennis/player1/ranking"));
+        assert!(matches("sport/+", "sport/"));
+        assert!(!matches("sport/+", "sport"));
+        assert!(matches("+/+", "/finance"));
+        assert!(matches("/+", "/finance"));
+        assert!(!matches("+", "/finance"));
+        assert_eq!(routed(&["+/tennis/#", "sport/+/player1", "+"], "sport/tennis/player1"),
+                   vec!["+/tennis/#", "sport/+/player1"]);
+    }
+
+    #[test]
+    fn multi_level_wildcard() {
+        // Examples from section 4.7.1.2 of the MQTT 3.1.1 specification.
+        for topic in &["sport/tennis/player1", "sport/tennis/player1/ranking",
+                       "sport/tennis/player1/score/wimbledon"] {
+            assert!(matches("sport/tennis/player1/#", topic));
+        }
+        assert!(matches("sport/#", "sport"));
+        assert!(matches("#", "sport/tennis"));
+        assert!(matches("#", "/"));
+        assert_eq!(routed(&["sport/#", "sport/tennis/#", "#"], "sport"),
+                   vec!["#", "sport/#"]);
+    }
+
+    #[test]
+    fn dollar_topics() {
+        assert!(!matches("#", "$SYS/broker/uptime"));
+        assert!(!matches("+/broker/uptime", "$SYS/broker/uptime"));
+        assert!(matches("$SYS/#", "$SYS/broker/uptime"));
+        assert!(matches("$SYS/+/uptime", "$SYS/broker/uptime"));
+        assert!(matches("a/#", "a/$b"));
+        assert_eq!(routed(&["#", "+/#", "$SYS/#", "$SYS"], "$SYS"), vec!["$SYS", "$SYS/#"]);
+    }
+
+    #[test]
+    fn filter_validation() {
+        for filter in &["#", "+", "a/#", "+/+/#", "/", "a//b", "$SYS/#"] {
+            assert!(valid_filter(filter), "{}", filter);
+        }
+        for filter in &["", "a/#/b", "a#", "a/b+", "#/", "sport+"] {
+            assert!(!valid_filter(filter), "{}", filter);
+        }
+        assert!(Router::new().insert("a/#/b", ()).is_err());
+    }
+
+    #[test]
+    fn repeated_values() {
+        let mut router = Router::new();
+        router.insert("a/+", 1).unwrap();
+        router.insert("a/b", 1).unwrap();
+        router.insert("a/b", 2).unwrap();
+        let mut found = router.matches("a/b");
+        found.sort();
+        assert_eq!(found, vec![&1, &1, &2]);
+    }
+
+    #[test]
+    fn retain_prunes() {
+        let mut router = Router::new();
+        router.insert("a/b/c", 1).unwrap();
+        router.insert("a/+", 2).unwrap();
+        router.retain(|v| *v != 1);
+        assert_eq!(router.matches("a/b"), vec![&2]);
+        assert!(router.matches("a/b/c").is_empty());
+        router.retain(|v| *v != 2);
+        assert!(router.root.is_empty());
+    }
+
+    fn level() -> BoxedStrategy<String> {
+        prop::sample::select(vec!["", "a", "b", "$SYS", "$x"]).prop_map(|s| s.to_string()).boxed()
+    }
+
+    fn topic() -> BoxedStrategy<String> {
+        prop::collection::vec(level(), 1..5).prop_map(|levels| levels.join("/"))
+            .prop_filter("topics are not empty", |topic| !topic.is_empty()).boxed()
+    }
+
+    fn filter() -> BoxedStrategy<String> {
+        let level = prop_oneof![3 => level(), 1 => Just("+".to_string())];
+        (prop::collection::vec(level, 1..5), any::<bool>()).prop_map(|(mut levels, all)| {
+            if all {
+                levels.push("#".to_string());
+            }
+            levels.join("/")
+        }).prop_filter("filters are not empty", |filter| !filter.is_empty()).boxed()
+    }
+
+    proptest! {
+        #[test]
+        fn router_agrees_with_matches(filters in prop::collection::vec(filter(), 0..20),
+                                      topic in topic()) {
+            let mut router = Router::new();
+            for (i, filter) in filters.iter().enumerate() {
+                router.insert(filter, i).unwrap();
+            }
+            let mut found: Vec<usize> = router.matches(&topic).into_iter().cloned().collect();
+            found.sort();
+            let expected: Vec<usize> = (0..filters.len())
+                .filter(|&i| matches(&filters[i], &topic))
+                .collect();
+            prop_assert_eq!(found, expected);
+        }
+
+        #[test]
+        fn topic_matches_itself(topic in topic()) {
+            prop_assert!(valid_filter(&topic));
+            prop_assert!(matches(&topic, &topic));
+        }
+
+        #[test]
+        fn plus_matches_any_level(topic in topic(), index in any::<prop::sample::Index>()) {
+            let mut levels: Vec<&str> = topic.split('/').collect();
+            let i = index.index(levels.len());
+            let dollar = i == 0 && levels[0].starts_with('$');
+            levels[i] = "+";
+            prop_assert_eq!(matches(&levels.join("/"), &topic), !dollar);
+        }
+
+        #[test]
+        fn hash_matches_descendants(topic in topic(), suffix in topic()) {
+            let filter = format!("{}/#", topic);
+            let descendant = format!("{}/{}", topic, suffix);
+            prop_assert!(matches(&filter, &topic));
+            prop_assert!(matches(&filter, &descendant));
+            prop_assert_eq!(matches("#", &topic), !topic.starts_with('$'));
+        }
+    }
+}
@@ -0,0 +1,427 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+
+use serde_json::{self, Value};
+
+use config::DeviceConfig;
+use discovery::{add_property, push_type, Discovery, DiscoveryEvent};
+use gateway::{ActionDescription, PropertyDescription};
+use mqtt;
+use payload::{PayloadFormat, ValueMapping};
+use topics::TopicScheme;
+
+/// `src` sent with Gen2 RPC requests. Replies go to `<src>/rpc`, which
+/// nothing listens to; commands are confirmed by the next status update.
+const RPC_SOURCE: &str = "mqtt-adapter";
+
+/// Builds devices from Shelly Gen1 announcements on `<prefix>/announce` and
+/// from Gen2 status updates on `<device>/status/<component>:<id>`.
+pub struct Shelly {
+    prefix: String,
+    /// Last announcement of each Gen1 device.
+    gen1: HashMap<String, Value>,
+    /// Components seen for each Gen2 device.
+    gen2: HashMap<String, BTreeSet<String>>,
+}
+
+impl Shelly {
+    pub fn new(prefix: &str) -> Shelly {
+        Shelly {
+            prefix: prefix.to_string(),
+            gen1: HashMap::new(),
+            gen2: HashMap::new(),
+        }
+    }
+
+    fn handle_announce(&mut self, payload: &[u8]) -> Vec<DiscoveryEvent> {
+        let announce: Value = match serde_json::from_slice(payload) {
+            Ok(announce) => announce,
+            Err(e) => {
+                warn!("unreadable announce: {}", e);
+                return Vec::new();
+            }
+        };
+        let id = match announce.get("id").and_then(Value::as_str) {
+            Some(id) => id.to_string(),
+            None => return Vec::new(),
+        };
+        if self.gen1.get(&id) == Some(&announce) {
+            return Vec::new();
+        }
+        let config = gen1_config(&self.prefix, &id, &announce);
+        self.gen1.insert(id, announce);
+        config.map(|c| vec![DiscoveryEvent::Added(Box::new(c))]).unwrap_or_default()
+    }
+
+    fn handle_status(&mut self, device: &str, component: &str) -> Vec<DiscoveryEvent> {
+        if !device.starts_with("shelly") || gen2_kind(component).is_none() {
+            return Vec::new();
+        }
+        let components = self.gen2.entry(device.to_string()).or_default();
+        if !components.insert(component.to_string()) {
+            return Vec::new();
+        }
+        vec![DiscoveryEvent::Added(Box::new(gen2_config(device, components)))]
+    }
+}
+
+impl Discovery for Shelly {
+    fn topics(&self) -> Vec<String> {
+        vec![
+            format!("{}/announce", self.prefix),
+            "+/status/+".to_string(),
+        ]
+    }
+
+    fn handle_publish(&mut self, topic: &str, payload: &[u8]) -> Vec<DiscoveryEvent> {
+        if topic == format!("{}/announce", self.prefix) {
+            return self.handle_announce(payload);
+        }
+        let parts: Vec<&str> = topic.split('/').collect();
+        match parts.as_slice() {
+            &[device, "status", component] => self.handle_status(device, component),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Asks every Gen1 device to announce itself again.
+    fn start_pairing(&mut self, mqtt: &mqtt::MQTT, _timeout: f64) -> Result<(), io::Error> {
+        mqtt.publish(&format!("{}/command", self.prefix), b"announce".to_vec())
+            .map_err(mqtt::mqtt_error)
+    }
+}
+
+/// Relay count, roller, dimmer, power metering and internal temperature for
+/// the Gen1 models we know about.
+fn gen1_features(model: &str, mode: Option<&str>) -> Option<(usize, bool, bool, bool, bool)> {
+    let roller = mode == Some("roller");
+    Some(match model {
+        "SHSW-1" => (1, false, false, false, false),
+        "SHSW-PM" => (1, false, false, true, true),
+        "SHSW-21" => (if roller { 0 } else { 2 }, roller, false, true, false),
+        "SHSW-25" => (if roller { 0 } else { 2 }, roller, false, true, true),
+        "SHSW-44" => (4, false, false, true, false),
+        "SHPLG-1" | "SHPLG2-1" | "SHPLG-U1" => (1, false, false, true, false),
+        "SHPLG-S" => (1, false, false, true, true),
+        "SHDM-1" | "SHDM-2" => (0, false, true, true, true),
+        _ => return None,
+    })
+}
+
+fn gen1_config(prefix: &str, id: &str, announce: &Value) -> Option<DeviceConfig> {
+    let model = announce.get("model").and_then(Value::as_str).unwrap_or("");
+    let mode = announce.get("mode").and_then(Value::as_str);
+    let (relays, roller, dimmer, power, temperature) = match gen1_features(model, mode) {
+        Some(features) => features,
+        None => {
+            info!("unsupported model {} for {}", model, id);
+            return None;
+        }
+    };
+
+    let mut config = DeviceConfig::new(&format!("shelly-{}", id), id);
+    config.topic = Some(id.to_string());
+    config.base = Some(prefix.to_string());
+    config.topics = Some(TopicScheme {
+        base: prefix.to_string(),
+        command: "{base}/{device}/{property}/command".to_string(),
+        state: "{base}/{device}/{property}".to_string(),
+        action: "{base}/{device}/{action}".to_string(),
+        action_payload: "{action}".to_string(),
+        payloads: HashMap::new(),
+    });
+
+    for i in 0..relays {
+        let format = on_off_format(&format!("{{base}}/{{device}}/relay/{}/command", i),
+                                   &format!("{{base}}/{{device}}/relay/{}", i), None);
+        add_property(&mut config, on_off(&channel_name("relay", i, relays), relays, i), format);
+        if power {
+            add_property(&mut config, power_property(&channel_name("power", i, relays)),
+                         state_format(&format!("{{base}}/{{device}}/relay/{}/power", i), None));
+        }
+    }
+    if relays > 0 {
+        config.types.push("OnOffSwitch".to_string());
+    }
+
+    if roller {
+        let mut format = state_format("{base}/{device}/roller/0/pos", None);
+        format.command_topic = Some("{base}/{device}/roller/0/command/pos".to_string());
+        add_property(&mut config, position("position"), format);
+        add_cover_actions(&mut config, "{base}/{device}/roller/0/command", |action| action.to_string());
+        if power {
+            add_property(&mut config, power_property("power"),
+                         state_format("{base}/{device}/roller/0/power", None));
+        }
+    }
+
+    if dimmer {
+        let status = "{base}/{device}/light/0/status";
+        let mut on = on_off_format("{base}/{device}/light/0/set", status, Some("/ison"));
+        on.command_template = Some("{\"turn\":\"{{value}}\"}".to_string());
+        add_property(&mut config, on_off("on", 1, 0), on);
+        let mut level = state_format(status, Some("/brightness"));
+        level.command_topic = Some("{base}/{device}/light/0/set".to_string());
+        level.command_template = Some("{\"brightness\":{{json}}}".to_string());
+        add_property(&mut config, brightness("brightness"), level);
+        config.types.push("OnOffSwitch".to_string());
+        config.types.push("Light".to_string());
+        if power {
+            add_property(&mut config, power_property("power"),
+                         state_format("{base}/{device}/light/0/power", None));
+        }
+    }
+
+    if temperature {
+        add_property(&mut config, temperature_property("temperature"),
+                     state_format("{base}/{device}/temperature", None));
+        config.types.push("TemperatureSensor".to_string());
+    }
+    if power {
+        config.types.push("EnergyMonitor".to_string());
+    }
+    Some(config)
+}
+
+fn gen2_kind(component: &str) -> Option<(&str, usize)> {
+    let mut parts = component.splitn(2, ':');
+    let kind = parts.next()?;
+    let index = parts.next()?.parse().ok()?;
+    match kind {
+        "switch" | "cover" | "light" => Some((kind, index)),
+        _ => None,
+    }
+}
+
+fn gen2_config(device: &str, components: &BTreeSet<String>) -> DeviceConfig {
+    let mut config = DeviceConfig::new(&format!("shelly-{}", device), device);
+    config.topic = Some(device.to_string());
+    config.topics = Some(TopicScheme {
+        base: String::new(),
+        command: "{device}/rpc".to_string(),
+        state: "{device}/status/{property}".to_string(),
+        action: "{device}/rpc".to_string(),
+        action_payload: String::new(),
+        payloads: HashMap::new(),
+    });
+
+    for component in components {
+        let (kind, index) = match gen2_kind(component) {
+            Some(kind) => kind,
+            None => continue,
+        };
+        let status = format!("{{device}}/status/{}", component);
+        let name = format!("{}{}", kind, index);
+        match kind {
+            "switch" | "light" => {
+                let mut on = state_format(&status, Some("/output"));
+                on.command_topic = Some("{device}/rpc".to_string());
+                on.command_template = Some(rpc(&format!("{}.Set", capitalize(kind)),
+                                               &format!("\"id\":{},\"on\":{{{{json}}}}", index)));
+                add_property(&mut config, on_off(&name, 1, 0), on);
+                if kind == "light" {
+                    let mut level = state_format(&status, Some("/brightness"));
+                    level.command_topic = Some("{device}/rpc".to_string());
+                    level.command_template = Some(rpc("Light.Set",
+                                                      &format!("\"id\":{},\"brightness\":{{{{json}}}}", index)));
+                    add_property(&mut config, brightness(&format!("{}_brightness", name)), level);
+                    push_type(&mut config, "Light");
+                }
+                push_type(&mut config, "OnOffSwitch");
+            },
+            "cover" => {
+                let mut pos = state_format(&status, Some("/current_pos"));
+                pos.command_topic = Some("{device}/rpc".to_string());
+                pos.command_template = Some(rpc("Cover.GoToPosition",
+                                                &format!("\"id\":{},\"pos\":{{{{json}}}}", index)));
+                add_property(&mut config, position(&format!("{}_position", name)), pos);
+                add_cover_actions(&mut config, "{device}/rpc", |action| {
+                    rpc(&format!("Cover.{}", capitalize(action)), &format!("\"id\":{}", index))
+                });
+            },
+            _ => continue,
+        }
+        add_property(&mut config, power_property(&format!("{}_power", name)),
+                     state_format(&status, Some("/apower")));
+        add_property(&mut config, temperature_property(&format!("{}_temperature", name)),
+                     state_format(&status, Some("/temperature/tC")));
+        push_type(&mut config, "EnergyMonitor");
+        push_type(&mut config, "TemperatureSensor");
+    }
+    config
+}
+
+fn rpc(method: &str, params: &str) -> String {
+    format!("{{\"id\":1,\"src\":\"{}\",\"method\":\"{}\",\"params\":{{{}}}}}",
+            RPC_SOURCE, method, params)
+}
+
+fn capitalize(s: &str) -> String {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// `relay` for single-channel devices, `relay0`, `relay1`, ... otherwise.
+fn channel_name(name: &str, i: usize, count: usize) -> String {
+    if count == 1 { name.to_string() } else { format!("{}{}", name, i) }
+}
+
+fn add_cover_actions<F: Fn(&str) -> String>(config: &mut DeviceConfig, topic: &str, payload: F) {
+    for action in &["open", "close", "stop"] {
+        let mut format = PayloadFormat::default();
+        format.command_topic = Some(topic.to_string());
+        format.command_template = Some(payload(action));
+        config.payloads.insert(action.to_string(), format);
+        config.actions.push(ActionDescription {
+            title: Some(capitalize(action)),
+            ..ActionDescription::new(action)
+        });
+    }
+}
+
+fn state_format(topic: &str, path: Option<&str>) -> PayloadFormat {
+    let mut format = PayloadFormat::default();
+    format.state_topics = vec![topic.to_string()];
+    format.state_path = path.map(|p| p.to_string());
+    format
+}
+
+fn on_off_format(command: &str, state: &str, path: Option<&str>) -> PayloadFormat {
+    let mut format = state_format(state, path);
+    format.command_topic = Some(command.to_string());
+    format.value_map = vec![
+        ValueMapping { value: Value::Bool(true), payload: "on".to_string() },
+        ValueMapping { value: Value::Bool(false), payload: "off".to_string() },
+    ];
+    format
+}
+
+fn on_off(name: &str, count: usize, i: usize) -> PropertyDescription {
+    PropertyDescription {
+        at_type: Some("OnOffProperty".to_string()),
+        title: Some(if count == 1 { "On/Off".to_string() } else { format!("Channel {}", i + 1) }),
+        ..PropertyDescription::new(name, "boolean", Value::Bool(false))
+    }
+}
+
+fn brightness(name: &str) -> PropertyDescription {
+    PropertyDescription {
+        at_type: Some("BrightnessProperty".to_string()),
+        title: Some("Brightness".to_string()),
+        unit: Some("percent".to_string()),
+        minimum: Some(0.0),
+        maximum: Some(100.0),
+        ..PropertyDescription::new(name, "integer", Value::from(0))
+    }
+}
+
+fn position(name: &str) -> PropertyDescription {
+    PropertyDescription {
+        title: Some("Position".to_string()),
+        unit: Some("percent".to_string()),
+        minimum: Some(0.0),
+        maximum: Some(100.0),
+        ..PropertyDescription::new(name, "integer", Value::from(0))
+    }
+}
+
+fn power_property(name: &str) -> PropertyDescription {
+    PropertyDescription {
+        at_type: Some("InstantaneousPowerProperty".to_string()),
+        title: Some("Power".to_string()),
+        unit: Some("watt".to_string()),
+        read_only: Some(true),
+        ..PropertyDescription::new(name, "number", Value::from(0))
+    }
+}
+
+fn temperature_property(name: &str) -> PropertyDescription {
+    PropertyDescription {
+        at_type: Some("TemperatureProperty".to_string()),
+        title: Some("Temperature".to_string()),
+        unit: Some("degree celsius".to_string()),
+        read_only: Some(true),
+        ..PropertyDescription::new(name, "number", Value::from(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn added(events: Vec<DiscoveryEvent>) -> DeviceConfig {
+        match events.into_iter().next() {
+            Some(DiscoveryEvent::Added(config)) => *config,
+            _ => panic!("no device"),
+        }
+    }
+
+    fn names(config: &DeviceConfig) -> Vec<&str> {
+        config.properties.iter().map(|p| p.name.as_str()).collect()
+    }
+
+    #[test]
+    fn gen1_relays() {
+        let mut shelly = Shelly::new("shellies");
+        let announce = json!({ "id": "shellyswitch25-AABBCC", "model": "SHSW-25", "mode": "relay" });
+        let config = added(shelly.handle_publish("shellies/announce", announce.to_string().as_bytes()));
+        assert_eq!(config.id, "shelly-shellyswitch25-AABBCC");
+        assert_eq!(names(&config), vec!["relay0", "power0", "relay1", "power1", "temperature"]);
+        assert_eq!(config.types, vec!["OnOffSwitch", "TemperatureSensor", "EnergyMonitor"]);
+        let relay = &config.payloads["relay1"];
+        assert_eq!(relay.command_topic, Some("{base}/{device}/relay/1/command".to_string()));
+        assert_eq!(relay.encode(&Value::Bool(true)), b"on".to_vec());
+        assert_eq!(relay.decode(b"off"), Some(Value::Bool(false)));
+
+        // Repeated announcements change nothing.
+        assert!(shelly.handle_publish("shellies/announce", announce.to_string().as_bytes()).is_empty());
+    }
+
+    #[test]
+    fn gen1_rollers_and_dimmers() {
+        let mut shelly = Shelly::new("shellies");
+        let roller = json!({ "id": "shellyswitch25-DDEEFF", "model": "SHSW-25", "mode": "roller" });
+        let config = added(shelly.handle_publish("shellies/announce", roller.to_string().as_bytes()));
+        assert_eq!(names(&config), vec!["position", "power", "temperature"]);
+        let actions: Vec<&str> = config.actions.iter().map(|a| a.name.as_str()).collect();
+        assert_eq!(actions, vec!["open", "close", "stop"]);
+        assert_eq!(config.payloads["stop"].command_template, Some("stop".to_string()));
+
+        let dimmer = json!({ "id": "shellydimmer-112233", "model": "SHDM-1" });
+        let config = added(shelly.handle_publish("shellies/announce", dimmer.to_string().as_bytes()));
+        let on = &config.payloads["on"];
+        assert_eq!(on.encode(&Value::Bool(true)), br#"{"turn":"on"}"#.to_vec());
+        assert_eq!(on.decode(br#"{"ison":true,"brightness":40}"#), Some(Value::Bool(true)));
+        assert_eq!(config.payloads["brightness"].encode(&json!(40)), br#"{"brightness":40}"#.to_vec());
+
+        let unknown = json!({ "id": "shellyht-445566", "model": "SHHT-1" });
+        assert!(shelly.handle_publish("shellies/announce", unknown.to_string().as_bytes()).is_empty());
+    }
+
+    #[test]
+    fn gen2_components() {
+        let mut shelly = Shelly::new("shellies");
+        assert!(shelly.handle_publish("shellyplus1pm-a8032ab/status/sys", b"{}").is_empty());
+        assert!(shelly.handle_publish("sensor/status/switch:0", b"{}").is_empty());
+        let config = added(shelly.handle_publish("shellyplus1pm-a8032ab/status/switch:0", b"{}"));
+        assert_eq!(config.id, "shelly-shellyplus1pm-a8032ab");
+        assert_eq!(names(&config), vec!["switch0", "switch0_power", "switch0_temperature"]);
+        let switch = &config.payloads["switch0"];
+        let request: Value = serde_json::from_slice(&switch.encode(&Value::Bool(true))).unwrap();
+        assert_eq!(request, json!({
+            "id": 1,
+            "src": RPC_SOURCE,
+            "method": "Switch.Set",
+            "params": { "id": 0, "on": true },
+        }));
+        assert_eq!(switch.decode(br#"{"id":0,"output":true}"#), Some(Value::Bool(true)));
+        assert!(shelly.handle_publish("shellyplus1pm-a8032ab/status/switch:0", b"{}").is_empty());
+
+        let config = added(shelly.handle_publish("shellyplus1pm-a8032ab/status/cover:0", b"{}"));
+        assert_eq!(config.types, vec!["EnergyMonitor", "TemperatureSensor", "OnOffSwitch"]);
+        assert!(config.payloads.contains_key("cover0_position"));
+    }
+}
@@ -0,0 +1,699 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde_json::Value;
+
+use config::DeviceConfig;
+use discovery::{Discovery, DiscoveryEvent};
+use gateway::{Property, PropertyDescription};
+use mqtt;
+use payload::PayloadFormat;
+use protobuf::{self, Field, Reader};
+use topics::TopicScheme;
+
+const NAMESPACE: &str = "spBv1.0";
+const REBIRTH_METRIC: &str = "Node Control/Rebirth";
+/// Minimum time between rebirth requests to the same edge node.
+const REBIRTH_INTERVAL: u64 = 5;
+
+// Sparkplug B metric data types.
+const INT8: u32 = 1;
+const INT16: u32 = 2;
+const INT32: u32 = 3;
+const INT64: u32 = 4;
+const UINT8: u32 = 5;
+const UINT16: u32 = 6;
+const UINT32: u32 = 7;
+const UINT64: u32 = 8;
+const FLOAT: u32 = 9;
+const DOUBLE: u32 = 10;
+const BOOLEAN: u32 = 11;
+const STRING: u32 = 12;
+const DATETIME: u32 = 13;
+const TEXT: u32 = 14;
+const UUID: u32 = 15;
+
+/// The metric a property is written to with NCMD/DCMD messages.
+#[derive(Clone, Debug)]
+struct MetricSpec {
+    name: String,
+    datatype: u32,
+}
+
+impl MetricSpec {
+    /// A command payload setting this metric to `value`.
+    fn encode_command(&self, value: &Value) -> Vec<u8> {
+        let now = timestamp();
+        let mut metric = Vec::new();
+        protobuf::write_bytes(&mut metric, 1, self.name.as_bytes());
+        protobuf::write_uint(&mut metric, 3, now);
+        protobuf::write_uint(&mut metric, 4, self.datatype as u64);
+        let int = value.as_i64().unwrap_or_else(|| value.as_u64().unwrap_or(0) as i64);
+        match self.datatype {
+            INT8 | INT16 | INT32 | UINT8 | UINT16 | UINT32 =>
+                protobuf::write_uint(&mut metric, 10, int as u32 as u64),
+            INT64 | UINT64 | DATETIME => protobuf::write_uint(&mut metric, 11, int as u64),
+            FLOAT => protobuf::write_float(&mut metric, 12, value.as_f64().unwrap_or(0.0) as f32),
+            DOUBLE => protobuf::write_double(&mut metric, 13, value.as_f64().unwrap_or(0.0)),
+            BOOLEAN => protobuf::write_bool(&mut metric, 14, value.as_bool().unwrap_or(false)),
+            _ => {
+                let text = match *value {
+                    Value::String(ref s) => s.clone(),
+                    ref other => other.to_string(),
+                };
+                protobuf::write_bytes(&mut metric, 15, text.as_bytes());
+            },
+        }
+
+        let mut payload = Vec::new();
+        protobuf::write_uint(&mut payload, 1, now);
+        protobuf::write_bytes(&mut payload, 2, &metric);
+        payload
+    }
+}
+
+enum MetricValue {
+    Int(u64),
+    Long(u64),
+    Float(f32),
+    Double(f64),
+    Boolean(bool),
+    String(String),
+}
+
+struct Metric {
+    name: Option<String>,
+    alias: Option<u64>,
+    datatype: Option<u32>,
+    is_null: bool,
+    value: Option<MetricValue>,
+}
+
+impl Metric {
+    fn decode(buf: &[u8]) -> Result<Metric, io::Error> {
+        let mut metric = Metric {
+            name: None,
+            alias: None,
+            datatype: None,
+            is_null: false,
+            value: None,
+        };
+        let mut reader = Reader::new(buf);
+        while let Some((number, field)) = reader.next_field()? {
+            match (number, field) {
+                (1, ref field) => metric.name = field.as_string(),
+                (2, ref field) => metric.alias = field.as_u64(),
+                (4, ref field) => metric.datatype = field.as_u64().map(|t| t as u32),
+                (7, ref field) => metric.is_null = field.as_u64() == Some(1),
+                (10, Field::Varint(v)) => metric.value = Some(MetricValue::Int(v)),
+                (11, Field::Varint(v)) => metric.value = Some(MetricValue::Long(v)),
+                (12, Field::Fixed32(v)) => metric.value = Some(MetricValue::Float(f32::from_bits(v))),
+                (13, Field::Fixed64(v)) => metric.value = Some(MetricValue::Double(f64::from_bits(v))),
+                (14, Field::Varint(v)) => metric.value = Some(MetricValue::Boolean(v != 0)),
+                (15, ref field) => metric.value = field.as_string().map(MetricValue::String),
+                _ => {},
+            }
+        }
+        Ok(metric)
+    }
+
+    /// The metric's value as JSON, interpreted according to `datatype`.
+    fn to_json(&self, datatype: u32) -> Value {
+        if self.is_null {
+            return Value::Null;
+        }
+        match self.value {
+            Some(MetricValue::Int(v)) => match datatype {
+                // Signed values are stored as their two's complement.
+                INT8 => Value::from(v as u8 as i8),
+                INT16 => Value::from(v as u16 as i16),
+                INT32 => Value::from(v as u32 as i32),
+                _ => Value::from(v as u32),
+            },
+            Some(MetricValue::Long(v)) => match datatype {
+                INT64 => Value::from(v as i64),
+                _ => Value::from(v),
+            },
+            Some(MetricValue::Float(v)) => Value::from(v as f64),
+            Some(MetricValue::Double(v)) => Value::from(v),
+            Some(MetricValue::Boolean(v)) => Value::Bool(v),
+            Some(MetricValue::String(ref v)) => Value::String(v.clone()),
+            None => Value::Null,
+        }
+    }
+}
+
+struct Payload {
+    seq: Option<u64>,
+    metrics: Vec<Metric>,
+}
+
+impl Payload {
+    fn decode(buf: &[u8]) -> Result<Payload, io::Error> {
+        let mut payload = Payload {
+            seq: None,
+            metrics: Vec::new(),
+        };
+        let mut reader = Reader::new(buf);
+        while let Some((number, field)) = reader.next_field()? {
+            match number {
+                2 => if let Some(bytes) = field.as_bytes() {
+                    payload.metrics.push(Metric::decode(bytes)?);
+                },
+                3 => payload.seq = field.as_u64(),
+                _ => {},
+            }
+        }
+        Ok(payload)
+    }
+}
+
+/// A metric announced in a BIRTH certificate.
+struct BornMetric {
+    datatype: u32,
+    /// The property reporting the metric, if it has one.
+    property: Option<String>,
+}
+
+/// What an edge node announced in its last NBIRTH and the DBIRTHs since.
+struct EdgeNode {
+    seq: u64,
+    bd_seq: Option<u64>,
+    aliases: HashMap<u64, String>,
+    /// Metrics of the node (`None`) and each of its devices.
+    metrics: HashMap<Option<String>, HashMap<String, BornMetric>>,
+}
+
+/// Builds devices from the NBIRTH and DBIRTH certificates of Sparkplug B
+/// edge nodes and reports their NDATA and DDATA metrics as property values.
+///
+/// Messages are checked against each node's sequence number. Data from a
+/// node whose birth was missed, or arriving out of order, triggers a rebirth
+/// request.
+pub struct Sparkplug {
+    group: String,
+    mqtt: mqtt::MQTT,
+    nodes: HashMap<(String, String), EdgeNode>,
+    rebirths: HashMap<(String, String), Instant>,
+    /// The metric each property of a device is commanded through, by
+    /// device id.
+    commands: HashMap<String, HashMap<String, MetricSpec>>,
+}
+
+impl Sparkplug {
+    pub fn new(group: &str, mqtt: mqtt::MQTT) -> Sparkplug {
+        Sparkplug {
+            group: group.to_string(),
+            mqtt,
+            nodes: HashMap::new(),
+            rebirths: HashMap::new(),
+            commands: HashMap::new(),
+        }
+    }
+
+    fn handle_nbirth(&mut self, group: &str, node: &str, payload: Payload) -> Vec<DiscoveryEvent> {
+        let key = (group.to_string(), node.to_string());
+        self.rebirths.remove(&key);
+        let bd_seq = bd_seq(&payload.metrics);
+        let mut edge_node = EdgeNode {
+            seq: payload.seq.unwrap_or(0),
+            bd_seq,
+            aliases: HashMap::new(),
+            metrics: HashMap::new(),
+        };
+        let config = birth_config(group, node, None, &payload.metrics, &mut edge_node,
+                                  &mut self.commands);
+        self.nodes.insert(key, edge_node);
+        config.map(|config| DiscoveryEvent::Added(Box::new(config))).into_iter().collect()
+    }
+
+    fn handle_ndeath(&mut self, group: &str, node: &str, payload: Payload) -> Vec<DiscoveryEvent> {
+        let key = (group.to_string(), node.to_string());
+        let bd_seq = bd_seq(&payload.metrics);
+        let edge_node = match self.nodes.get(&key) {
+            // A will left over from an earlier session.
+            Some(edge_node) if bd_seq.is_some() && edge_node.bd_seq.is_some() &&
+                bd_seq != edge_node.bd_seq => return Vec::new(),
+            Some(_) => self.nodes.remove(&key).unwrap(),
+            None => return Vec::new(),
+        };
+        edge_node.metrics.keys()
+            .map(|device| offline(group, node, device.as_ref().map(|d| d.as_str())))
+            .collect()
+    }
+
+    /// Advances the node's sequence number, returning false if the message
+    /// is not the one expected next.
+    fn check_seq(&mut self, group: &str, node: &str, seq: Option<u64>) -> bool {
+        match self.nodes.get_mut(&(group.to_string(), node.to_string())) {
+            Some(edge_node) => {
+                let expected = (edge_node.seq + 1) % 256;
+                if seq != Some(expected) {
+                    warn!("{}/{} sent seq {:?}, expected {}", group, node, seq, expected);
+                    return false;
+                }
+                edge_node.seq = expected;
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn request_rebirth(&mut self, group: &str, node: &str) {
+        let key = (group.to_string(), node.to_string());
+        if let Some(requested) = self.rebirths.get(&key) {
+            if requested.elapsed() < Duration::from_secs(REBIRTH_INTERVAL) {
+                return;
+            }
+        }
+        info!("requesting rebirth of {}/{}", group, node);
+        let rebirth = MetricSpec {
+            name: REBIRTH_METRIC.to_string(),
+            datatype: BOOLEAN,
+        };
+        let topic = format!("{}/{}/NCMD/{}", NAMESPACE, group, node);
+        if let Err(e) = self.mqtt.publish(&topic, rebirth.encode_command(&Value::Bool(true))) {
+            warn!(topic = topic.as_str(); "rebirth request failed: {:?}", e);
+        }
+        self.rebirths.insert(key, Instant::now());
+    }
+
+    /// Resolves DATA metrics to property values, or `None` if the message
+    /// refers to metrics the node has not announced.
+    fn values(&self, group: &str, node: &str, device: Option<&str>, metrics: &[Metric])
+              -> Option<Vec<Property>> {
+        let edge_node = self.nodes.get(&(group.to_string(), node.to_string()))?;
+        let born = edge_node.metrics.get(&device.map(|d| d.to_string()))?;
+        let mut properties = Vec::new();
+        for metric in metrics {
+            let name = match (metric.name.as_ref(), metric.alias) {
+                (Some(name), _) => name,
+                (None, Some(alias)) => edge_node.aliases.get(&alias)?,
+                (None, None) => continue,
+            };
+            if is_control(name) {
+                continue;
+            }
+            let announced = born.get(name)?;
+            let property = match announced.property {
+                Some(ref property) => property,
+                None => continue,
+            };
+            properties.push(Property {
+                name: property.clone(),
+                value: metric.to_json(metric.datatype.unwrap_or(announced.datatype)),
+            });
+        }
+        Some(properties)
+    }
+}
+
+impl Discovery for Sparkplug {
+    fn topics(&self) -> Vec<String> {
+        vec![format!("{}/{}/#", NAMESPACE, self.group)]
+    }
+
+    fn handle_publish(&mut self, topic: &str, payload: &[u8]) -> Vec<DiscoveryEvent> {
+        let parts: Vec<&str> = topic.split('/').collect();
+        let (group, kind, node, device) = match *parts.as_slice() {
+            [NAMESPACE, group, kind, node] => (group, kind, node, None),
+            [NAMESPACE, group, kind, node, device] => (group, kind, node, Some(device)),
+            _ => return Vec::new(),
+        };
+        if kind.ends_with("CMD") {
+            return Vec::new();
+        }
+        let payload = match Payload::decode(payload) {
+            Ok(payload) => payload,
+            Err(e) => {
+                warn!(topic = topic; "unreadable payload: {}", e);
+                return Vec::new();
+            }
+        };
+
+        match kind {
+            "NBIRTH" => return self.handle_nbirth(group, node, payload),
+            "NDEATH" => return self.handle_ndeath(group, node, payload),
+            "DBIRTH" | "DDEATH" | "NDATA" | "DDATA" => {},
+            _ => return Vec::new(),
+        }
+        if !self.check_seq(group, node, payload.seq) {
+            self.request_rebirth(group, node);
+            return Vec::new();
+        }
+        match kind {
+            "DBIRTH" => {
+                let edge_node = self.nodes.get_mut(&(group.to_string(), node.to_string())).unwrap();
+                birth_config(group, node, device, &payload.metrics, edge_node, &mut self.commands)
+                    .map(|config| DiscoveryEvent::Added(Box::new(config)))
+                    .into_iter()
+                    .collect()
+            },
+            "DDEATH" => vec![offline(group, node, device)],
+            _ => match self.values(group, node, device, &payload.metrics) {
+                Some(properties) => vec![DiscoveryEvent::Updated(thing_id(group, node, device),
+                                                                 properties)],
+                None => {
+                    self.request_rebirth(group, node);
+                    Vec::new()
+                }
+            },
+        }
+    }
+
+    fn encode_command(&self, config_id: &str, property: &str, value: &Value)
+                      -> Result<Option<Vec<u8>>, io::Error> {
+        if !config_id.starts_with("sparkplug-") {
+            return Ok(None);
+        }
+        // Devices restored from the registry are commanded once their node
+        // has been born again.
+        match self.commands.get(config_id).and_then(|specs| specs.get(property)) {
+            Some(metric) => Ok(Some(metric.encode_command(value))),
+            None => Err(io::Error::new(io::ErrorKind::NotConnected,
+                                       format!("waiting for a birth certificate for {}", config_id))),
+        }
+    }
+}
+
+/// Records the metrics of a BIRTH certificate and builds the matching
+/// device, unless the birth carries nothing but control metrics.
+fn birth_config(group: &str, node: &str, device: Option<&str>, metrics: &[Metric],
+                edge_node: &mut EdgeNode, commands: &mut HashMap<String, HashMap<String, MetricSpec>>)
+                -> Option<DeviceConfig> {
+    let mut born = HashMap::new();
+    let mut specs = HashMap::new();
+    let mut taken: HashSet<String> = HashSet::new();
+    taken.insert("available".to_string());
+    let mut config = DeviceConfig::new(&thing_id(group, node, device), device.unwrap_or(node));
+    let (command, data) = match device {
+        Some(device) => (format!("{}/{}/DCMD/{}/{}", NAMESPACE, group, node, device),
+                         format!("{}/{}/DDATA/{}/{}", NAMESPACE, group, node, device)),
+        None => (format!("{}/{}/NCMD/{}", NAMESPACE, group, node),
+                 format!("{}/{}/NDATA/{}", NAMESPACE, group, node)),
+    };
+    config.topics = Some(TopicScheme {
+        base: String::new(),
+        command: command.clone(),
+        state: data,
+        action: command.clone(),
+        action_payload: String::new(),
+        payloads: HashMap::new(),
+    });
+
+    for metric in metrics {
+        let (name, datatype) = match (metric.name.as_ref(), metric.datatype) {
+            (Some(name), Some(datatype)) => (name, datatype),
+            _ => continue,
+        };
+        if let Some(alias) = metric.alias {
+            edge_node.aliases.insert(alias, name.clone());
+        }
+        let typ = match datatype {
+            _ if is_control(name) => None,
+            INT8 | INT16 | INT32 | INT64 | UINT8 | UINT16 | UINT32 | UINT64 | DATETIME => Some("integer"),
+            FLOAT | DOUBLE => Some("number"),
+            BOOLEAN => Some("boolean"),
+            STRING | TEXT | UUID => Some("string"),
+            _ => None,
+        };
+        let property = typ.map(|_| unique_name(&property_name(name), &mut taken));
+        born.insert(name.clone(), BornMetric {
+            datatype,
+            property: property.clone(),
+        });
+        let (typ, property) = match (typ, property) {
+            (Some(typ), Some(property)) => (typ, property),
+            _ => continue,
+        };
+        // Values arrive in DATA messages and commands are encoded by
+        // `encode_command`, so there is no state topic to follow.
+        let mut format = PayloadFormat::default();
+        format.write_only = true;
+        config.payloads.insert(property.clone(), format);
+        config.properties.push(PropertyDescription {
+            title: Some(name.clone()),
+            ..PropertyDescription::new(&property, typ, metric.to_json(datatype))
+        });
+        specs.insert(property, MetricSpec {
+            name: name.clone(),
+            datatype,
+        });
+    }
+    edge_node.metrics.insert(device.map(|d| d.to_string()), born);
+    if config.properties.is_empty() {
+        return None;
+    }
+    commands.insert(config.id.clone(), specs);
+
+    config.properties.push(PropertyDescription {
+        title: Some("Available".to_string()),
+        read_only: Some(true),
+        ..PropertyDescription::new("available", "boolean", Value::Bool(true))
+    });
+    Some(config)
+}
+
+/// The birth/death sequence number pairing an NDEATH with its NBIRTH.
+fn bd_seq(metrics: &[Metric]) -> Option<u64> {
+    metrics.iter()
+        .find(|m| m.name.as_ref().is_some_and(|n| n == "bdSeq"))
+        .and_then(|m| match m.value {
+            Some(MetricValue::Long(v)) | Some(MetricValue::Int(v)) => Some(v),
+            _ => None,
+        })
+}
+
+fn offline(group: &str, node: &str, device: Option<&str>) -> DiscoveryEvent {
+    DiscoveryEvent::Updated(thing_id(group, node, device), vec![Property {
+        name: "available".to_string(),
+        value: Value::Bool(false),
+    }])
+}
+
+fn thing_id(group: &str, node: &str, device: Option<&str>) -> String {
+    match device {
+        Some(device) => format!("sparkplug-{}-{}-{}", group, node, device),
+        None => format!("sparkplug-{}-{}", group, node),
+    }
+}
+
+/// Metric names are paths like `Inputs/Temperature`, which cannot be used
+/// as property names as they are.
+fn property_name(metric: &str) -> String {
+    metric.chars()
+        .map(|c| if c.is_alphanumeric() || c == '_' || c == '-' { c } else { '_' })
+        .collect()
+}
+
+/// `name`, or `name` with the first free suffix `_2`, `_3`, ... if another
+/// metric of the device already maps to it.
+fn unique_name(name: &str, taken: &mut HashSet<String>) -> String {
+    let mut unique = name.to_string();
+    let mut n = 2;
+    while !taken.insert(unique.clone()) {
+        unique = format!("{}_{}", name, n);
+        n += 1;
+    }
+    unique
+}
+
+fn is_control(metric: &str) -> bool {
+    metric == "bdSeq" || metric.starts_with("Node Control/") ||
+        metric.starts_with("Device Control/") || metric.starts_with("Properties/")
+}
+
+fn timestamp() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    now.as_secs() * 1000 + now.subsec_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metrics::Metrics;
+    use queue::OfflineQueue;
+
+    /// A client for a broker that is not there, so that publishes stay in
+    /// its queue.
+    fn offline_mqtt(metrics: &Metrics) -> mqtt::MQTT {
+        let options = mqtt::Options {
+            server: "127.0.0.1:1".to_string(),
+            username: None,
+            password: None,
+            tls: None,
+            client_id: "sparkplug-test".to_string(),
+            clean_session: true,
+            receive_maximum: 10,
+        };
+        let queue = OfflineQueue::new(60, 10, None).unwrap();
+        mqtt::MQTT::connect(options, queue, metrics.connection("sparkplug")).0
+    }
+
+    fn metric(name: Option<&str>, alias: Option<u64>, datatype: Option<u32>, value: u64) -> Vec<u8> {
+        let mut metric = Vec::new();
+        if let Some(name) = name {
+            protobuf::write_bytes(&mut metric, 1, name.as_bytes());
+        }
+        if let Some(alias) = alias {
+            protobuf::write_uint(&mut metric, 2, alias);
+        }
+        if let Some(datatype) = datatype {
+            protobuf::write_uint(&mut metric, 4, datatype as u64);
+        }
+        match datatype {
+            Some(BOOLEAN) => protobuf::write_bool(&mut metric, 14, value != 0),
+            Some(DOUBLE) => protobuf::write_double(&mut metric, 13, value as f64 / 10.0),
+            _ => protobuf::write_uint(&mut metric, 11, value),
+        }
+        metric
+    }
+
+    fn payload(seq: u64, metrics: &[Vec<u8>]) -> Vec<u8> {
+        let mut payload = Vec::new();
+        for metric in metrics {
+            protobuf::write_bytes(&mut payload, 2, metric);
+        }
+        protobuf::write_uint(&mut payload, 3, seq);
+        payload
+    }
+
+    fn nbirth(seq: u64) -> Vec<u8> {
+        payload(seq, &[
+            metric(Some("bdSeq"), None, Some(INT64), 3),
+            metric(Some("Node Control/Rebirth"), None, Some(BOOLEAN), 0),
+            metric(Some("Inputs/Temperature"), Some(1), Some(DOUBLE), 215),
+            metric(Some("Outputs/Pump"), Some(2), Some(BOOLEAN), 0),
+        ])
+    }
+
+    fn queued(metrics: &Metrics) -> usize {
+        metrics.connections()["sparkplug"].queue_depth
+    }
+
+    #[test]
+    fn births_become_devices() {
+        let metrics = Metrics::new();
+        let mut sparkplug = Sparkplug::new("Plant", offline_mqtt(&metrics));
+        let config = match sparkplug.handle_publish("spBv1.0/Plant/NBIRTH/edge1", &nbirth(0)).pop() {
+            Some(DiscoveryEvent::Added(config)) => config,
+            _ => panic!("no device"),
+        };
+        assert_eq!(config.id, "sparkplug-Plant-edge1");
+        let names: Vec<&str> = config.properties.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, vec!["Inputs_Temperature", "Outputs_Pump", "available"]);
+        assert_eq!(config.properties[0].typ, "number");
+        assert_eq!(config.properties[0].value, json!(21.5));
+        assert_eq!(config.topics.unwrap().command, "spBv1.0/Plant/NCMD/edge1");
+
+        // Commands set the metric by its full name.
+        assert!(config.payloads["Outputs_Pump"].write_only);
+        let command = sparkplug.encode_command(&config.id, "Outputs_Pump", &Value::Bool(true))
+            .unwrap().unwrap();
+        let sent = Payload::decode(&command).unwrap();
+        assert_eq!(sent.metrics[0].name, Some("Outputs/Pump".to_string()));
+        assert_eq!(sent.metrics[0].to_json(BOOLEAN), Value::Bool(true));
+    }
+
+    #[test]
+    fn data_updates_properties() {
+        let metrics = Metrics::new();
+        let mut sparkplug = Sparkplug::new("Plant", offline_mqtt(&metrics));
+        sparkplug.handle_publish("spBv1.0/Plant/NBIRTH/edge1", &nbirth(0));
+        // Metrics in DATA messages may be referred to by alias alone.
+        let mut temperature = Vec::new();
+        protobuf::write_uint(&mut temperature, 2, 1);
+        protobuf::write_double(&mut temperature, 13, 22.5);
+        let mut pump = Vec::new();
+        protobuf::write_uint(&mut pump, 2, 2);
+        protobuf::write_bool(&mut pump, 14, true);
+        let data = payload(1, &[temperature, pump]);
+        match sparkplug.handle_publish("spBv1.0/Plant/NDATA/edge1", &data).as_slice() {
+            &[DiscoveryEvent::Updated(ref id, ref properties)] => {
+                assert_eq!(id, "sparkplug-Plant-edge1");
+                let values: Vec<(&str, &Value)> = properties.iter()
+                    .map(|p| (p.name.as_str(), &p.value))
+                    .collect();
+                assert_eq!(values, vec![("Inputs_Temperature", &json!(22.5)),
+                                        ("Outputs_Pump", &Value::Bool(true))]);
+            },
+            _ => panic!("no update"),
+        }
+        assert_eq!(queued(&metrics), 0);
+
+        match sparkplug.handle_publish("spBv1.0/Plant/NDEATH/edge1",
+                                       &payload(0, &[metric(Some("bdSeq"), None, Some(INT64), 3)]))
+            .as_slice() {
+            &[DiscoveryEvent::Updated(_, ref properties)] => {
+                assert_eq!(properties[0].name, "available");
+                assert_eq!(properties[0].value, Value::Bool(false));
+            },
+            _ => panic!("node not marked offline"),
+        }
+    }
+
+    #[test]
+    fn gaps_trigger_rebirth() {
+        let metrics = Metrics::new();
+        let mut sparkplug = Sparkplug::new("Plant", offline_mqtt(&metrics));
+        // Data from a node whose birth was missed.
+        assert!(sparkplug.handle_publish("spBv1.0/Plant/NDATA/edge1", &payload(5, &[])).is_empty());
+        assert_eq!(queued(&metrics), 1);
+
+        sparkplug.handle_publish("spBv1.0/Plant/NBIRTH/edge1", &nbirth(0));
+        let skipped = payload(2, &[metric(Some("Outputs/Pump"), None, Some(BOOLEAN), 1)]);
+        assert!(sparkplug.handle_publish("spBv1.0/Plant/NDATA/edge1", &skipped).is_empty());
+        assert_eq!(queued(&metrics), 2);
+        // Requests are not repeated while the node has a chance to answer.
+        assert!(sparkplug.handle_publish("spBv1.0/Plant/NDATA/edge1", &skipped).is_empty());
+        assert_eq!(queued(&metrics), 2);
+        assert_eq!(sparkplug.handle_publish("spBv1.0/Plant/NDATA/edge1", &payload(1, &[])).len(), 1);
+    }
+
+    #[test]
+    fn commands_wait_for_births() {
+        let metrics = Metrics::new();
+        let sparkplug = Sparkplug::new("Plant", offline_mqtt(&metrics));
+        let err = sparkplug.encode_command("sparkplug-Plant-edge1", "Outputs_Pump", &Value::Bool(true))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+        assert!(sparkplug.encode_command("lamp", "on", &Value::Bool(true)).unwrap().is_none());
+    }
+
+    #[test]
+    fn property_names() {
+        assert_eq!(property_name("Inputs/Temperature 1"), "Inputs_Temperature_1");
+        assert_eq!(property_name("pump-2_speed"), "pump-2_speed");
+    }
+
+    #[test]
+    fn colliding_names_get_suffixes() {
+        let metrics = Metrics::new();
+        let mut sparkplug = Sparkplug::new("Plant", offline_mqtt(&metrics));
+        let birth = payload(0, &[
+            metric(Some("Pump/Speed"), None, Some(INT64), 1),
+            metric(Some("Pump Speed"), None, Some(INT64), 2),
+            metric(Some("Pump_Speed"), None, Some(INT64), 3),
+            metric(Some("available"), None, Some(BOOLEAN), 1),
+        ]);
+        let config = match sparkplug.handle_publish("spBv1.0/Plant/NBIRTH/edge1", &birth).pop() {
+            Some(DiscoveryEvent::Added(config)) => config,
+            _ => panic!("no device"),
+        };
+        let names: Vec<&str> = config.properties.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, vec!["Pump_Speed", "Pump_Speed_2", "Pump_Speed_3", "available_2", "available"]);
+
+        let data = payload(1, &[metric(Some("Pump Speed"), None, Some(INT64), 5)]);
+        match sparkplug.handle_publish("spBv1.0/Plant/NDATA/edge1", &data).as_slice() {
+            &[DiscoveryEvent::Updated(_, ref properties)] => {
+                assert_eq!(properties[0].name, "Pump_Speed_2");
+                assert_eq!(properties[0].value, json!(5));
+            },
+            _ => panic!("no update"),
+        }
+        let command = sparkplug.encode_command(&config.id, "Pump_Speed_3", &json!(7)).unwrap().unwrap();
+        let sent = Payload::decode(&command).unwrap();
+        assert_eq!(sent.metrics[0].name, Some("Pump_Speed".to_string()));
+    }
+}
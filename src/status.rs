@@ -0,0 +1,102 @@
+use std::time::{Duration, Instant};
+
+use serde_json::Value;
+
+use gateway::{Property, PropertyDescription};
+use metrics::{self, ConnectionStats, GatewayStats};
+
+pub const STATUS_DEVICE_NAME: &str = "MQTT Adapter Status";
+
+/// Seconds between updates of the status device.
+const INTERVAL: u64 = 5;
+
+/// Name, type, title and unit of each property, in the order of
+/// `Status::values`.
+const PROPERTIES: &[(&str, &str, &str, Option<&str>)] = &[
+    ("connected", "boolean", "Broker connected", None),
+    ("reconnects", "integer", "Reconnections", None),
+    ("messagesIn", "number", "Messages in", Some("messages/s")),
+    ("messagesOut", "number", "Messages out", Some("messages/s")),
+    ("publishLatency", "number", "Publish latency", Some("millisecond")),
+    ("queueDepth", "integer", "Queued publishes", None),
+    ("gatewayLatency", "number", "Gateway latency", Some("millisecond")),
+    ("lastError", "string", "Last error", None),
+];
+
+/// Keeps a read-only device up to date with the health of a broker
+/// connection and of the link with the gateway.
+pub struct Status {
+    connection: metrics::Connection,
+    updated: Instant,
+    /// Messages in and out at the last update, to derive rates from.
+    counts: (u64, u64),
+}
+
+impl Status {
+    pub fn new(connection: metrics::Connection) -> Status {
+        let stats = connection.stats();
+        Status {
+            connection,
+            updated: Instant::now(),
+            counts: (stats.messages_in, stats.messages_out),
+        }
+    }
+
+    /// Properties of the status device, with their current values.
+    pub fn properties(&self) -> Vec<PropertyDescription> {
+        let values = self.values(&self.connection.stats(), &self.connection.metrics().gateway(), 0.0);
+        PROPERTIES.iter().zip(values).map(|(&(name, typ, title, unit), value)| {
+            let mut descr = PropertyDescription::new(name, typ, value);
+            descr.title = Some(title.to_string());
+            descr.unit = unit.map(|unit| unit.to_string());
+            descr.read_only = Some(true);
+            descr
+        }).collect()
+    }
+
+    /// The current values of the properties, once every `INTERVAL`.
+    pub fn poll(&mut self) -> Option<Vec<Property>> {
+        let elapsed = self.updated.elapsed();
+        if elapsed < Duration::from_secs(INTERVAL) {
+            return None;
+        }
+        let stats = self.connection.stats();
+        let values = self.values(&stats, &self.connection.metrics().gateway(), seconds(elapsed));
+        self.updated = Instant::now();
+        self.counts = (stats.messages_in, stats.messages_out);
+        Some(PROPERTIES.iter().zip(values).map(|(&(name, ..), value)| Property {
+            name: name.to_string(),
+            value,
+        }).collect())
+    }
+
+    fn values(&self, stats: &ConnectionStats, gateway: &GatewayStats,
+              elapsed: f64) -> Vec<Value> {
+        let rate = |count: u64, last: u64| match elapsed > 0.0 {
+            true => Value::from(round((count - last) as f64 / elapsed)),
+            false => Value::from(0.0),
+        };
+        let millis = |duration: Option<Duration>| {
+            Value::from(round(duration.map_or(0.0, seconds) * 1000.0))
+        };
+        vec![
+            Value::Bool(stats.connected),
+            Value::from(stats.reconnects),
+            rate(stats.messages_in, self.counts.0),
+            rate(stats.messages_out, self.counts.1),
+            millis(stats.publish_latency),
+            Value::from(stats.queue_depth),
+            millis(gateway.latency),
+            Value::from(stats.last_error.clone().unwrap_or_default()),
+        ]
+    }
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9
+}
+
+/// Rounds to two decimals, which is all the gateway needs to show.
+fn round(n: f64) -> f64 {
+    (n * 100.0).round() / 100.0
+}
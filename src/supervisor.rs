@@ -0,0 +1,82 @@
+use std::any::Any;
+use std::cmp;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Longest wait before a failed worker is started again, in seconds.
+const MAX_RESTART_DELAY: u64 = 60;
+
+/// Runs `work` until it returns `Ok`, starting it again whenever it returns
+/// an error or panics. Restarts are delayed, increasingly so while the
+/// worker keeps failing soon after starting.
+///
+/// A worker that panicked may have left state it shares with other threads
+/// half updated, or its locks poisoned. Those keep failing it, and it keeps
+/// being restarted, more and more slowly, with each failure logged.
+pub fn supervise<F>(name: &str, mut work: F)
+    where F: FnMut() -> Result<(), io::Error> {
+    let mut delay = 1;
+    loop {
+        let started = Instant::now();
+        match guard(&mut work) {
+            Ok(()) => return,
+            Err(e) => error!("{} failed: {}", name, e),
+        }
+        if started.elapsed() > Duration::from_secs(MAX_RESTART_DELAY) {
+            delay = 1;
+        }
+        warn!("restarting {} in {}s", name, delay);
+        thread::sleep(Duration::from_secs(delay));
+        delay = cmp::min(delay * 2, MAX_RESTART_DELAY);
+    }
+}
+
+/// Runs `work` under `supervise` on a thread of its own.
+pub fn spawn<F>(name: &str, work: F) -> JoinHandle<()>
+    where F: FnMut() -> Result<(), io::Error> + Send + 'static {
+    let name = name.to_string();
+    thread::spawn(move || supervise(&name, work))
+}
+
+/// Runs `work`, turning a panic into an error.
+fn guard<F>(work: F) -> Result<(), io::Error>
+    where F: FnOnce() -> Result<(), io::Error> {
+    match panic::catch_unwind(AssertUnwindSafe(work)) {
+        Ok(result) => result,
+        Err(panic) => Err(io::Error::other(format!("panicked: {}", panic_message(&panic)))),
+    }
+}
+
+fn panic_message(panic: &Box<dyn Any + Send>) -> &str {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => panic.downcast_ref::<String>().map_or("unknown cause", |message| message.as_str()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restarts_panicked_workers() {
+        let mut runs = 0;
+        supervise("test worker", || {
+            runs += 1;
+            if runs == 1 {
+                panic!("first run");
+            }
+            Ok(())
+        });
+        assert_eq!(runs, 2);
+    }
+
+    #[test]
+    fn panics_become_errors() {
+        let err = guard(|| panic!("boom")).unwrap_err();
+        assert_eq!(err.to_string(), "panicked: boom");
+        assert!(guard(|| Ok(())).is_ok());
+    }
+}
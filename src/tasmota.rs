@@ -0,0 +1,383 @@
+use std::collections::HashMap;
+
+use serde_json::{self, Value};
+
+use config::DeviceConfig;
+use discovery::{add_property, push_type, Discovery, DiscoveryEvent};
+use gateway::PropertyDescription;
+use payload::{Conversion, PayloadFormat, ValueMapping};
+use topics::TopicScheme;
+
+// Values of the `rl` (relay type) array.
+const RELAY_POWER: u64 = 1;
+const RELAY_LIGHT: u64 = 2;
+
+// Values of `lt_st` (light subtype).
+const LIGHT_DIMMER: u64 = 1;
+const LIGHT_CT: u64 = 2;
+const LIGHT_RGB: u64 = 3;
+const LIGHT_RGBCW: u64 = 5;
+
+/// What has been announced so far for one device.
+#[derive(Default)]
+struct Announcement {
+    config: Option<Value>,
+    sensors: Option<Value>,
+}
+
+/// Builds devices from Tasmota's native discovery messages on
+/// `<prefix>/<mac>/config` and `<prefix>/<mac>/sensors`.
+pub struct Tasmota {
+    prefix: String,
+    devices: HashMap<String, Announcement>,
+}
+
+impl Tasmota {
+    pub fn new(prefix: &str) -> Tasmota {
+        Tasmota {
+            prefix: prefix.to_string(),
+            devices: HashMap::new(),
+        }
+    }
+}
+
+impl Discovery for Tasmota {
+    fn topics(&self) -> Vec<String> {
+        vec![
+            format!("{}/+/config", self.prefix),
+            format!("{}/+/sensors", self.prefix),
+        ]
+    }
+
+    fn handle_publish(&mut self, topic: &str, payload: &[u8]) -> Vec<DiscoveryEvent> {
+        if !topic.starts_with(&self.prefix) {
+            return Vec::new();
+        }
+        let parts: Vec<&str> = topic[self.prefix.len()..].split('/').collect();
+        let (mac, kind) = match parts.as_slice() {
+            &["", mac, kind] => (mac.to_string(), kind),
+            _ => return Vec::new(),
+        };
+        let id = format!("tasmota-{}", mac);
+
+        // Tasmota clears its retained config when discovery is turned off.
+        if payload.is_empty() {
+            if kind == "config" && self.devices.remove(&mac).is_some() {
+                return vec![DiscoveryEvent::Removed(id)];
+            }
+            return Vec::new();
+        }
+
+        let message: Value = match serde_json::from_slice(payload) {
+            Ok(message) => message,
+            Err(e) => {
+                warn!(topic = topic; "unreadable payload: {}", e);
+                return Vec::new();
+            }
+        };
+        let announcement = self.devices.entry(mac).or_default();
+        let changed = match kind {
+            "config" => replace(&mut announcement.config, message),
+            "sensors" => replace(&mut announcement.sensors, message),
+            _ => false,
+        };
+        if !changed {
+            return Vec::new();
+        }
+        match announcement.config {
+            Some(ref config) => match device_config(&id, config, announcement.sensors.as_ref()) {
+                Some(config) => vec![DiscoveryEvent::Added(Box::new(config))],
+                None => Vec::new(),
+            },
+            None => Vec::new(),
+        }
+    }
+}
+
+fn replace(slot: &mut Option<Value>, value: Value) -> bool {
+    if slot.as_ref() == Some(&value) {
+        return false;
+    }
+    *slot = Some(value);
+    true
+}
+
+fn device_config(id: &str, config: &Value, sensors: Option<&Value>) -> Option<DeviceConfig> {
+    let topic = config.get("t")?.as_str()?;
+    let name = config.get("dn").or_else(|| config.get("hn"))
+        .and_then(Value::as_str)
+        .unwrap_or(topic);
+    let full_topic = config.get("ft").and_then(Value::as_str).unwrap_or("%prefix%/%topic%/");
+    let prefixes: Vec<&str> = config.get("tp").and_then(Value::as_array)
+        .map(|tp| tp.iter().filter_map(Value::as_str).collect())
+        .unwrap_or_default();
+    let prefix = |i: usize, default: &str| -> String {
+        let mut topic = full_topic
+            .replace("%prefix%", prefixes.get(i).cloned().unwrap_or(default))
+            .replace("%topic%", "{device}");
+        if !topic.ends_with('/') {
+            topic.push('/');
+        }
+        topic
+    };
+    let (cmnd, stat, tele) = (prefix(0, "cmnd"), prefix(1, "stat"), prefix(2, "tele"));
+
+    let mut device = DeviceConfig::new(id, name);
+    device.topic = Some(topic.to_string());
+    device.topics = Some(TopicScheme {
+        base: String::new(),
+        command: format!("{}{{property}}", cmnd),
+        state: format!("{}RESULT", stat),
+        action: format!("{}{{action}}", cmnd),
+        action_payload: "1".to_string(),
+        payloads: HashMap::new(),
+    });
+    let state_topics = vec![format!("{}RESULT", stat), format!("{}STATE", tele)];
+
+    let states: Vec<String> = config.get("state").and_then(Value::as_array)
+        .map(|s| s.iter().filter_map(Value::as_str).map(|s| s.to_string()).collect())
+        .unwrap_or_default();
+    let off = states.first().cloned().unwrap_or_else(|| "OFF".to_string());
+    let on = states.get(1).cloned().unwrap_or_else(|| "ON".to_string());
+
+    let relays: Vec<u64> = config.get("rl").and_then(Value::as_array)
+        .map(|rl| rl.iter().map(|r| r.as_u64().unwrap_or(0)).collect())
+        .unwrap_or_default();
+    let relay_count = relays.iter().filter(|&&r| r == RELAY_POWER || r == RELAY_LIGHT).count();
+    let mut is_light = false;
+    for (i, &relay) in relays.iter().enumerate() {
+        if relay != RELAY_POWER && relay != RELAY_LIGHT {
+            continue;
+        }
+        is_light = is_light || relay == RELAY_LIGHT;
+        let property = if relay_count == 1 { "POWER".to_string() } else { format!("POWER{}", i + 1) };
+        add_property(&mut device, PropertyDescription {
+            at_type: Some("OnOffProperty".to_string()),
+            title: Some(if relay_count == 1 { "On/Off".to_string() } else { format!("Relay {}", i + 1) }),
+            ..PropertyDescription::new(&property, "boolean", Value::Bool(false))
+        }, {
+            let mut format = json_field(&property, &state_topics);
+            format.value_map = vec![
+                ValueMapping { value: Value::Bool(true), payload: on.clone() },
+                ValueMapping { value: Value::Bool(false), payload: off.clone() },
+            ];
+            format
+        });
+    }
+    if relay_count > 0 {
+        device.types.push("OnOffSwitch".to_string());
+    }
+
+    let light = config.get("lt_st").and_then(Value::as_u64).unwrap_or(0);
+    if light >= LIGHT_DIMMER {
+        is_light = true;
+        add_property(&mut device, PropertyDescription {
+            at_type: Some("BrightnessProperty".to_string()),
+            title: Some("Brightness".to_string()),
+            unit: Some("percent".to_string()),
+            minimum: Some(0.0),
+            maximum: Some(100.0),
+            ..PropertyDescription::new("Dimmer", "integer", Value::from(0))
+        }, json_field("Dimmer", &state_topics));
+    }
+    if light == LIGHT_CT || light == LIGHT_RGBCW {
+        // Tasmota takes color temperatures in mireds, from 153 to 500, Web
+        // Things in kelvin.
+        let mut format = json_field("CT", &state_topics);
+        format.conversion = Some(Conversion::Mired);
+        add_property(&mut device, PropertyDescription {
+            at_type: Some("ColorTemperatureProperty".to_string()),
+            title: Some("Color Temperature".to_string()),
+            unit: Some("kelvin".to_string()),
+            minimum: Some(2000.0),
+            maximum: Some(6536.0),
+            ..PropertyDescription::new("CT", "integer", Value::from(2700))
+        }, format);
+        push_type(&mut device, "ColorControl");
+    }
+    if light >= LIGHT_RGB {
+        // Colors are reported as `RRGGBB` followed by any white channels.
+        add_property(&mut device, PropertyDescription {
+            at_type: Some("ColorProperty".to_string()),
+            title: Some("Color".to_string()),
+            ..PropertyDescription::new("Color", "string", Value::String("#000000".to_string()))
+        }, {
+            let mut format = PayloadFormat::default();
+            format.command_template = Some("{{value}}".to_string());
+            format.state_pattern = Some("\"Color\":\"([0-9A-Fa-f]{6})".to_string());
+            format.state_template = Some("#{{value}}".to_string());
+            format.state_topics = state_topics.clone();
+            format
+        });
+        push_type(&mut device, "ColorControl");
+    }
+    if is_light {
+        device.types.push("Light".to_string());
+    }
+
+    if let Some(readings) = sensors.and_then(|s| s.get("sn")).and_then(Value::as_object) {
+        let temp_unit = match readings.get("TempUnit").and_then(Value::as_str) {
+            Some("F") => "degree fahrenheit",
+            _ => "degree celsius",
+        };
+        for (sensor, fields) in readings {
+            let fields = match fields.as_object() {
+                Some(fields) => fields,
+                None => continue,
+            };
+            for (field, reading) in fields {
+                if !reading.is_number() {
+                    continue;
+                }
+                let (at_type, unit, capability) = sensor_semantics(field, temp_unit);
+                if let Some(capability) = capability {
+                    push_type(&mut device, capability);
+                }
+                let property = format!("{}_{}", sensor, field);
+                add_property(&mut device, PropertyDescription {
+                    at_type: at_type.map(|t| t.to_string()),
+                    title: Some(format!("{} {}", sensor, field)),
+                    unit: unit.map(|u| u.to_string()),
+                    read_only: Some(true),
+                    ..PropertyDescription::new(&property, "number", reading.clone())
+                }, {
+                    let mut format = PayloadFormat::default();
+                    format.state_path = Some(format!("/{}/{}", sensor, field));
+                    format.state_topics = vec![format!("{}SENSOR", tele)];
+                    format
+                });
+            }
+        }
+    }
+
+    Some(device)
+}
+
+/// A format reading `field` from the JSON state published on
+/// `state_topics` and sending the bare value to `cmnd/<topic>/<field>`.
+fn json_field(field: &str, state_topics: &[String]) -> PayloadFormat {
+    let mut format = PayloadFormat::default();
+    format.state_path = Some(format!("/{}", field));
+    format.state_topics = state_topics.to_vec();
+    format
+}
+
+fn sensor_semantics(field: &str, temp_unit: &'static str)
+                    -> (Option<&'static str>, Option<&'static str>, Option<&'static str>) {
+    match field {
+        "Temperature" => (Some("TemperatureProperty"), Some(temp_unit), Some("TemperatureSensor")),
+        "Humidity" => (Some("HumidityProperty"), Some("percent"), Some("HumiditySensor")),
+        "Pressure" => (None, Some("hectopascal"), None),
+        "Power" => (Some("InstantaneousPowerProperty"), Some("watt"), Some("EnergyMonitor")),
+        "Voltage" => (Some("VoltageProperty"), Some("volt"), Some("EnergyMonitor")),
+        "Current" => (Some("CurrentProperty"), Some("ampere"), Some("EnergyMonitor")),
+        "Total" | "Today" | "Yesterday" => (None, Some("kilowatt_hour"), None),
+        "Illuminance" => (None, Some("lux"), None),
+        _ => (None, None, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Value {
+        json!({
+            "dn": "Heater",
+            "t": "heater",
+            "ft": "%prefix%/%topic%/",
+            "tp": ["cmnd", "stat", "tele"],
+            "rl": [1, 1, 0, 0],
+            "state": ["AUS", "AN", "TOGGLE", "HOLD"],
+            "lt_st": 0,
+        })
+    }
+
+    fn added(events: Vec<DiscoveryEvent>) -> DeviceConfig {
+        match events.into_iter().next() {
+            Some(DiscoveryEvent::Added(config)) => *config,
+            _ => panic!("no device"),
+        }
+    }
+
+    #[test]
+    fn relays_and_sensors() {
+        let mut tasmota = Tasmota::new("tasmota/discovery");
+        let config = added(tasmota.handle_publish("tasmota/discovery/AABBCC/config",
+                                                  config().to_string().as_bytes()));
+        assert_eq!(config.id, "tasmota-AABBCC");
+        assert_eq!(config.name, "Heater");
+        assert_eq!(config.topic, Some("heater".to_string()));
+        assert_eq!(config.types, vec!["OnOffSwitch"]);
+        let names: Vec<&str> = config.properties.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, vec!["POWER1", "POWER2"]);
+        let power = &config.payloads["POWER1"];
+        assert_eq!(power.state_topics, vec!["stat/{device}/RESULT", "tele/{device}/STATE"]);
+        assert_eq!(power.decode(br#"{"POWER1":"AN"}"#), Some(Value::Bool(true)));
+        assert_eq!(power.encode(&Value::Bool(false)), b"AUS".to_vec());
+        assert_eq!(config.topics.unwrap().command, "cmnd/{device}/{property}");
+
+        let sensors = json!({ "sn": { "AM2301": { "Temperature": 21.5, "Humidity": 40 },
+                                      "TempUnit": "F" } });
+        let config = added(tasmota.handle_publish("tasmota/discovery/AABBCC/sensors",
+                                                  sensors.to_string().as_bytes()));
+        assert_eq!(config.types, vec!["OnOffSwitch", "HumiditySensor", "TemperatureSensor"]);
+        let temperature = config.properties.iter().find(|p| p.name == "AM2301_Temperature").unwrap();
+        assert_eq!(temperature.unit, Some("degree fahrenheit".to_string()));
+        assert_eq!(temperature.read_only, Some(true));
+        let format = &config.payloads["AM2301_Temperature"];
+        assert_eq!(format.state_topics, vec!["tele/{device}/SENSOR"]);
+        assert_eq!(format.decode(br#"{"AM2301":{"Temperature":22.1}}"#), Some(json!(22.1)));
+
+        // Unchanged announcements are not reported again.
+        assert!(tasmota.handle_publish("tasmota/discovery/AABBCC/sensors",
+                                       sensors.to_string().as_bytes()).is_empty());
+    }
+
+    #[test]
+    fn color_lights() {
+        let mut config = config();
+        config["rl"] = json!([2]);
+        config["lt_st"] = json!(LIGHT_RGBCW);
+        let mut tasmota = Tasmota::new("tasmota/discovery");
+        let config = added(tasmota.handle_publish("tasmota/discovery/AABBCC/config",
+                                                  config.to_string().as_bytes()));
+        assert_eq!(config.types, vec!["OnOffSwitch", "ColorControl", "Light"]);
+        let names: Vec<&str> = config.properties.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, vec!["POWER", "Dimmer", "CT", "Color"]);
+        let mut color = config.payloads["Color"].clone();
+        color.prepare().unwrap();
+        assert_eq!(color.decode(br#"{"Color":"FF8000CC"}"#), Some(json!("#FF8000")));
+    }
+
+    #[test]
+    fn color_temperatures_in_kelvin() {
+        let mut config = config();
+        config["rl"] = json!([2]);
+        config["lt_st"] = json!(LIGHT_CT);
+        let mut tasmota = Tasmota::new("tasmota/discovery");
+        let config = added(tasmota.handle_publish("tasmota/discovery/AABBCC/config",
+                                                  config.to_string().as_bytes()));
+        assert_eq!(config.types, vec!["OnOffSwitch", "ColorControl", "Light"]);
+        let ct = config.properties.iter().find(|p| p.name == "CT").unwrap();
+        assert_eq!(ct.at_type, Some("ColorTemperatureProperty".to_string()));
+        assert_eq!(ct.unit, Some("kelvin".to_string()));
+        assert_eq!((ct.minimum, ct.maximum), (Some(2000.0), Some(6536.0)));
+        let mut format = config.payloads["CT"].clone();
+        format.prepare().unwrap();
+        assert_eq!(format.decode(br#"{"CT":250}"#), Some(json!(4000)));
+        assert_eq!(format.encode(&json!(4000)), b"250");
+    }
+
+    #[test]
+    fn cleared_config_removes_device() {
+        let mut tasmota = Tasmota::new("tasmota/discovery");
+        assert!(tasmota.handle_publish("tasmota/discovery/AABBCC/config", b"").is_empty());
+        tasmota.handle_publish("tasmota/discovery/AABBCC/config", config().to_string().as_bytes());
+        match tasmota.handle_publish("tasmota/discovery/AABBCC/config", b"").as_slice() {
+            &[DiscoveryEvent::Removed(ref id)] => assert_eq!(id, "tasmota-AABBCC"),
+            _ => panic!("device not removed"),
+        }
+        assert!(tasmota.handle_publish("tasmota/other/AABBCC/config", b"{}").is_empty());
+    }
+}
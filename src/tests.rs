@@ -0,0 +1,528 @@
+//! End-to-end tests running the plugin against the embedded broker and a
+//! fake gateway speaking the add-on IPC protocol.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::Receiver;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use mqtt3::{self, MqttRead, MqttWrite};
+use nanomsg::{Endpoint, Protocol, Socket};
+use serde_json::{self, Value};
+
+use broker::Broker;
+use config::{self, Config, EmbeddedBrokerConfig};
+use gateway::{GatewayBridge, Plugin};
+use metrics::Metrics;
+use mqtt;
+use protobuf::{self, Reader};
+use queue::OfflineQueue;
+use registry::Registry;
+use super::MQTTAdapter;
+
+const PLUGIN_ID: &str = "mqtt-adapter";
+const ADAPTER_ID: &str = "mqtt-0";
+const LAMP_ID: &str = "mqtt-0-lamp";
+
+/// Longest wait for an expected message, in seconds.
+const TIMEOUT: u64 = 5;
+
+static INSTANCES: AtomicUsize = AtomicUsize::new(0);
+
+/// A running plugin wired to its own broker and gateway.
+struct Harness {
+    gateway: Socket,
+    _gateway_endpoint: Endpoint,
+    _broker: Broker,
+    /// A client subscribed to everything, to publish as devices do and to
+    /// see what the plugin publishes.
+    observer: mqtt::MQTT,
+    traffic: Receiver<mqtt3::Publish>,
+    plugin: Option<JoinHandle<Result<(), io::Error>>>,
+    data_dir: PathBuf,
+}
+
+impl Harness {
+    fn start(config: Value) -> Harness {
+        let name = format!("mqtt-adapter-test-{}-{}", process::id(),
+                           INSTANCES.fetch_add(1, Ordering::SeqCst));
+        let data_dir = ::std::env::temp_dir().join(&name);
+
+        let broker = Broker::start(&EmbeddedBrokerConfig {
+            bind: "127.0.0.1".to_string(),
+            port: 0,
+            users: HashMap::new(),
+        }).unwrap();
+        let metrics = Metrics::new();
+        let (observer, traffic) = connect(broker.local_addr(), "observer", &metrics);
+        observer.subscribe(&["#".to_string()]).unwrap();
+        let (mqtt, inbox) = connect(broker.local_addr(), PLUGIN_ID, &metrics);
+
+        let mut manager = Socket::new(Protocol::Rep).unwrap();
+        let manager_url = format!("ipc:///tmp/{}.addonManager", name);
+        let mut manager_endpoint = manager.bind(&manager_url).unwrap();
+        manager.set_receive_timeout((TIMEOUT * 1000) as isize).unwrap();
+        let mut gateway = Socket::new(Protocol::Pair).unwrap();
+        let gateway_endpoint = gateway.bind(&format!("ipc:///tmp/{}", name)).unwrap();
+        gateway.set_receive_timeout(100).unwrap();
+
+        let (mut bridge, msg_sender, msg_receiver) = GatewayBridge::with_manager(PLUGIN_ID, &manager_url,
+                                                                                 metrics.clone());
+        thread::spawn(move || {
+            bridge.run_forever().unwrap();
+        });
+        let mut request = Vec::new();
+        manager.read_to_end(&mut request).unwrap();
+        let request: Value = serde_json::from_slice(&request).unwrap();
+        assert_eq!(request, json!({
+            "messageType": "registerPlugin",
+            "data": { "pluginId": PLUGIN_ID },
+        }));
+        let reply = json!({
+            "messageType": "registerPluginReply",
+            "data": { "pluginId": PLUGIN_ID, "ipcBaseAddr": name },
+        });
+        manager.write_all(reply.to_string().as_bytes()).unwrap();
+        manager_endpoint.shutdown().unwrap();
+
+        let registry_path = data_dir.join(config::REGISTRY_FILE);
+        let plugin = thread::spawn(move || {
+            let config: Config = serde_json::from_value(config).unwrap();
+            let mut plugin = Plugin::new("mqtt", PLUGIN_ID, msg_sender, msg_receiver);
+            let handle = plugin.adapter_handle(ADAPTER_ID);
+            let registry = Registry::load(&registry_path)?;
+            let adapter = MQTTAdapter::new(handle, mqtt, inbox, &config, config::DEFAULT_BROKER,
+                                           registry)?;
+            plugin.add_adapter(ADAPTER_ID, Box::new(adapter));
+            plugin.run_forever()
+        });
+
+        Harness {
+            gateway,
+            _gateway_endpoint: gateway_endpoint,
+            _broker: broker,
+            observer,
+            traffic,
+            plugin: Some(plugin),
+            data_dir,
+        }
+    }
+
+    /// Sends a message to the plugin as the gateway.
+    fn send(&mut self, message_type: &str, mut data: Value) {
+        data["pluginId"] = json!(PLUGIN_ID);
+        data["adapterId"] = json!(ADAPTER_ID);
+        let msg = json!({ "messageType": message_type, "data": data });
+        self.gateway.write_all(msg.to_string().as_bytes()).unwrap();
+    }
+
+    /// Waits for a message of `message_type` whose data satisfies `filter`,
+    /// skipping other messages, and returns its data.
+    fn expect<F: Fn(&Value) -> bool>(&mut self, message_type: &str, filter: F) -> Value {
+        let deadline = Instant::now() + Duration::from_secs(TIMEOUT);
+        while Instant::now() < deadline {
+            let mut buf = Vec::new();
+            if self.gateway.read_to_end(&mut buf).is_err() {
+                continue;
+            }
+            let msg: Value = serde_json::from_slice(&buf).unwrap();
+            assert_eq!(msg["data"]["pluginId"], PLUGIN_ID);
+            if msg["messageType"] == message_type && filter(&msg["data"]) {
+                return msg["data"].clone();
+            }
+        }
+        panic!("no {} from the plugin", message_type);
+    }
+
+    /// Publishes as a device would.
+    fn publish(&self, topic: &str, payload: &[u8]) {
+        self.observer.publish(topic, payload.to_vec()).unwrap();
+    }
+
+    /// Waits for a publish on `topic` and returns its payload.
+    fn expect_publish(&self, topic: &str) -> Vec<u8> {
+        let deadline = Instant::now() + Duration::from_secs(TIMEOUT);
+        loop {
+            let now = Instant::now();
+            assert!(now < deadline, "nothing published on {}", topic);
+            match self.traffic.recv_timeout(deadline - now) {
+                Ok(ref publish) if publish.topic_name == topic => return publish.payload.to_vec(),
+                _ => {},
+            }
+        }
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.data_dir);
+    }
+}
+
+fn connect(server: SocketAddr, client_id: &str, metrics: &Metrics)
+           -> (mqtt::MQTT, Receiver<mqtt3::Publish>) {
+    let options = mqtt::Options {
+        server: server.to_string(),
+        username: None,
+        password: None,
+        tls: None,
+        client_id: client_id.to_string(),
+        clean_session: true,
+        receive_maximum: 100,
+    };
+    let queue = OfflineQueue::new(60, 100, None).unwrap();
+    mqtt::MQTT::connect(options, queue, metrics.connection(client_id))
+}
+
+/// An on/off switch on `devices/lamp/on`, switched through
+/// `devices/lamp/on/set`.
+fn lamp() -> Value {
+    json!({
+        "id": "lamp",
+        "name": "Lamp",
+        "capability": "OnOffSwitch",
+        "topicScheme": "generic",
+        "actions": [{ "name": "blink" }],
+    })
+}
+
+fn lamp_config() -> Value {
+    json!({ "devices": [lamp()] })
+}
+
+#[test]
+fn announces_adapter_and_devices() {
+    let mut harness = Harness::start(lamp_config());
+    let adapter = harness.expect("addAdapter", |_| true);
+    assert_eq!(adapter["adapterId"], ADAPTER_ID);
+    assert_eq!(adapter["name"], "MQTT Adapter");
+    let device = harness.expect("handleDeviceAdded", |d| d["id"] == LAMP_ID);
+    assert_eq!(device["name"], "Lamp");
+    assert_eq!(device["@type"], json!(["OnOffSwitch"]));
+    assert_eq!(device["properties"]["on"]["value"], false);
+    assert!(device["actions"]["blink"].is_object());
+}
+
+#[test]
+fn default_device_starts_with_description_values() {
+    let mut harness = Harness::start(json!({}));
+    let device = harness.expect("handleDeviceAdded", |d| d["id"] == "mqtt-0-0");
+    assert_eq!(device["@type"], json!(["OnOffSwitch"]));
+    assert_eq!(device["properties"]["on"]["value"], false);
+}
+
+#[test]
+fn announces_status_device() {
+    let mut harness = Harness::start(lamp_config());
+    let device = harness.expect("handleDeviceAdded", |d| d["id"] == "mqtt-0-adapter-status");
+    assert_eq!(device["name"], "MQTT Adapter Status");
+    assert_eq!(device["properties"]["connected"]["value"], true);
+    assert_eq!(device["properties"]["connected"]["readOnly"], true);
+    assert_eq!(device["properties"]["lastError"]["value"], "");
+
+    harness.send("setProperty", json!({
+        "deviceId": "mqtt-0-adapter-status",
+        "propertyName": "connected",
+        "propertyValue": false,
+    }));
+    let error = harness.expect("pluginError", |_| true);
+    assert!(error["message"].as_str().unwrap().ends_with("read-only"));
+}
+
+#[test]
+fn set_property_publishes_command() {
+    let mut harness = Harness::start(lamp_config());
+    harness.expect("handleDeviceAdded", |d| d["id"] == LAMP_ID);
+    harness.send("setProperty", json!({
+        "deviceId": LAMP_ID,
+        "propertyName": "on",
+        "propertyValue": true,
+    }));
+    assert_eq!(harness.expect_publish("devices/lamp/on/set"), b"true");
+    let changed = harness.expect("propertyChanged", |d| d["deviceId"] == LAMP_ID);
+    assert_eq!(changed["adapterId"], ADAPTER_ID);
+    assert_eq!(changed["property"], json!({ "name": "on", "value": true }));
+}
+
+#[test]
+fn set_property_reports_invalid_values() {
+    let mut harness = Harness::start(lamp_config());
+    harness.expect("handleDeviceAdded", |d| d["id"] == LAMP_ID);
+    harness.send("setProperty", json!({
+        "deviceId": LAMP_ID,
+        "propertyName": "on",
+        "propertyValue": "bright",
+    }));
+    let error = harness.expect("pluginError", |_| true);
+    assert!(error["message"].as_str().unwrap().starts_with("mqtt-0/mqtt-0-lamp"));
+}
+
+#[test]
+fn failed_requests_do_not_stop_plugin() {
+    let mut harness = Harness::start(lamp_config());
+    harness.expect("handleDeviceAdded", |d| d["id"] == LAMP_ID);
+    harness.send("requestAction", json!({
+        "deviceId": "mqtt-0-missing",
+        "actionId": 1,
+        "actionName": "blink",
+        "input": {},
+    }));
+    let error = harness.expect("pluginError", |_| true);
+    assert_eq!(error["message"], "mqtt-0/mqtt-0-missing: Device not found");
+    harness.send("removeThing", json!({ "deviceId": "mqtt-0-missing" }));
+    harness.expect("pluginError", |_| true);
+
+    harness.send("setProperty", json!({
+        "deviceId": LAMP_ID,
+        "propertyName": "on",
+        "propertyValue": true,
+    }));
+    assert_eq!(harness.expect_publish("devices/lamp/on/set"), b"true");
+}
+
+#[test]
+fn reported_state_changes_property() {
+    let mut harness = Harness::start(lamp_config());
+    harness.expect("handleDeviceAdded", |d| d["id"] == LAMP_ID);
+    harness.publish("devices/lamp/on", b"true");
+    let changed = harness.expect("propertyChanged", |d| d["deviceId"] == LAMP_ID);
+    assert_eq!(changed["property"], json!({ "name": "on", "value": true }));
+}
+
+#[test]
+fn request_action_publishes_action() {
+    let mut harness = Harness::start(lamp_config());
+    harness.expect("handleDeviceAdded", |d| d["id"] == LAMP_ID);
+    harness.send("requestAction", json!({
+        "deviceId": LAMP_ID,
+        "actionId": 1,
+        "actionName": "blink",
+        "input": {},
+    }));
+    assert_eq!(harness.expect_publish("devices/lamp/blink"), b"blink");
+}
+
+#[test]
+fn pairing_permits_joins_and_adds_devices() {
+    let mut harness = Harness::start(json!({ "devices": [lamp()], "zigbee2mqtt": {} }));
+    harness.expect("handleDeviceAdded", |d| d["id"] == LAMP_ID);
+
+    harness.send("startPairing", json!({ "timeout": 60 }));
+    let request: Value = serde_json::from_slice(
+        &harness.expect_publish("zigbee2mqtt/bridge/request/permit_join")).unwrap();
+    assert_eq!(request, json!({ "value": true, "time": 60 }));
+
+    let devices = json!([{
+        "ieee_address": "0x00158d0001",
+        "friendly_name": "door",
+        "type": "EndDevice",
+        "supported": true,
+        "interview_completed": true,
+        "definition": {
+            "exposes": [{ "type": "binary", "property": "contact", "access": 1 }],
+        },
+    }]);
+    harness.publish("zigbee2mqtt/bridge/devices", devices.to_string().as_bytes());
+    let device = harness.expect("handleDeviceAdded", |d| d["id"] == "mqtt-0-0x00158d0001");
+    assert_eq!(device["name"], "door");
+    assert!(device["properties"]["contact"].is_object());
+
+    harness.send("cancelPairing", json!({}));
+    let request: Value = serde_json::from_slice(
+        &harness.expect_publish("zigbee2mqtt/bridge/request/permit_join")).unwrap();
+    assert_eq!(request, json!({ "value": false }));
+}
+
+#[test]
+fn sparkplug_commands_are_protobuf() {
+    let mut harness = Harness::start(json!({ "devices": [lamp()], "sparkplug": { "groupId": "Plant" } }));
+    harness.expect("handleDeviceAdded", |d| d["id"] == LAMP_ID);
+
+    let mut pump = Vec::new();
+    protobuf::write_bytes(&mut pump, 1, b"Outputs/Pump");
+    protobuf::write_uint(&mut pump, 4, 11);
+    protobuf::write_bool(&mut pump, 14, false);
+    let mut birth = Vec::new();
+    protobuf::write_bytes(&mut birth, 2, &pump);
+    protobuf::write_uint(&mut birth, 3, 0);
+    harness.publish("spBv1.0/Plant/NBIRTH/edge1", &birth);
+    harness.expect("handleDeviceAdded", |d| d["id"] == "mqtt-0-sparkplug-Plant-edge1");
+
+    harness.send("setProperty", json!({
+        "deviceId": "mqtt-0-sparkplug-Plant-edge1",
+        "propertyName": "Outputs_Pump",
+        "propertyValue": true,
+    }));
+    let command = harness.expect_publish("spBv1.0/Plant/NCMD/edge1");
+    let mut metric = None;
+    let mut reader = Reader::new(&command);
+    while let Some((number, field)) = reader.next_field().unwrap() {
+        if number == 2 {
+            metric = field.as_bytes().map(|b| b.to_vec());
+        }
+    }
+    let metric = metric.expect("no metric in the command");
+    let mut fields = HashMap::new();
+    let mut reader = Reader::new(&metric);
+    while let Some((number, field)) = reader.next_field().unwrap() {
+        fields.insert(number, (field.as_string(), field.as_u64()));
+    }
+    assert_eq!(fields[&1].0, Some("Outputs/Pump".to_string()));
+    assert_eq!(fields[&14].1, Some(1));
+}
+
+#[test]
+fn changed_devices_are_removed_before_being_added_again() {
+    let mut harness = Harness::start(json!({ "devices": [lamp()], "zigbee2mqtt": {} }));
+    harness.expect("handleDeviceAdded", |d| d["id"] == LAMP_ID);
+    let mut devices = json!([{
+        "ieee_address": "0x00158d0001",
+        "friendly_name": "door",
+        "type": "EndDevice",
+        "definition": {
+            "exposes": [{ "type": "binary", "property": "contact", "access": 1 }],
+        },
+    }]);
+    harness.publish("zigbee2mqtt/bridge/devices", devices.to_string().as_bytes());
+    harness.expect("handleDeviceAdded", |d| d["id"] == "mqtt-0-0x00158d0001");
+
+    devices[0]["definition"]["exposes"] = json!([{ "type": "binary", "property": "tamper", "access": 1 }]);
+    harness.publish("zigbee2mqtt/bridge/devices", devices.to_string().as_bytes());
+    harness.expect("handleDeviceRemoved", |d| d["id"] == "mqtt-0-0x00158d0001");
+    let device = harness.expect("handleDeviceAdded", |d| d["id"] == "mqtt-0-0x00158d0001");
+    assert!(device["properties"]["tamper"].is_object());
+    assert!(device["properties"]["contact"].is_null());
+}
+
+#[test]
+fn remove_thing_removes_device() {
+    let mut harness = Harness::start(lamp_config());
+    harness.expect("handleDeviceAdded", |d| d["id"] == LAMP_ID);
+    harness.send("removeThing", json!({ "deviceId": LAMP_ID }));
+    let removed = harness.expect("handleDeviceRemoved", |_| true);
+    assert_eq!(removed["id"], LAMP_ID);
+    assert_eq!(removed["adapterId"], ADAPTER_ID);
+}
+
+#[test]
+fn unload_stops_plugin() {
+    let mut harness = Harness::start(lamp_config());
+    harness.expect("handleDeviceAdded", |d| d["id"] == LAMP_ID);
+    harness.send("unloadAdapter", json!({}));
+    let unloaded = harness.expect("adapterUnloaded", |_| true);
+    assert_eq!(unloaded["adapterId"], ADAPTER_ID);
+
+    harness.send("unloadPlugin", json!({}));
+    harness.expect("pluginUnloaded", |_| true);
+    let plugin = harness.plugin.take().unwrap();
+    plugin.join().unwrap().unwrap();
+}
+
+#[test]
+fn unload_saves_renames_and_values() {
+    let mut harness = Harness::start(lamp_config());
+    harness.expect("handleDeviceAdded", |d| d["id"] == LAMP_ID);
+    harness.send("setTitle", json!({ "deviceId": LAMP_ID, "title": "Desk lamp" }));
+    harness.send("setProperty", json!({
+        "deviceId": LAMP_ID,
+        "propertyName": "on",
+        "propertyValue": true,
+    }));
+    harness.expect("propertyChanged", |d| d["deviceId"] == LAMP_ID);
+    harness.send("unloadAdapter", json!({}));
+    harness.expect("adapterUnloaded", |_| true);
+
+    let registry = Registry::load(&harness.data_dir.join(config::REGISTRY_FILE)).unwrap();
+    let lamp = registry.get(LAMP_ID).unwrap();
+    assert_eq!(lamp.name, Some("Desk lamp".to_string()));
+    assert_eq!(lamp.values["on"], json!(true));
+}
+
+#[test]
+fn publishes_once_broker_is_up() {
+    let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let metrics = Metrics::new();
+    let (mqtt, _inbox) = connect(address, PLUGIN_ID, &metrics);
+    assert!(!metrics.connection(PLUGIN_ID).stats().connected);
+    mqtt.publish_retained("devices/lamp/on/set", b"true".to_vec()).unwrap();
+
+    let _broker = Broker::start(&EmbeddedBrokerConfig {
+        bind: "127.0.0.1".to_string(),
+        port: address.port(),
+        users: HashMap::new(),
+    }).unwrap();
+    let (observer, traffic) = connect(address, "observer", &metrics);
+    observer.subscribe(&["devices/#".to_string()]).unwrap();
+    let publish = traffic.recv_timeout(Duration::from_secs(TIMEOUT)).unwrap();
+    assert_eq!(publish.topic_name, "devices/lamp/on/set");
+    assert_eq!(&publish.payload[..], b"true");
+    assert!(metrics.connection(PLUGIN_ID).stats().connected);
+}
+
+#[test]
+fn broker_keeps_clients_without_id_apart() {
+    let broker = Broker::start(&EmbeddedBrokerConfig {
+        bind: "127.0.0.1".to_string(),
+        port: 0,
+        users: HashMap::new(),
+    }).unwrap();
+    let metrics = Metrics::new();
+    let (first, first_traffic) = connect(broker.local_addr(), "", &metrics);
+    first.subscribe(&["devices/#".to_string()]).unwrap();
+    let (second, second_traffic) = connect(broker.local_addr(), "", &metrics);
+    second.subscribe(&["devices/#".to_string()]).unwrap();
+    thread::sleep(Duration::from_millis(100));
+
+    first.publish("devices/lamp/on", b"true".to_vec()).unwrap();
+    for traffic in &[first_traffic, second_traffic] {
+        let publish = traffic.recv_timeout(Duration::from_secs(TIMEOUT)).unwrap();
+        assert_eq!(publish.topic_name, "devices/lamp/on");
+    }
+}
+
+#[test]
+fn anonymous_broker_stays_on_this_host() {
+    let result = Broker::start(&EmbeddedBrokerConfig {
+        bind: "0.0.0.0".to_string(),
+        port: 0,
+        users: HashMap::new(),
+    });
+    assert_eq!(result.err().map(|e| e.kind()), Some(io::ErrorKind::PermissionDenied));
+}
+
+/// The CONNACK return code `broker` answers a CONNECT with.
+fn connack(broker: &Broker, username: &str, password: &str) -> mqtt3::ConnectReturnCode {
+    let mut stream = TcpStream::connect(broker.local_addr()).unwrap();
+    stream.write_packet(&mqtt3::Packet::Connect(Box::new(mqtt3::Connect {
+        protocol: mqtt3::Protocol::MQTT(4),
+        keep_alive: 30,
+        client_id: "client".to_string(),
+        clean_session: true,
+        last_will: None,
+        username: Some(username.to_string()),
+        password: Some(password.to_string()),
+    }))).unwrap();
+    match stream.read_packet().unwrap() {
+        mqtt3::Packet::Connack(connack) => connack.code,
+        packet => panic!("unexpected {:?}", packet),
+    }
+}
+
+#[test]
+fn broker_checks_passwords_once_it_has_users() {
+    let broker = Broker::start(&EmbeddedBrokerConfig {
+        bind: "127.0.0.1".to_string(),
+        port: 0,
+        users: HashMap::new(),
+    }).unwrap();
+    assert_eq!(connack(&broker, "anyone", "anything"), mqtt3::ConnectReturnCode::Accepted);
+    broker.add_user("adapter", "secret");
+    assert_eq!(connack(&broker, "adapter", "wrong"), mqtt3::ConnectReturnCode::BadUsernamePassword);
+    assert_eq!(connack(&broker, "adapter", "secret"), mqtt3::ConnectReturnCode::Accepted);
+}
@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+use std::io;
+
+use serde_json::Value;
+
+use payload::{PayloadFormat, ValueMapping};
+
+/// Topic templates for a device. Templates may use the `{base}`, `{device}`,
+/// `{property}` and `{action}` placeholders.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TopicScheme {
+    /// Prefix substituted for `{base}` when the device does not set one.
+    #[serde(default)]
+    pub base: String,
+    /// Where property values are sent.
+    pub command: String,
+    /// Where the device reports property values.
+    pub state: String,
+    /// Where actions are sent.
+    pub action: String,
+    /// Payload sent with an action.
+    #[serde(default = "default_action_payload")]
+    pub action_payload: String,
+    /// Topics and payloads of properties the layout treats specially, by
+    /// property name. A device's own `payloads` take precedence.
+    #[serde(skip_serializing_if = "HashMap::is_empty", default)]
+    pub payloads: HashMap<String, PayloadFormat>,
+}
+
+fn default_action_payload() -> String {
+    "{action}".to_string()
+}
+
+impl TopicScheme {
+    /// Looks up one of the built-in layouts: `adafruit`, `tasmota`,
+    /// `zigbee2mqtt` or `generic`.
+    pub fn preset(name: &str) -> Result<TopicScheme, io::Error> {
+        let (base, command, state, action, action_payload) = match name {
+            "adafruit" => ("", "{base}/feeds/{property}", "{base}/feeds/{property}",
+                           "{base}/feeds/actions", "{action}"),
+            "tasmota" => ("", "cmnd/{device}/{property}", "stat/{device}/{property}",
+                          "cmnd/{device}/{action}", "1"),
+            "zigbee2mqtt" => ("zigbee2mqtt", "{base}/{device}/set", "{base}/{device}",
+                              "{base}/{device}/set", "{action}"),
+            "generic" => ("devices", "{base}/{device}/{property}/set", "{base}/{device}/{property}",
+                          "{base}/{device}/{action}", "{action}"),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                           format!("Unknown topic scheme {}", name))),
+        };
+        Ok(TopicScheme {
+            base: base.to_string(),
+            command: command.to_string(),
+            state: state.to_string(),
+            action: action.to_string(),
+            action_payload: action_payload.to_string(),
+            payloads: preset_payloads(name),
+        })
+    }
+}
+
+/// How the devices of a preset layout expect the capability properties.
+fn preset_payloads(name: &str) -> HashMap<String, PayloadFormat> {
+    let mut payloads = HashMap::new();
+    match name {
+        // Tasmota names its commands after what they control, and answers
+        // them on `RESULT` as well as on the command's own state topic.
+        "tasmota" => {
+            let mut on = PayloadFormat::default();
+            on.command_topic = Some("cmnd/{device}/POWER".to_string());
+            on.state_topics = vec!["stat/{device}/POWER".to_string(), "stat/{device}/RESULT".to_string()];
+            on.state_pattern = Some(r#"^(?:\{.*"POWER":")?(ON|OFF)"#.to_string());
+            on.value_map = on_off_map("ON", "OFF");
+            payloads.insert("on".to_string(), on);
+
+            let mut level = PayloadFormat::default();
+            level.command_topic = Some("cmnd/{device}/Dimmer".to_string());
+            level.state_topics = vec!["stat/{device}/RESULT".to_string()];
+            level.state_path = Some("Dimmer".to_string());
+            payloads.insert("level".to_string(), level);
+        },
+        // Zigbee2MQTT takes and reports a JSON object of the device's state.
+        "zigbee2mqtt" => {
+            let mut on = PayloadFormat::default();
+            on.command_template = Some("{\"state\":\"{{value}}\"}".to_string());
+            on.state_path = Some("state".to_string());
+            on.value_map = on_off_map("ON", "OFF");
+            payloads.insert("on".to_string(), on);
+        },
+        _ => {},
+    }
+    payloads
+}
+
+fn on_off_map(on: &str, off: &str) -> Vec<ValueMapping> {
+    vec![
+        ValueMapping { value: Value::Bool(true), payload: on.to_string() },
+        ValueMapping { value: Value::Bool(false), payload: off.to_string() },
+    ]
+}
+
+/// A `TopicScheme` bound to one device.
+#[derive(Clone, Debug)]
+pub struct DeviceTopics {
+    scheme: TopicScheme,
+    base: String,
+    device: String,
+}
+
+impl DeviceTopics {
+    pub fn new(scheme: TopicScheme, base: &str, device: &str) -> DeviceTopics {
+        DeviceTopics {
+            scheme,
+            base: base.to_string(),
+            device: device.to_string(),
+        }
+    }
+
+    pub fn command(&self, property: &str) -> String {
+        self.expand(&self.scheme.command, property, "")
+    }
+
+    pub fn state(&self, property: &str) -> String {
+        self.expand(&self.scheme.state, property, "")
+    }
+
+    pub fn action(&self, action: &str) -> String {
+        self.expand(&self.scheme.action, "", action)
+    }
+
+    pub fn action_payload(&self, action: &str) -> Vec<u8> {
+        self.expand(&self.scheme.action_payload, "", action).into_bytes()
+    }
+
+    /// Expands a per-property topic template such as
+    /// `PayloadFormat::command_topic`.
+    pub fn property_topic(&self, template: &str, property: &str) -> String {
+        self.expand(template, property, "")
+    }
+
+    fn expand(&self, template: &str, property: &str, action: &str) -> String {
+        template
+            .replace("{base}", &self.base)
+            .replace("{device}", &self.device)
+            .replace("{property}", property)
+            .replace("{action}", action)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prepared(scheme: &TopicScheme, property: &str) -> PayloadFormat {
+        let mut format = scheme.payloads[property].clone();
+        format.prepare().unwrap();
+        format
+    }
+
+    #[test]
+    fn presets_expand_topics() {
+        let topics = DeviceTopics::new(TopicScheme::preset("generic").unwrap(), "devices", "lamp");
+        assert_eq!(topics.command("on"), "devices/lamp/on/set");
+        assert_eq!(topics.state("on"), "devices/lamp/on");
+        assert_eq!(topics.action("blink"), "devices/lamp/blink");
+        assert_eq!(topics.action_payload("blink"), b"blink");
+
+        let topics = DeviceTopics::new(TopicScheme::preset("adafruit").unwrap(), "me", "lamp");
+        assert_eq!(topics.command("on"), "me/feeds/on");
+        assert!(TopicScheme::preset("unknown").is_err());
+    }
+
+    #[test]
+    fn tasmota_switches_power() {
+        let scheme = TopicScheme::preset("tasmota").unwrap();
+        let topics = DeviceTopics::new(scheme.clone(), "", "plug");
+        let on = prepared(&scheme, "on");
+        assert_eq!(topics.property_topic(on.command_topic.as_ref().unwrap(), "on"), "cmnd/plug/POWER");
+        assert_eq!(on.encode(&Value::Bool(true)), b"ON");
+        assert_eq!(on.decode(b"OFF"), Some(Value::Bool(false)));
+        assert_eq!(on.decode(br#"{"POWER":"ON","Dimmer":40}"#), Some(Value::Bool(true)));
+        assert_eq!(on.decode(br#"{"POWER2":"ON"}"#), None);
+
+        let level = prepared(&scheme, "level");
+        assert_eq!(level.decode(br#"{"POWER":"ON","Dimmer":40}"#), Some(Value::from(40)));
+    }
+
+    #[test]
+    fn zigbee2mqtt_sends_state_objects() {
+        let on = prepared(&TopicScheme::preset("zigbee2mqtt").unwrap(), "on");
+        assert_eq!(on.encode(&Value::Bool(true)), br#"{"state":"ON"}"#);
+        assert_eq!(on.decode(br#"{"state":"OFF","linkquality":90}"#), Some(Value::Bool(false)));
+    }
+}
@@ -0,0 +1,135 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use native_tls::{Certificate, Identity, TlsConnector, TlsStream};
+
+use config::TlsConfig;
+
+/// Longest a read on a TLS connection keeps it to itself, in milliseconds.
+const TLS_READ_SLICE: u64 = 50;
+
+/// A connection to a broker, plain or over TLS, that one thread reads from
+/// while others write to it.
+///
+/// Unlike a socket, a TLS session cannot be split into a reading and a
+/// writing half, so clones share it and reads wait for data in short slices,
+/// letting writers in between.
+pub struct Stream {
+    socket: TcpStream,
+    tls: Option<Arc<Mutex<TlsStream<TcpStream>>>>,
+    /// How long a read waits for data before failing.
+    read_timeout: Option<Duration>,
+}
+
+impl Stream {
+    /// Connects to `server`, given as `host:port`, over TLS if `tls` is set.
+    pub fn connect(server: &str, tls: Option<&TlsConfig>) -> Result<Stream, io::Error> {
+        let socket = TcpStream::connect(server)?;
+        let tls = match tls {
+            Some(tls) => tls,
+            None => return Ok(Stream {
+                socket,
+                tls: None,
+                read_timeout: None,
+            }),
+        };
+        let host = server.rsplitn(2, ':').last().unwrap_or(server)
+            .trim_matches(|c| c == '[' || c == ']');
+        let session = connector(tls)?.connect(host, socket.try_clone()?).map_err(|e| {
+            io::Error::other(format!("TLS handshake with {}: {}", server, e))
+        })?;
+        socket.set_read_timeout(Some(Duration::from_millis(TLS_READ_SLICE)))?;
+        Ok(Stream {
+            socket,
+            tls: Some(Arc::new(Mutex::new(session))),
+            read_timeout: None,
+        })
+    }
+
+    pub fn try_clone(&self) -> Result<Stream, io::Error> {
+        Ok(Stream {
+            socket: self.socket.try_clone()?,
+            tls: self.tls.clone(),
+            read_timeout: self.read_timeout,
+        })
+    }
+
+    /// Makes reads fail with `TimedOut` when no data arrives for `timeout`.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<(), io::Error> {
+        if self.tls.is_none() {
+            self.socket.set_read_timeout(timeout)?;
+        }
+        self.read_timeout = timeout;
+        Ok(())
+    }
+
+    /// Closes the connection for all clones, waking up a blocked reader.
+    pub fn shutdown(&self) {
+        let _ = self.socket.shutdown(Shutdown::Both);
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let tls = match self.tls {
+            Some(ref tls) => tls,
+            None => return self.socket.read(buf),
+        };
+        let started = Instant::now();
+        loop {
+            match tls.lock().unwrap().read(buf) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock
+                    || e.kind() == io::ErrorKind::TimedOut => {},
+                result => return result,
+            }
+            if self.read_timeout.is_some_and(|timeout| started.elapsed() >= timeout) {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "read timed out"));
+            }
+            thread::yield_now();
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.tls {
+            Some(ref tls) => tls.lock().unwrap().write(buf),
+            None => self.socket.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.tls {
+            Some(ref tls) => tls.lock().unwrap().flush(),
+            None => self.socket.flush(),
+        }
+    }
+}
+
+fn connector(tls: &TlsConfig) -> Result<TlsConnector, io::Error> {
+    let mut builder = TlsConnector::builder();
+    if let Some(ref path) = tls.ca_file {
+        let certificate = Certificate::from_pem(&read_file(path)?).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path, e))
+        })?;
+        builder.add_root_certificate(certificate);
+    }
+    if let Some(ref path) = tls.identity_file {
+        let identity = Identity::from_pkcs12(&read_file(path)?, &tls.identity_password).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path, e))
+        })?;
+        builder.identity(identity);
+    }
+    builder.danger_accept_invalid_certs(tls.accept_invalid_certs);
+    builder.build().map_err(io::Error::other)
+}
+
+fn read_file(path: &str) -> Result<Vec<u8>, io::Error> {
+    let mut contents = Vec::new();
+    File::open(path)?.read_to_end(&mut contents)?;
+    Ok(contents)
+}
@@ -0,0 +1,470 @@
+use std::collections::HashMap;
+use std::io;
+
+use serde_json::{self, Value};
+
+use config::DeviceConfig;
+use discovery::{add_property, push_type, unit_name, Discovery, DiscoveryEvent};
+use gateway::{ActionDescription, PropertyDescription};
+use mqtt;
+use payload::{Conversion, PayloadFormat, ValueMapping};
+
+// Bits of an expose's `access` field.
+const ACCESS_STATE: u64 = 1;
+const ACCESS_SET: u64 = 2;
+
+/// Turns the devices reported on `<base>/bridge/devices` into Things.
+pub struct Zigbee2Mqtt {
+    base: String,
+    /// Last seen `bridge/devices` entry for each IEEE address, `null` for
+    /// devices restored from the registry.
+    known: HashMap<String, Value>,
+    version: Option<String>,
+    permit_join: bool,
+}
+
+impl Zigbee2Mqtt {
+    pub fn new(base: &str) -> Zigbee2Mqtt {
+        Zigbee2Mqtt {
+            base: base.to_string(),
+            known: HashMap::new(),
+            version: None,
+            permit_join: false,
+        }
+    }
+
+    fn handle_devices(&mut self, devices: &[Value]) -> Vec<DiscoveryEvent> {
+        let mut events = Vec::new();
+        let mut seen = HashMap::new();
+        for device in devices {
+            let ieee = match device.get("ieee_address").and_then(Value::as_str) {
+                Some(ieee) => ieee.to_string(),
+                None => continue,
+            };
+            let config = match device_config(&self.base, device) {
+                Some(config) => config,
+                None => continue,
+            };
+            if self.known.get(&ieee) != Some(device) {
+                events.push(DiscoveryEvent::Added(Box::new(config)));
+            }
+            seen.insert(ieee, device.clone());
+        }
+        for ieee in self.known.keys() {
+            if !seen.contains_key(ieee) {
+                events.push(DiscoveryEvent::Removed(ieee.clone()));
+            }
+        }
+        self.known = seen;
+        events
+    }
+
+    fn permit_join(&mut self, mqtt: &mqtt::MQTT, value: bool, time: f64) -> Result<(), io::Error> {
+        let mut request = json!({ "value": value });
+        if value {
+            // Zigbee2MQTT caps the window at 254 seconds.
+            request["time"] = Value::from(time.clamp(1.0, 254.0).round() as u64);
+        }
+        mqtt.publish(&format!("{}/bridge/request/permit_join", self.base),
+                     request.to_string().into_bytes())
+            .map_err(mqtt::mqtt_error)?;
+        self.permit_join = value;
+        Ok(())
+    }
+}
+
+impl Discovery for Zigbee2Mqtt {
+    fn topics(&self) -> Vec<String> {
+        vec![
+            format!("{}/bridge/devices", self.base),
+            format!("{}/bridge/info", self.base),
+        ]
+    }
+
+    fn restore(&mut self, config: &DeviceConfig) {
+        if config.topic_scheme.as_deref() == Some("zigbee2mqtt") && config.base.as_ref() == Some(&self.base) {
+            self.known.entry(config.id.clone()).or_insert(Value::Null);
+        }
+    }
+
+    fn handle_publish(&mut self, topic: &str, payload: &[u8]) -> Vec<DiscoveryEvent> {
+        let rest = match topic.starts_with(&self.base) {
+            true => &topic[self.base.len()..],
+            false => return Vec::new(),
+        };
+        match rest {
+            "/bridge/devices" => match serde_json::from_slice::<Value>(payload) {
+                Ok(Value::Array(devices)) => self.handle_devices(&devices),
+                _ => {
+                    warn!(topic = topic; "unreadable device list");
+                    Vec::new()
+                }
+            },
+            "/bridge/info" => {
+                if let Ok(info) = serde_json::from_slice::<Value>(payload) {
+                    let version = info.get("version").and_then(Value::as_str).map(|v| v.to_string());
+                    if let Some(ref v) = version {
+                        if version != self.version {
+                            info!("bridge version {}", v);
+                        }
+                    }
+                    self.version = version;
+                    if let Some(permit_join) = info.get("permit_join").and_then(Value::as_bool) {
+                        self.permit_join = permit_join;
+                    }
+                }
+                Vec::new()
+            },
+            _ => Vec::new(),
+        }
+    }
+
+    fn start_pairing(&mut self, mqtt: &mqtt::MQTT, timeout: f64) -> Result<(), io::Error> {
+        self.permit_join(mqtt, true, timeout)
+    }
+
+    fn cancel_pairing(&mut self, mqtt: &mqtt::MQTT) -> Result<(), io::Error> {
+        if !self.permit_join {
+            return Ok(());
+        }
+        self.permit_join(mqtt, false, 0.0)
+    }
+}
+
+/// Maps one `bridge/devices` entry to a device definition. The coordinator
+/// and devices that are unsupported or still interviewing are skipped.
+fn device_config(base: &str, device: &Value) -> Option<DeviceConfig> {
+    if device.get("type").and_then(Value::as_str) == Some("Coordinator") {
+        return None;
+    }
+    if device.get("supported").and_then(Value::as_bool) == Some(false) ||
+        device.get("interview_completed").and_then(Value::as_bool) == Some(false) {
+        return None;
+    }
+    let ieee = device.get("ieee_address")?.as_str()?;
+    let friendly_name = device.get("friendly_name").and_then(Value::as_str).unwrap_or(ieee);
+    let exposes = device.get("definition")?.get("exposes")?.as_array()?;
+
+    let mut config = DeviceConfig::new(ieee, friendly_name);
+    config.topic_scheme = Some("zigbee2mqtt".to_string());
+    config.base = Some(base.to_string());
+    config.topic = Some(friendly_name.to_string());
+
+    for expose in exposes {
+        let kind = expose.get("type").and_then(Value::as_str).unwrap_or("");
+        match expose.get("features").and_then(Value::as_array) {
+            Some(_) if kind == "composite" => add_feature(&mut config, "", expose),
+            Some(features) => {
+                for feature in features {
+                    add_feature(&mut config, kind, feature);
+                }
+            },
+            None => add_feature(&mut config, "", expose),
+        }
+    }
+    Some(config)
+}
+
+/// Adds a generic expose (binary, numeric, enum or text) as a property, or
+/// as an action when it can only be set. `parent` is the type of the
+/// specific expose (light, switch, climate, ...) it belongs to, if any.
+fn add_feature(config: &mut DeviceConfig, parent: &str, feature: &Value) {
+    let property = match feature.get("property").and_then(Value::as_str) {
+        Some(property) => property,
+        None => return,
+    };
+    let kind = feature.get("type").and_then(Value::as_str).unwrap_or("");
+    if kind == "composite" {
+        add_color(config, property, feature);
+        return;
+    }
+    let access = feature.get("access").and_then(Value::as_u64).unwrap_or(ACCESS_STATE);
+
+    let (typ, schema) = match kind {
+        "binary" => ("boolean", json!({ "type": "boolean" })),
+        "numeric" => {
+            let mut schema = json!({ "type": "number" });
+            for &(key, field) in &[("value_min", "minimum"), ("value_max", "maximum")] {
+                if let Some(limit) = feature.get(key) {
+                    schema[field] = limit.clone();
+                }
+            }
+            ("number", schema)
+        },
+        "enum" => ("string", json!({
+            "type": "string",
+            "enum": feature.get("values").cloned().unwrap_or_else(|| json!([])),
+        })),
+        "text" => ("string", json!({ "type": "string" })),
+        _ => return,
+    };
+
+    // Color temperatures are in mireds, Web Things use kelvin.
+    let mired = kind == "numeric" && property == "color_temp";
+    let mut format = command_format(property);
+    if mired {
+        format.conversion = Some(Conversion::Mired);
+    }
+    if kind == "binary" {
+        for &(value, key) in &[(true, "value_on"), (false, "value_off")] {
+            if let Some(payload) = feature.get(key).and_then(Value::as_str) {
+                format.value_map.push(ValueMapping {
+                    value: Value::Bool(value),
+                    payload: payload.to_string(),
+                });
+            }
+        }
+    }
+    config.payloads.insert(property.to_string(), format);
+
+    let title = feature.get("label").or_else(|| feature.get("name"))
+        .and_then(Value::as_str)
+        .map(|t| t.to_string());
+    let description = feature.get("description").and_then(Value::as_str).map(|d| d.to_string());
+
+    if access & ACCESS_STATE == 0 && access & ACCESS_SET != 0 {
+        config.actions.push(ActionDescription {
+            title,
+            description,
+            input: Some(schema),
+            ..ActionDescription::new(property)
+        });
+        return;
+    }
+
+    let (at_type, capability) = semantics(parent, property, kind, access);
+    if let Some(capability) = capability {
+        for t in capability {
+            if !config.types.iter().any(|x| x == t) {
+                config.types.push(t.to_string());
+            }
+        }
+    }
+
+    let initial = match typ {
+        "boolean" => Value::Bool(false),
+        "number" => Value::from(0),
+        _ => Value::String(String::new()),
+    };
+    let mut descr = PropertyDescription {
+        at_type: at_type.map(|t| t.to_string()),
+        title,
+        description,
+        unit: feature.get("unit").and_then(Value::as_str).map(unit_name),
+        minimum: feature.get("value_min").and_then(Value::as_f64),
+        maximum: feature.get("value_max").and_then(Value::as_f64),
+        multiple_of: feature.get("value_step").and_then(Value::as_f64),
+        enumeration: feature.get("values").and_then(Value::as_array).cloned(),
+        read_only: if access & ACCESS_SET == 0 { Some(true) } else { None },
+        ..PropertyDescription::new(property, typ, initial)
+    };
+    if mired {
+        // The coldest mired value is the warmest in kelvin.
+        let kelvin = |mired: Option<f64>| mired.filter(|&m| m > 0.0).map(|m| (1e6 / m).round());
+        let (minimum, maximum) = (kelvin(descr.maximum), kelvin(descr.minimum));
+        descr.typ = "integer".to_string();
+        descr.unit = Some("kelvin".to_string());
+        descr.minimum = minimum;
+        descr.maximum = maximum;
+        descr.multiple_of = None;
+        descr.value = Value::from(minimum.unwrap_or(2700.0) as u64);
+    }
+    config.properties.push(descr);
+}
+
+/// Adds a light's `color_xy` or `color_hs` composite as an `#rrggbb` color
+/// property. Lights exposing both are set through the first one.
+fn add_color(config: &mut DeviceConfig, property: &str, feature: &Value) {
+    let conversion = match feature.get("name").and_then(Value::as_str) {
+        Some("color_xy") => Conversion::ColorXy,
+        Some("color_hs") => Conversion::ColorHs,
+        _ => return,
+    };
+    if config.payloads.contains_key(property) {
+        return;
+    }
+    let mut format = command_format(property);
+    format.conversion = Some(conversion);
+    add_property(config, PropertyDescription {
+        at_type: Some("ColorProperty".to_string()),
+        title: Some("Color".to_string()),
+        ..PropertyDescription::new(property, "string", Value::String("#ffffff".to_string()))
+    }, format);
+    push_type(config, "ColorControl");
+}
+
+/// A format setting `property` in a JSON object and reading it from the
+/// device's state.
+fn command_format(property: &str) -> PayloadFormat {
+    let mut format = PayloadFormat::default();
+    format.command_template = Some(format!("{{\"{}\":{{{{json}}}}}}", property));
+    format.state_path = Some(format!("/{}", property));
+    format
+}
+
+/// The property `@type` and device capabilities implied by an expose.
+fn semantics(parent: &str, property: &str, kind: &str, access: u64)
+             -> (Option<&'static str>, Option<&'static [&'static str]>) {
+    match (parent, property) {
+        ("light", "state") => (Some("OnOffProperty"), Some(&["OnOffSwitch", "Light"])),
+        ("light", "brightness") => (Some("BrightnessProperty"), None),
+        ("light", "color_temp") => (Some("ColorTemperatureProperty"), Some(&["ColorControl"])),
+        ("switch", "state") => (Some("OnOffProperty"), Some(&["OnOffSwitch"])),
+        ("climate", "local_temperature") => (Some("TemperatureProperty"), Some(&["TemperatureSensor"])),
+        ("climate", "occupied_heating_setpoint") | ("climate", "current_heating_setpoint") =>
+            (Some("TargetTemperatureProperty"), Some(&["Thermostat"])),
+        ("climate", "system_mode") => (Some("ThermostatModeProperty"), None),
+        (_, "temperature") => (Some("TemperatureProperty"), Some(&["TemperatureSensor"])),
+        (_, "humidity") => (Some("HumidityProperty"), Some(&["HumiditySensor"])),
+        (_, "occupancy") => (Some("MotionProperty"), Some(&["MotionSensor"])),
+        (_, "power") => (Some("InstantaneousPowerProperty"), Some(&["EnergyMonitor"])),
+        (_, "state") if kind == "binary" && access & ACCESS_SET != 0 =>
+            (Some("OnOffProperty"), Some(&["OnOffSwitch"])),
+        _ => (None, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bulb() -> Value {
+        json!({
+            "ieee_address": "0x00158d0001a2b3c4",
+            "friendly_name": "hall/bulb",
+            "type": "Router",
+            "supported": true,
+            "interview_completed": true,
+            "definition": { "exposes": [
+                { "type": "light", "features": [
+                    { "type": "binary", "property": "state", "access": 7,
+                      "value_on": "ON", "value_off": "OFF" },
+                    { "type": "numeric", "property": "brightness", "access": 7,
+                      "value_min": 0, "value_max": 254 },
+                ] },
+                { "type": "numeric", "property": "linkquality", "access": 1, "unit": "lqi" },
+                { "type": "enum", "property": "effect", "access": 2, "values": ["blink", "okay"] },
+            ] },
+        })
+    }
+
+    fn devices(zigbee: &mut Zigbee2Mqtt, devices: Value) -> Vec<DiscoveryEvent> {
+        zigbee.handle_publish("zigbee2mqtt/bridge/devices", devices.to_string().as_bytes())
+    }
+
+    #[test]
+    fn maps_exposes() {
+        let mut zigbee = Zigbee2Mqtt::new("zigbee2mqtt");
+        let coordinator = json!({ "ieee_address": "0x00124b0018e2", "type": "Coordinator" });
+        let events = devices(&mut zigbee, json!([coordinator, bulb()]));
+        let config = match events.as_slice() {
+            &[DiscoveryEvent::Added(ref config)] => config,
+            _ => panic!("bulb not added"),
+        };
+        assert_eq!(config.id, "0x00158d0001a2b3c4");
+        assert_eq!(config.topic, Some("hall/bulb".to_string()));
+        assert_eq!(config.types, vec!["OnOffSwitch", "Light"]);
+
+        let state = config.properties.iter().find(|p| p.name == "state").unwrap();
+        assert_eq!(state.at_type, Some("OnOffProperty".to_string()));
+        let format = &config.payloads["state"];
+        assert_eq!(format.encode(&Value::Bool(true)), br#"{"state":"ON"}"#.to_vec());
+        assert_eq!(format.decode(br#"{"state":"OFF","brightness":3}"#), Some(Value::Bool(false)));
+
+        let brightness = config.properties.iter().find(|p| p.name == "brightness").unwrap();
+        assert_eq!(brightness.maximum, Some(254.0));
+        let linkquality = config.properties.iter().find(|p| p.name == "linkquality").unwrap();
+        assert_eq!(linkquality.read_only, Some(true));
+
+        // Set-only exposes become actions.
+        assert!(config.properties.iter().all(|p| p.name != "effect"));
+        assert_eq!(config.actions[0].name, "effect");
+        assert_eq!(config.actions[0].input, Some(json!({ "type": "string", "enum": ["blink", "okay"] })));
+    }
+
+    #[test]
+    fn reports_changes_and_removals() {
+        let mut zigbee = Zigbee2Mqtt::new("zigbee2mqtt");
+        assert_eq!(devices(&mut zigbee, json!([bulb()])).len(), 1);
+        assert!(devices(&mut zigbee, json!([bulb()])).is_empty());
+
+        let mut interviewing = bulb();
+        interviewing["ieee_address"] = json!("0x00158d0001ffffff");
+        interviewing["interview_completed"] = json!(false);
+        assert!(devices(&mut zigbee, json!([bulb(), interviewing])).is_empty());
+
+        match devices(&mut zigbee, json!([])).as_slice() {
+            &[DiscoveryEvent::Removed(ref id)] => assert_eq!(id, "0x00158d0001a2b3c4"),
+            _ => panic!("bulb not removed"),
+        }
+        assert!(zigbee.handle_publish("zigbee2mqtt/bridge/devices", b"{}").is_empty());
+    }
+
+    #[test]
+    fn removes_restored_devices_that_are_gone() {
+        let mut zigbee = Zigbee2Mqtt::new("zigbee2mqtt");
+        let mut gone = device_config("zigbee2mqtt", &bulb()).unwrap();
+        gone.id = "0x00158d0001ffffff".to_string();
+        zigbee.restore(&gone);
+        zigbee.restore(&device_config("zigbee2mqtt", &bulb()).unwrap());
+        let mut other = DeviceConfig::new("lamp", "Lamp");
+        other.topic_scheme = Some("generic".to_string());
+        zigbee.restore(&other);
+
+        let mut events = devices(&mut zigbee, json!([bulb()]));
+        events.sort_by_key(|event| match *event {
+            DiscoveryEvent::Added(_) => 0,
+            _ => 1,
+        });
+        match events.as_slice() {
+            &[DiscoveryEvent::Added(ref config), DiscoveryEvent::Removed(ref id)] => {
+                assert_eq!(config.id, "0x00158d0001a2b3c4");
+                assert_eq!(id, "0x00158d0001ffffff");
+            },
+            _ => panic!("restored devices not reconciled"),
+        }
+    }
+
+    #[test]
+    fn color_lights() {
+        let mut bulb = bulb();
+        bulb["definition"]["exposes"][0]["features"] = json!([
+            { "type": "binary", "property": "state", "access": 7, "value_on": "ON", "value_off": "OFF" },
+            { "type": "numeric", "property": "color_temp", "access": 7, "unit": "mired",
+              "value_min": 150, "value_max": 500 },
+            { "type": "composite", "property": "color", "name": "color_xy", "access": 7, "features": [
+                { "type": "numeric", "property": "x", "access": 7 },
+                { "type": "numeric", "property": "y", "access": 7 },
+            ] },
+            { "type": "composite", "property": "color", "name": "color_hs", "access": 7, "features": [
+                { "type": "numeric", "property": "hue", "access": 7 },
+                { "type": "numeric", "property": "saturation", "access": 7 },
+            ] },
+        ]);
+        let mut zigbee = Zigbee2Mqtt::new("zigbee2mqtt");
+        let config = match devices(&mut zigbee, json!([bulb])).pop() {
+            Some(DiscoveryEvent::Added(config)) => config,
+            _ => panic!("bulb not added"),
+        };
+        assert_eq!(config.types, vec!["OnOffSwitch", "Light", "ColorControl"]);
+        let names: Vec<&str> = config.properties.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, vec!["state", "color_temp", "color", "linkquality"]);
+
+        let color_temp = &config.properties[1];
+        assert_eq!(color_temp.at_type, Some("ColorTemperatureProperty".to_string()));
+        assert_eq!(color_temp.unit, Some("kelvin".to_string()));
+        assert_eq!((color_temp.minimum, color_temp.maximum), (Some(2000.0), Some(6667.0)));
+        let format = &config.payloads["color_temp"];
+        assert_eq!(format.encode(&json!(4000)), br#"{"color_temp":250}"#.to_vec());
+        assert_eq!(format.decode(br#"{"color_temp":370}"#), Some(json!(2703)));
+
+        let color = &config.properties[2];
+        assert_eq!(color.at_type, Some("ColorProperty".to_string()));
+        let format = &config.payloads["color"];
+        let sent: Value = serde_json::from_slice(&format.encode(&json!("#ff0000"))).unwrap();
+        assert_eq!(sent, json!({ "color": { "x": 0.6401, "y": 0.33 } }));
+        assert_eq!(format.decode(br#"{"color":{"x":0.6401,"y":0.33},"state":"ON"}"#),
+                   Some(json!("#ff0000")));
+        assert_eq!(format.decode(br#"{"state":"ON"}"#), None);
+    }
+}